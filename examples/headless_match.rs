@@ -0,0 +1,32 @@
+//Plays a bot against bot match with no server, using only the library.
+//Run with "cargo run --example headless_match". Time is stepped as fast as the machine can go rather than in real time.
+
+use ping_pong::room_controller::room::bot::BotDifficulty;
+use ping_pong::room_controller::room::fixed_dt;
+use ping_pong::Room;
+
+#[tokio::main]
+async fn main() {
+    let hard = BotDifficulty::preset("hard").expect("Hard is a preset");
+
+    let mut room = Room::new().await;
+    room.add_bot(hard.clone());
+    room.add_bot(hard); //Filling the room starts the match.
+
+    //Ten minutes of match time at most, a tick at a time.
+    for _ in 0..(600.0 / fixed_dt()) as u32 {
+        room.advance(fixed_dt());
+
+        for event in room.take_events() {
+            if event["type"] == "point" || event["type"] == "match_over" || event["type"] == "match_summary" {
+                println!("{}", event);
+            }
+        }
+
+        if room.state == "Finished" {
+            break;
+        }
+    }
+
+    println!("Final score {:?} after {} ticks", room.rules.score, room.tick_count);
+}
@@ -0,0 +1,287 @@
+//This is the load test.
+//It connects a crowd of fake players to a running server, lets matchmaking put them in rooms and has them play (moving and swinging at set rates).
+//At the end it reports how many connected, how late room state arrived, how long requests took to be answered and how much CPU/memory the server used per room.
+//Run the server first, then "cargo run --release --bin loadtest -- --clients 200".
+//CPU and memory are read from /proc, so they're only reported on Linux with the server on the same machine.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use tokio::time::{self, Instant};
+use tokio_tungstenite::tungstenite::Message;
+
+const CLOCK_TICKS_PER_SEC: f64 = 100.0; //USER_HZ, what /proc times are counted in. It's 100 on every mainstream Linux.
+
+#[derive(Parser, Debug)]
+#[command(name = "loadtest", about = "Simulated players for load testing the ping pong server")]
+struct Args {
+    #[arg(long, default_value = "ws://127.0.0.1:3000/ws")]
+    url: String,
+
+    #[arg(long, default_value_t = 100)]
+    clients: u32,
+
+    #[arg(long, default_value = "singles", help = "Room type to queue for, singles or doubles")]
+    room_type: String,
+
+    #[arg(long, default_value = "classic", help = "Game mode to queue for")]
+    mode: String,
+
+    #[arg(long, default_value_t = 30.0, help = "Moves each client sends per second")]
+    move_rate: f64,
+
+    #[arg(long, default_value_t = 0.5, help = "Swings each client makes per second")]
+    hit_rate: f64,
+
+    #[arg(long, default_value_t = 1.0, help = "Room list requests each client sends per second, their replies are timed for latency")]
+    ping_rate: f64,
+
+    #[arg(long, default_value_t = 30, help = "How long to play for once everyone has connected")]
+    duration_secs: u64,
+
+    #[arg(long, default_value_t = 50.0, help = "New connections per second while ramping up")]
+    connect_rate: f64,
+
+    #[arg(long, default_value_t = 30, help = "The server's tick rate, snapshots further apart than one tick are late")]
+    tick_rate: u32,
+
+    #[arg(long, help = "Server process id for CPU and memory use, found by name if not given")]
+    server_pid: Option<u32>,
+}
+
+//Everything the clients measured, shared between them.
+#[derive(Default)]
+struct Stats {
+    connected: u32,
+    failed: u32,
+    dropped: u32, //Connections the server closed before the test ended.
+    joined: u32,
+    rooms: std::collections::HashSet<String>,
+    tick_lateness_ms: Vec<f64>, //How much later than one tick apart each snapshot arrived.
+    latency_ms: Vec<f64>, //Round trip of each room list request.
+    messages_in: u64,
+    messages_out: u64,
+}
+
+//A small xorshift, good enough to vary the traffic and keeps the test free of extra dependencies.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+//CPU time (in clock ticks) and resident memory (in KiB) of a process.
+struct ProcessUsage {
+    cpu_ticks: u64,
+    rss_kib: u64,
+}
+
+fn process_usage(pid: u32) -> Option<ProcessUsage> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+
+    //The process name is in brackets and may contain spaces, the fields after it are utime and stime at 12 and 13.
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let cpu_ticks = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+
+    let rss_kib = status.lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+
+    Some(ProcessUsage { cpu_ticks, rss_kib })
+}
+
+fn find_server_pid() -> Option<u32> {
+    std::fs::read_dir("/proc").ok()?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+        .find(|pid| std::fs::read_to_string(format!("/proc/{}/comm", pid)).is_ok_and(|name| name.trim() == "ping-pong"))
+}
+
+fn percentiles(samples: &mut [f64]) -> String {
+    if samples.is_empty() {
+        return "no samples".to_string();
+    }
+
+    samples.sort_by(f64::total_cmp);
+    let at = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+
+    format!("p50 {:.1}ms  p90 {:.1}ms  p99 {:.1}ms  max {:.1}ms  ({} samples)", at(0.5), at(0.9), at(0.99), at(1.0), samples.len())
+}
+
+async fn run_client(args: Arc<Args>, stats: Arc<Mutex<Stats>>, seed: u64, end: Instant) {
+    let Ok((socket, _)) = tokio_tungstenite::connect_async(args.url.as_str()).await else {
+        stats.lock().await.failed += 1;
+        return;
+    };
+    stats.lock().await.connected += 1;
+
+    let (mut sender, mut receiver) = socket.split();
+    let mut rng = Rng(seed | 1);
+
+    let join_msg = json!({ "type": "join_room", "room_type": args.room_type, "mode": args.mode });
+    let _ = sender.send(Message::Text(join_msg.to_string().into())).await;
+
+    //Timers are staggered so the clients don't all send on the same instant.
+    let period = |rate: f64| Duration::from_secs_f64(1.0 / rate.max(0.001));
+    let stagger = |rng: &mut Rng, period: Duration| Instant::now() + period.mul_f64(rng.next());
+
+    let move_period = period(args.move_rate);
+    let mut moves = time::interval_at(stagger(&mut rng, move_period), move_period);
+    let hit_period = period(args.hit_rate);
+    let mut hits = time::interval_at(stagger(&mut rng, hit_period), hit_period);
+    let ping_period = period(args.ping_rate);
+    let mut pings = time::interval_at(stagger(&mut rng, ping_period), ping_period);
+
+    let tick = 1000.0 / args.tick_rate as f64;
+    let mut in_room = false;
+    let mut last_snapshot: Option<Instant> = None;
+    let mut pings_sent: std::collections::VecDeque<Instant> = Default::default();
+    let (mut lateness, mut latency) = (Vec::new(), Vec::new());
+    let (mut messages_in, mut messages_out) = (0u64, 1u64);
+    let mut dropped = false;
+
+    loop {
+        let outgoing = tokio::select! {
+            _ = time::sleep_until(end) => break,
+            message = receiver.next() => {
+                let Some(Ok(Message::Text(text))) = message else {
+                    match message {
+                        Some(Ok(_)) => continue, //Pings and the like.
+                        _ => { dropped = true; break; }
+                    }
+                };
+                messages_in += 1;
+
+                let Ok(message) = serde_json::from_str::<Value>(&text) else { continue };
+                match message["type"].as_str() {
+                    Some("room_joined") => {
+                        in_room = true;
+                        let mut stats = stats.lock().await;
+                        stats.joined += 1;
+                        stats.rooms.insert(message["room_id"].as_str().unwrap_or_default().to_string());
+                    }
+                    Some("ball_state") => {
+                        //One per tick, so the gap between them shows how late the room's ticks are.
+                        let now = Instant::now();
+                        if let Some(last) = last_snapshot {
+                            lateness.push((now.duration_since(last).as_secs_f64() * 1000.0 - tick).max(0.0));
+                        }
+                        last_snapshot = Some(now);
+                    }
+                    Some("room_list") => {
+                        if let Some(sent) = pings_sent.pop_front() {
+                            latency.push(sent.elapsed().as_secs_f64() * 1000.0);
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            _ = moves.tick(), if in_room => {
+                let (dx, dz) = (rng.next() * 0.2 - 0.1, rng.next() * 0.2 - 0.1);
+                vec![json!({ "type": "move", "dx": dx, "dy": 0.0, "dz": dz })]
+            }
+            _ = hits.tick(), if in_room => {
+                //A swing is a press then a release, sent together as the test has no reason to hold it.
+                vec![json!({ "type": "hit_begin" }), json!({ "type": "hit_end" })]
+            }
+            _ = pings.tick() => {
+                pings_sent.push_back(Instant::now());
+                vec![json!({ "type": "list_rooms" })]
+            }
+        };
+
+        for message in outgoing {
+            messages_out += 1;
+            if sender.send(Message::Text(message.to_string().into())).await.is_err() {
+                dropped = true;
+                break;
+            }
+        }
+    }
+
+    let _ = sender.close().await;
+
+    let mut stats = stats.lock().await;
+    stats.dropped += dropped as u32;
+    stats.tick_lateness_ms.extend(lateness);
+    stats.latency_ms.extend(latency);
+    stats.messages_in += messages_in;
+    stats.messages_out += messages_out;
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Arc::new(Args::parse());
+    let stats = Arc::new(Mutex::new(Stats::default()));
+
+    let server_pid = args.server_pid.or_else(find_server_pid);
+    let usage_before = server_pid.and_then(process_usage);
+    if usage_before.is_none() {
+        println!("Server process not found, CPU and memory won't be reported (pass --server-pid)");
+    }
+
+    println!("Connecting {} clients to {} at {}/s", args.clients, args.url, args.connect_rate);
+
+    //The test runs for duration_secs after the last client has connected.
+    let ramp = Duration::from_secs_f64(args.clients as f64 / args.connect_rate.max(0.001));
+    let started = Instant::now();
+    let end = started + ramp + Duration::from_secs(args.duration_secs);
+
+    let mut clients = Vec::new();
+    let mut connects = time::interval(Duration::from_secs_f64(1.0 / args.connect_rate.max(0.001)));
+    for index in 0..args.clients {
+        connects.tick().await;
+        let seed = uuid::Uuid::new_v4().as_u64_pair().0 ^ index as u64;
+        clients.push(tokio::spawn(run_client(args.clone(), stats.clone(), seed, end)));
+    }
+
+    for client in clients {
+        let _ = client.await;
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let usage_after = server_pid.and_then(process_usage);
+    let mut stats = stats.lock().await;
+    let rooms = stats.rooms.len().max(1) as f64;
+
+    println!();
+    println!("Connections  {} ok, {} failed, {} dropped by the server", stats.connected, stats.failed, stats.dropped);
+    println!("Rooms        {} clients joined {} rooms", stats.joined, stats.rooms.len());
+    println!("Messages     {:.0}/s in, {:.0}/s out", stats.messages_in as f64 / elapsed, stats.messages_out as f64 / elapsed);
+    println!("Tick lateness {}", percentiles(&mut stats.tick_lateness_ms));
+    println!("Latency      {}", percentiles(&mut stats.latency_ms));
+
+    if let (Some(before), Some(after)) = (usage_before, usage_after) {
+        let cpu = (after.cpu_ticks - before.cpu_ticks) as f64 / CLOCK_TICKS_PER_SEC / elapsed * 100.0; //Percent of one core.
+        let grown = after.rss_kib as f64 - before.rss_kib as f64;
+
+        println!("Server CPU   {:.1}% of a core, {:.2}% per room", cpu, cpu / rooms);
+        println!("Server RSS   {:.1} MiB, grew {:.1} MiB ({:.0} KiB per room)", after.rss_kib as f64 / 1024.0, grown / 1024.0, grown / rooms);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_pick_from_sorted_samples() {
+        let mut samples: Vec<f64> = (1..=100).rev().map(f64::from).collect();
+        assert_eq!(percentiles(&mut samples), "p50 51.0ms  p90 90.0ms  p99 99.0ms  max 100.0ms  (100 samples)");
+        assert_eq!(percentiles(&mut []), "no samples");
+    }
+}
@@ -0,0 +1,526 @@
+//This is the config file.
+//It holds every server setting that used to be hard-coded, grouped into network, tick, physics, materials, rules, chat, replays, logging and admin sections.
+//Settings are layered: built-in defaults, then the TOML file, then environment variables and command-line flags (flags win).
+//The config is loaded once at startup and read anywhere through config::get(), tests that never load one get the defaults.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+
+use crate::logging;
+
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+pub const MAX_TICK_RATE: u32 = 240;
+pub const MIN_ADMIN_TOKEN_LENGTH: usize = 16;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub tick: TickConfig,
+    pub physics: PhysicsConfig,
+    pub materials: MaterialsConfig,
+    pub rules: RulesConfig,
+    pub chat: ChatConfig,
+    pub replays: ReplaysConfig,
+    pub logging: LoggingConfig,
+    pub admin: AdminConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub bind: SocketAddr,
+    pub broadcast_capacity: usize, //Server-wide messages each connection can fall behind on before it starts missing them.
+    pub shutdown_grace_secs: u64, //How long matches get to finish after a shutdown signal before they're ended where they stand.
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            bind: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            broadcast_capacity: 16,
+            shutdown_grace_secs: 120,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TickConfig {
+    pub tick_rate: u32, //Simulation steps (and state messages) per second.
+    pub max_steps_per_advance: u32, //Caps catch-up steps after a stall so a slow tick can't snowball.
+}
+
+impl Default for TickConfig {
+    fn default() -> Self {
+        TickConfig {
+            tick_rate: 30,
+            max_steps_per_advance: 5,
+        }
+    }
+}
+
+impl TickConfig {
+    pub fn fixed_dt(&self) -> f32 {
+        //Every physics step advances the world by exactly this much.
+        1.0 / self.tick_rate as f32
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PhysicsConfig {
+    pub paddle_plane_z: f64, //Distance of each player's bat plane from the net.
+    pub partner_depth_offset: f64, //How much further back doubles partners stand.
+    pub ball_radius: f32,
+    pub gravity: [f32; 3],
+    pub serve_speed: f32,
+    pub hit_timer_secs: u64,
+    pub max_bat_angular_speed: f32, //Radians per second, faster turns asked for by a player are spread over the next ticks.
+    pub swing_speed: f32, //Speed a perfectly timed swing drives the blade through the ball at.
+    pub table_height: f32, //Height of the table's top, the bats play at 0 so with no gravity only shots angled down reach it.
+    pub table_half_width: f32,
+    pub table_half_length: f32, //From the net to each end, the bat planes are beyond the ends.
+    pub net_height: f32, //Top of the net above the table, it hangs across z = 0.
+}
+
+impl Default for PhysicsConfig {
+    fn default() -> Self {
+        PhysicsConfig {
+            paddle_plane_z: 9.0,
+            partner_depth_offset: 1.5,
+            ball_radius: 0.1,
+            gravity: [0.0, 0.0, 0.0], //No gravity as all the directions are controlled programatically.
+            serve_speed: 8.0,
+            hit_timer_secs: 3,
+            max_bat_angular_speed: 20.0,
+            swing_speed: 8.0,
+            table_height: -1.0,
+            table_half_width: 3.9,
+            table_half_length: 7.0,
+            net_height: 0.75,
+        }
+    }
+}
+
+//Named material profiles, so the feel of the ball, table and bats can be tuned without recompiling.
+//Listing profiles of a kind in the config file replaces the built-in ones of that kind.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaterialsConfig {
+    pub ball: String, //Profile from balls used for every ball.
+    pub table: String, //Profile from tables used for every table.
+    pub default_rubber: String, //Rubber on a bat until it's player picks another.
+    pub balls: BTreeMap<String, BallMaterial>,
+    pub tables: BTreeMap<String, TableMaterial>,
+    pub rubbers: BTreeMap<String, RubberMaterial>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BallMaterial {
+    pub mass: f32, //Kilograms, a regulation 40mm ball is 2.7 grams.
+    pub restitution: f32,
+    pub friction: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TableMaterial {
+    pub restitution: f32, //Share of the ball's vertical speed kept after a bounce.
+    pub grip: f32, //0 to 1, how much of the ball's slide across the top is turned into spin.
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RubberMaterial {
+    pub restitution: f32, //Share of the ball's speed into the blade it keeps coming off it.
+    pub grip: f32, //0 to 1, how much the blade turns the ball's slide across it into spin.
+}
+
+impl Default for MaterialsConfig {
+    fn default() -> Self {
+        let rubber = |restitution, grip| RubberMaterial { restitution, grip };
+
+        MaterialsConfig {
+            ball: "regulation".to_string(),
+            table: "regulation".to_string(),
+            default_rubber: "inverted".to_string(),
+            balls: BTreeMap::from([("regulation".to_string(), BallMaterial { mass: 0.0027, restitution: 0.89, friction: 0.3 })]),
+            tables: BTreeMap::from([("regulation".to_string(), TableMaterial { restitution: 0.89, grip: 0.6 })]),
+            rubbers: BTreeMap::from([
+                ("inverted".to_string(), rubber(0.9, 0.8)), //Smooth and tacky, the most spin.
+                ("short_pips".to_string(), rubber(0.92, 0.45)), //Fast and flat.
+                ("long_pips".to_string(), rubber(0.85, 0.15)), //Hardly grips, spin mostly passes through.
+                ("anti".to_string(), rubber(0.75, 0.05)), //Slick and dead, kills pace and spin.
+            ]),
+        }
+    }
+}
+
+impl MaterialsConfig {
+    pub fn ball(&self) -> &BallMaterial {
+        //Validated at startup to exist.
+        &self.balls[&self.ball]
+    }
+
+    pub fn table(&self) -> &TableMaterial {
+        &self.tables[&self.table]
+    }
+
+    pub fn rubber(&self, name: &str) -> Result<&RubberMaterial, String> {
+        self.rubbers.get(name).ok_or_else(|| format!("Unknown rubber: {}", name))
+    }
+
+    fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let unit = |value: f32| (0.0..=1.0).contains(&value);
+
+        for (kind, name, known) in [
+            ("materials.ball", &self.ball, self.balls.contains_key(&self.ball)),
+            ("materials.table", &self.table, self.tables.contains_key(&self.table)),
+            ("materials.default_rubber", &self.default_rubber, self.rubbers.contains_key(&self.default_rubber)),
+        ] {
+            if !known {
+                problems.push(format!("{} names a profile that isn't listed: {}", kind, name));
+            }
+        }
+
+        for (name, ball) in &self.balls {
+            if !ball.mass.is_finite() || ball.mass <= 0.0 {
+                problems.push(format!("materials.balls.{}.mass must be greater than 0", name));
+            }
+            if !unit(ball.restitution) {
+                problems.push(format!("materials.balls.{}.restitution must be between 0 and 1", name));
+            }
+            if !ball.friction.is_finite() || ball.friction < 0.0 {
+                problems.push(format!("materials.balls.{}.friction can't be negative", name));
+            }
+        }
+
+        let surfaces = self.tables.iter().map(|(name, table)| ("tables", name, table.restitution, table.grip))
+            .chain(self.rubbers.iter().map(|(name, rubber)| ("rubbers", name, rubber.restitution, rubber.grip)));
+        for (kind, name, restitution, grip) in surfaces {
+            if !unit(restitution) {
+                problems.push(format!("materials.{}.{}.restitution must be between 0 and 1", kind, name));
+            }
+            if !unit(grip) {
+                problems.push(format!("materials.{}.{}.grip must be between 0 and 1", kind, name));
+            }
+        }
+
+        problems
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RulesConfig {
+    pub default_match_length: u32, //Points needed to win.
+    pub max_match_length: u32,
+    pub spectator_capacity: i32,
+    pub fixture_no_show_secs: u64, //How long a tournament fixture's room waits for the second player before giving it to the first, 0 waits forever.
+}
+
+impl Default for RulesConfig {
+    fn default() -> Self {
+        RulesConfig {
+            default_match_length: 11,
+            max_match_length: 21,
+            spectator_capacity: 8,
+            fixture_no_show_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatConfig {
+    pub max_length: usize, //Characters in one chat message.
+    pub rate_limit_messages: usize, //Chat messages and emotes a member can send in rate_limit_secs.
+    pub rate_limit_secs: u64,
+    pub blocked_words: Vec<String>, //Starred out of chat messages, matched whole word and ignoring case.
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        ChatConfig {
+            max_length: 200,
+            rate_limit_messages: 5,
+            rate_limit_secs: 10,
+            blocked_words: ["arse", "bastard", "bitch", "bollocks", "crap", "damn", "fuck", "shit"].map(String::from).to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplaysConfig {
+    pub max_files: usize, //The oldest replays are deleted past this many, 0 keeps them all.
+    pub max_age_days: u64, //Replays older than this are deleted, 0 keeps them however old.
+}
+
+impl Default for ReplaysConfig {
+    fn default() -> Self {
+        ReplaysConfig {
+            max_files: 1000,
+            max_age_days: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text, //Human readable, for running locally.
+    Json, //One JSON object per line, for the log pipeline.
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: String, //Level for everything not listed in modules, or a full filter such as "info,ping_pong::replay=debug".
+    pub format: LogFormat,
+    pub modules: BTreeMap<String, String>, //Level per module, the names are the keys of logging::LOG_MODULES.
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            modules: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub token: Option<String>, //Bearer token for the /admin API, the API is turned off when this isn't set.
+}
+
+//Command-line flags, each one can also be set with the environment variable named next to it.
+#[derive(Debug, Parser)]
+#[command(name = "ping-pong", about = "Ping pong game server")]
+pub struct Cli {
+    /// TOML config file, config.toml is used if it exists.
+    #[arg(long, env = "PINGPONG_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, e.g. 0.0.0.0:3000.
+    #[arg(long, env = "PINGPONG_BIND")]
+    pub bind: Option<SocketAddr>,
+
+    #[arg(long, env = "PINGPONG_BROADCAST_CAPACITY")]
+    pub broadcast_capacity: Option<usize>,
+
+    #[arg(long, env = "PINGPONG_SHUTDOWN_GRACE_SECS")]
+    pub shutdown_grace_secs: Option<u64>,
+
+    #[arg(long, env = "PINGPONG_TICK_RATE")]
+    pub tick_rate: Option<u32>,
+
+    #[arg(long, env = "PINGPONG_MAX_STEPS_PER_ADVANCE")]
+    pub max_steps_per_advance: Option<u32>,
+
+    #[arg(long, env = "PINGPONG_PADDLE_PLANE_Z")]
+    pub paddle_plane_z: Option<f64>,
+
+    #[arg(long, env = "PINGPONG_SERVE_SPEED")]
+    pub serve_speed: Option<f32>,
+
+    #[arg(long, env = "PINGPONG_HIT_TIMER_SECS")]
+    pub hit_timer_secs: Option<u64>,
+
+    #[arg(long, env = "PINGPONG_MATCH_LENGTH")]
+    pub match_length: Option<u32>,
+
+    #[arg(long, env = "PINGPONG_MAX_MATCH_LENGTH")]
+    pub max_match_length: Option<u32>,
+
+    #[arg(long, env = "PINGPONG_SPECTATOR_CAPACITY")]
+    pub spectator_capacity: Option<i32>,
+
+    /// Log level or filter, e.g. debug or info,ping_pong::replay=debug.
+    #[arg(long, env = "PINGPONG_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    #[arg(long, value_enum, env = "PINGPONG_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Token for the /admin API, best set through the environment so it doesn't show up in the process list.
+    #[arg(long, env = "PINGPONG_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+}
+
+impl Config {
+    pub fn load(cli: &Cli) -> Result<Self, String> {
+        //A missing file is only an error if it was asked for by name.
+        let mut config = match &cli.config {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Config::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+            None => Config::default(),
+        };
+
+        config.apply_overrides(cli);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+    }
+
+    fn apply_overrides(&mut self, cli: &Cli) {
+        if let Some(bind) = cli.bind { self.network.bind = bind; }
+        if let Some(capacity) = cli.broadcast_capacity { self.network.broadcast_capacity = capacity; }
+        if let Some(secs) = cli.shutdown_grace_secs { self.network.shutdown_grace_secs = secs; }
+        if let Some(tick_rate) = cli.tick_rate { self.tick.tick_rate = tick_rate; }
+        if let Some(max_steps) = cli.max_steps_per_advance { self.tick.max_steps_per_advance = max_steps; }
+        if let Some(plane_z) = cli.paddle_plane_z { self.physics.paddle_plane_z = plane_z; }
+        if let Some(speed) = cli.serve_speed { self.physics.serve_speed = speed; }
+        if let Some(secs) = cli.hit_timer_secs { self.physics.hit_timer_secs = secs; }
+        if let Some(length) = cli.match_length { self.rules.default_match_length = length; }
+        if let Some(length) = cli.max_match_length { self.rules.max_match_length = length; }
+        if let Some(capacity) = cli.spectator_capacity { self.rules.spectator_capacity = capacity; }
+        if let Some(level) = &cli.log_level { self.logging.level = level.clone(); }
+        if let Some(format) = cli.log_format { self.logging.format = format; }
+        if let Some(token) = &cli.admin_token { self.admin.token = Some(token.clone()); }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        //Collecting every problem so they can all be fixed in one go.
+        let mut problems = Vec::new();
+
+        if self.network.broadcast_capacity == 0 {
+            problems.push("network.broadcast_capacity must be at least 1".to_string());
+        }
+
+        if !(1..=MAX_TICK_RATE).contains(&self.tick.tick_rate) {
+            problems.push(format!("tick.tick_rate must be between 1 and {}", MAX_TICK_RATE));
+        }
+
+        if self.tick.max_steps_per_advance == 0 {
+            problems.push("tick.max_steps_per_advance must be at least 1".to_string());
+        }
+
+        if !self.physics.paddle_plane_z.is_finite() || self.physics.paddle_plane_z <= 0.0 {
+            problems.push("physics.paddle_plane_z must be greater than 0".to_string());
+        }
+
+        if !self.physics.partner_depth_offset.is_finite() || self.physics.partner_depth_offset < 0.0 {
+            problems.push("physics.partner_depth_offset can't be negative".to_string());
+        }
+
+        if !self.physics.ball_radius.is_finite() || self.physics.ball_radius <= 0.0 {
+            problems.push("physics.ball_radius must be greater than 0".to_string());
+        }
+
+        if self.physics.gravity.iter().any(|g| !g.is_finite()) {
+            problems.push("physics.gravity must be finite".to_string());
+        }
+
+        if !self.physics.serve_speed.is_finite() || self.physics.serve_speed <= 0.0 {
+            problems.push("physics.serve_speed must be greater than 0".to_string());
+        }
+
+        if self.physics.hit_timer_secs == 0 {
+            problems.push("physics.hit_timer_secs must be at least 1".to_string());
+        }
+
+        if !self.physics.max_bat_angular_speed.is_finite() || self.physics.max_bat_angular_speed <= 0.0 {
+            problems.push("physics.max_bat_angular_speed must be greater than 0".to_string());
+        }
+
+        if !self.physics.swing_speed.is_finite() || self.physics.swing_speed < 0.0 {
+            problems.push("physics.swing_speed can't be negative".to_string());
+        }
+
+        let table = [self.physics.table_height, self.physics.table_half_width, self.physics.table_half_length];
+        if table.iter().any(|size| !size.is_finite()) || table[1] <= 0.0 || table[2] <= 0.0 {
+            problems.push("physics.table_half_width and physics.table_half_length must be greater than 0".to_string());
+        }
+
+        if !self.physics.net_height.is_finite() || self.physics.net_height < 0.0 {
+            problems.push("physics.net_height can't be negative".to_string());
+        }
+
+        problems.extend(self.materials.check());
+
+        if self.rules.max_match_length == 0 {
+            problems.push("rules.max_match_length must be at least 1".to_string());
+        }
+
+        if !(1..=self.rules.max_match_length).contains(&self.rules.default_match_length) {
+            problems.push("rules.default_match_length must be between 1 and rules.max_match_length".to_string());
+        }
+
+        if self.rules.spectator_capacity < 0 {
+            problems.push("rules.spectator_capacity can't be negative".to_string());
+        }
+
+        if self.chat.max_length == 0 || self.chat.rate_limit_messages == 0 || self.chat.rate_limit_secs == 0 {
+            problems.push("chat.max_length, chat.rate_limit_messages and chat.rate_limit_secs must be at least 1".to_string());
+        }
+
+        if self.chat.blocked_words.iter().any(|word| word.trim().is_empty()) {
+            problems.push("chat.blocked_words can't have empty words".to_string());
+        }
+
+        problems.extend(logging::check_config(&self.logging));
+
+        if self.admin.token.as_ref().is_some_and(|token| token.len() < MIN_ADMIN_TOKEN_LENGTH) {
+            problems.push(format!("admin.token must be at least {} characters", MIN_ADMIN_TOKEN_LENGTH));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid config:\n  {}", problems.join("\n  ")))
+        }
+    }
+}
+
+pub fn init(config: Config) {
+    //Only the first call counts, the config can't change while rooms are running.
+    let _ = CONFIG.set(config);
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_config_matches_the_defaults() {
+        //Keeps config.example.toml in step with the defaults it documents.
+        let example = Config::from_file(Path::new("config.example.toml")).unwrap();
+        example.validate().unwrap();
+
+        assert_eq!(format!("{:?}", example), format!("{:?}", Config::default()));
+    }
+
+    #[test]
+    fn unknown_material_profiles_are_refused() {
+        let mut config = Config::default();
+        config.materials.default_rubber = "sandpaper".to_string();
+        config.materials.balls.get_mut("regulation").unwrap().restitution = 1.5;
+
+        let problems = config.validate().unwrap_err();
+        assert!(problems.contains("materials.default_rubber"), "{}", problems);
+        assert!(problems.contains("materials.balls.regulation.restitution"), "{}", problems);
+    }
+}
@@ -0,0 +1,24 @@
+//This is the library root.
+//It holds the whole game, so it can be used without running the server: rooms and their physics worlds, the match rules, replays and the messages players send.
+//The WebSocket server is the server module on top of it, behind the default "server" feature so tools using the game don't build axum. The ping-pong binary (main.rs) just loads the config and runs it.
+//To run a match in another tool, make a Room, add players, and call advance with the time passed, reading snapshot() and take_events() after each call.
+
+pub mod config;
+pub mod logging;
+pub mod metrics;
+pub mod player;
+pub mod player_messages;
+pub mod replay;
+pub mod room_controller;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod tournament;
+
+//The types most tools need, so they don't have to know where each lives.
+pub use player::Player;
+pub use player_messages::PlayerMessage;
+pub use replay::{ReplayEntry, ReplayPlayer};
+pub use room_controller::room::physics_world::PhysicsWorld;
+pub use room_controller::room::rules::MatchRules;
+pub use room_controller::room::Room;
+pub use room_controller::RoomController;
@@ -0,0 +1,88 @@
+//This is the logging file.
+//It sets up tracing from the [logging] config section, as readable text or one JSON object per line.
+//Connections and rooms each have a span (see main.rs and room.rs) carrying their ids, so every event says which player and room it's about.
+//Levels can be set per part of the server with short names, which are turned into filters on the module paths below.
+
+use std::io::IsTerminal;
+
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+
+use crate::config::{LogFormat, LoggingConfig};
+
+pub const NETWORK_TARGET: &str = "ping_pong::network"; //Socket handling lives in main.rs, it logs under this name so it can be filtered on it's own.
+
+//Module names allowed in [logging.modules] and the log targets they cover.
+pub const LOG_MODULES: [(&str, &str); 4] = [
+    ("room_controller", "ping_pong::room_controller"),
+    ("physics_world", "ping_pong::room_controller::room::physics_world"),
+    ("network", NETWORK_TARGET),
+    ("replay", "ping_pong::replay"),
+];
+
+fn filter_directives(config: &LoggingConfig) -> Result<String, Vec<String>> {
+    //The default level first, more specific module levels after it win for their modules.
+    let mut directives = vec![config.level.clone()];
+    let mut problems = Vec::new();
+
+    for (name, level) in &config.modules {
+        let Some((_, target)) = LOG_MODULES.iter().find(|(module, _)| module == name) else {
+            let names: Vec<&str> = LOG_MODULES.iter().map(|(module, _)| *module).collect();
+            problems.push(format!("logging.modules.{} is not a module, use one of {}", name, names.join(", ")));
+            continue;
+        };
+
+        if level.parse::<LevelFilter>().is_err() {
+            problems.push(format!("logging.modules.{} must be trace, debug, info, warn, error or off", name));
+            continue;
+        }
+
+        directives.push(format!("{}={}", target, level));
+    }
+
+    if let Err(e) = EnvFilter::builder().parse(&config.level) {
+        problems.push(format!("logging.level is not a valid level or filter: {}", e));
+    }
+
+    if problems.is_empty() {
+        Ok(directives.join(","))
+    } else {
+        Err(problems)
+    }
+}
+
+pub fn check_config(config: &LoggingConfig) -> Vec<String> {
+    filter_directives(config).err().unwrap_or_default()
+}
+
+pub fn init(config: &LoggingConfig) {
+    //Called once at startup with an already validated config.
+    let directives = filter_directives(config).unwrap_or_else(|_| config.level.clone());
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::builder().parse_lossy(directives))
+        .with_ansi(std::io::stdout().is_terminal()); //No colour codes when the output is piped into a file or collector.
+
+    match config.format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).init(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_levels_become_target_filters() {
+        let mut config = LoggingConfig::default();
+        config.modules.insert("physics_world".to_string(), "warn".to_string());
+        config.modules.insert("network".to_string(), "debug".to_string());
+
+        assert_eq!(
+            filter_directives(&config).unwrap(),
+            "info,ping_pong::network=debug,ping_pong::room_controller::room::physics_world=warn",
+        );
+
+        config.modules.insert("renderer".to_string(), "loud".to_string());
+        assert_eq!(check_config(&config).len(), 1);
+    }
+}
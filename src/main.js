@@ -166,6 +166,10 @@ camera.position.y = 3;
 
 // ---- WS setup.
 
+socket.addEventListener('open', () => {
+  socket.send(JSON.stringify({ type: 'join_room' })); //Asking the server for a seat in a room.
+});
+
 socket.addEventListener('message', event => {
   const data = JSON.parse(event.data);
 
@@ -187,11 +191,11 @@ socket.addEventListener('message', event => {
   }
 
 
-  if (data.type === 'init') {
-    id = data.player_id;
+  if (data.type === 'init' || data.type === 'room_joined') {
+    if (data.player_id) id = data.player_id;
     console.log(data); //This finds what order the player joins the server and how to broadcast others.
 
-     if(data.player_index == 1) {
+     if(data.player_index % 2 == 1) { //Odd seats (team 1) play from the far end, in doubles too.
        side = 1;
      } else {
        side = 0;
@@ -0,0 +1,130 @@
+//This is the metrics file.
+//It keeps the server's counters, gauges and histograms and renders them for Prometheus to scrape at GET /metrics.
+//Metrics are created once and read anywhere through metrics::get(), the same way as the config.
+//Room gauges are refreshed from the room list every second rather than counted up and down, so they can't drift from it.
+
+use std::sync::LazyLock;
+
+use prometheus::{
+    exponential_buckets, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use serde::Deserialize;
+
+use crate::room_controller::room_task::RoomInfo;
+
+pub const ROOM_STATES: [&str; 3] = ["Not Started", "In Progress", "Finished"];
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub connected_sockets: IntGauge,
+    pub queued_players: IntGauge, //Players sat in rooms that haven't started, waiting for an opponent or the owner.
+    pub rooms: IntGaugeVec, //By state.
+    pub room_tick_seconds: Histogram, //One tick of a single room, including catch-up steps.
+    pub physics_step_seconds: Histogram, //One fixed step of a single room's physics world.
+    pub messages_in: IntCounterVec, //By message type, "invalid" for messages that don't parse.
+    pub messages_out: IntCounterVec, //By message type.
+    pub broadcast_lagged: IntCounter, //Server-wide messages a connection fell too far behind to receive.
+    pub disconnects: IntCounterVec, //By reason.
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let connected_sockets = IntGauge::new("pingpong_connected_sockets", "Open WebSocket connections").unwrap();
+        let queued_players = IntGauge::new("pingpong_queued_players", "Players waiting in rooms that haven't started").unwrap();
+        let rooms = IntGaugeVec::new(Opts::new("pingpong_rooms", "Rooms by state"), &["state"]).unwrap();
+
+        //Ticks have a budget of 1/tick_rate (33ms by default), so the buckets are finer than Prometheus' defaults.
+        let room_tick_seconds = Histogram::with_opts(
+            HistogramOpts::new("pingpong_room_tick_seconds", "Time taken to tick one room")
+                .buckets(exponential_buckets(0.0001, 2.0, 12).unwrap()),
+        ).unwrap();
+        let physics_step_seconds = Histogram::with_opts(
+            HistogramOpts::new("pingpong_physics_step_seconds", "Time taken by one physics step of a room")
+                .buckets(exponential_buckets(0.00001, 2.0, 14).unwrap()),
+        ).unwrap();
+
+        let messages_in = IntCounterVec::new(Opts::new("pingpong_messages_in_total", "Messages received from clients"), &["type"]).unwrap();
+        let messages_out = IntCounterVec::new(Opts::new("pingpong_messages_out_total", "Messages sent to clients"), &["type"]).unwrap();
+        let broadcast_lagged = IntCounter::new("pingpong_broadcast_lagged_total", "Broadcast messages dropped for slow connections").unwrap();
+        let disconnects = IntCounterVec::new(Opts::new("pingpong_disconnects_total", "Closed connections by reason"), &["reason"]).unwrap();
+
+        registry.register(Box::new(connected_sockets.clone())).unwrap();
+        registry.register(Box::new(queued_players.clone())).unwrap();
+        registry.register(Box::new(rooms.clone())).unwrap();
+        registry.register(Box::new(room_tick_seconds.clone())).unwrap();
+        registry.register(Box::new(physics_step_seconds.clone())).unwrap();
+        registry.register(Box::new(messages_in.clone())).unwrap();
+        registry.register(Box::new(messages_out.clone())).unwrap();
+        registry.register(Box::new(broadcast_lagged.clone())).unwrap();
+        registry.register(Box::new(disconnects.clone())).unwrap();
+
+        Metrics {
+            registry,
+            connected_sockets,
+            queued_players,
+            rooms,
+            room_tick_seconds,
+            physics_step_seconds,
+            messages_in,
+            messages_out,
+            broadcast_lagged,
+            disconnects,
+        }
+    }
+
+    pub fn update_rooms(&self, rooms: &[RoomInfo]) {
+        //Every state is set (to 0 if there are none) so a state doesn't disappear from graphs when its last room goes.
+        for state in ROOM_STATES {
+            let count = rooms.iter().filter(|room| room.state == state).count();
+            self.rooms.with_label_values(&[state]).set(count as i64);
+        }
+
+        let queued = rooms.iter()
+            .filter(|room| room.state == "Not Started")
+            .map(|room| room.pop)
+            .sum::<i32>();
+        self.queued_players.set(queued as i64);
+    }
+
+    pub fn count_message_out(&self, message: &str) {
+        //Everything sent is a JSON object with a "type", only the type is read.
+        #[derive(Deserialize)]
+        struct Typed<'a> {
+            #[serde(rename = "type")]
+            kind: &'a str,
+        }
+
+        let kind = serde_json::from_str::<Typed>(message).map_or("unknown", |typed| typed.kind);
+        self.messages_out.with_label_values(&[kind]).inc();
+    }
+
+    pub fn render(&self) -> String {
+        //Prometheus text exposition format.
+        TextEncoder::new().encode_to_string(&self.registry.gather()).unwrap_or_default()
+    }
+}
+
+pub fn get() -> &'static Metrics {
+    &METRICS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outgoing_messages_are_counted_by_type() {
+        let metrics = Metrics::new();
+        metrics.count_message_out(r#"{"player_id":"x","type":"init"}"#);
+        metrics.count_message_out(r#"{"type":"init"}"#);
+        metrics.count_message_out("not json");
+
+        assert_eq!(metrics.messages_out.with_label_values(&["init"]).get(), 2);
+        assert_eq!(metrics.messages_out.with_label_values(&["unknown"]).get(), 1);
+        assert!(metrics.render().contains(r#"pingpong_messages_out_total{type="init"} 2"#));
+    }
+}
@@ -8,9 +8,6 @@
 
 use uuid::Uuid;
 
-use std::sync::Arc;
-use tokio::sync::Mutex;
-
 #[derive(Debug, Clone)]
 pub struct Player {
     pub id: Uuid,
@@ -18,6 +15,12 @@ pub struct Player {
 
 }
 
+impl Default for Player {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Player {
     pub fn new() -> Self  {
 
@@ -33,6 +36,7 @@ impl Player {
         
     }
 
+    #[allow(dead_code)]
     pub fn get_id(&mut self) -> Uuid { //Retreive player id.
         self.id
     }
@@ -1,7 +1,8 @@
 use serde::Deserialize;
+use uuid::Uuid;
 
-mod player;
-use player::Player;
+use crate::room_controller::room::bot::BotChoice;
+use crate::room_controller::room::ball_machine::Drill;
 
 
 #[derive(Debug, Deserialize)]
@@ -9,14 +10,97 @@ use player::Player;
 pub enum PlayerMessage {
     #[serde(rename = "join_room")]
     JoinRoom {
-        player_data: Player,
+        bot: Option<BotChoice>, //Play against a bot instead of being matched, a preset name ("easy", "medium", "hard") or custom settings.
+        code: Option<String>, //Invite code of a private room.
+        room_type: Option<String>, //"singles" (default) or "doubles".
+        mode: Option<String>, //"classic" (default), "quick", "blitz", "practice" or "fun".
+    },
+    #[serde(rename = "create_private_room")]
+    CreatePrivateRoom {
+        match_length: Option<u32>, //Defaults to the mode's.
+        room_type: Option<String>,
+        mode: Option<String>,
+    },
+    #[serde(rename = "room_settings")]
+    RoomSettings {
+        match_length: u32,
+    },
+    #[serde(rename = "kick")]
+    Kick {
+        player_id: Uuid,
+    },
+    #[serde(rename = "start_match")]
+    StartMatch {
+
+    },
+    #[serde(rename = "ball_machine")]
+    BallMachine {
+        speed: Option<f32>,
+        spin: Option<[f32; 2]>, //Topspin (negative for backspin) and sidespin, radians per second.
+        interval_secs: Option<f32>,
+        drill: Option<Drill>, //"alternate", "random", {"fixed": [x, y]} or {"sequence": [[x, y], ...]}.
+        aim_assist: Option<bool>, //Adds an aim_hint to each snapshot while a ball is on it's way.
+    },
+    #[serde(rename = "set_rubber")]
+    SetRubber {
+        rubber: String, //One of the rubbers listed in the init message.
+    },
+    #[serde(rename = "chat")]
+    Chat {
+        text: String, //To everyone in the room, players and spectators.
+    },
+    #[serde(rename = "emote")]
+    Emote {
+        emote: String, //One of the emotes listed in the init message.
+    },
+    #[serde(rename = "mute")]
+    Mute {
+        player_id: Uuid, //Someone in the same room, their chat and emotes stop being sent to this connection.
+        muted: Option<bool>, //Defaults to true, false unmutes.
+    },
+    #[serde(rename = "set_name")]
+    SetName {
+        display_name: String, //3 to 20 characters, unique ignoring case.
+    },
+    #[serde(rename = "friend_request")]
+    FriendRequest {
+        player: String, //Player id or display name, the same for the friend messages below.
+    },
+    #[serde(rename = "accept_friend")]
+    AcceptFriend {
+        player: String,
+    },
+    #[serde(rename = "remove_friend")]
+    RemoveFriend {
+        player: String, //Also turns down or takes back a friend request.
+    },
+    #[serde(rename = "list_friends")]
+    ListFriends {
+
+    },
+    #[serde(rename = "invite_friend")]
+    InviteFriend {
+        player: String, //An online friend, invited to the private room this connection is in.
+    },
+    #[serde(rename = "list_tournaments")]
+    ListTournaments {
+
+    },
+    #[serde(rename = "tournament_sign_up")]
+    TournamentSignUp {
+        tournament_id: Uuid, //Only while it's taking sign-ups.
+    },
+    #[serde(rename = "tournament_withdraw")]
+    TournamentWithdraw {
+        tournament_id: Uuid,
     },
     #[serde(rename = "move")]
     Move {
-        
+
         dx: f64,
         dy: f64,
         dz: f64,
+        rot: Option<[f64; 4]>, //Bat rotation as a quaternion [x, y, z, w], left as it is if not sent.
     },
     #[serde(rename = "hit_begin")]
     HitBegin {
@@ -25,7 +109,72 @@ pub enum PlayerMessage {
      #[serde(rename = "hit_end")]
     HitEnd {
 
+    },
+    #[serde(rename = "spectate")]
+    Spectate {
+        room_id: Uuid, //Room the connection wants to watch.
+        code: Option<String>, //Invite code, only needed to watch a private room.
+    },
+    #[serde(rename = "list_rooms")]
+    ListRooms {
+
+    },
+    #[serde(rename = "list_replays")]
+    ListReplays {
+
+    },
+    #[serde(rename = "watch_replay")]
+    WatchReplay {
+        replay_id: Uuid,
+        speed: Option<f64>, //0.5, 1 or 2, defaults to 1.
+    },
+    #[serde(rename = "replay_control")]
+    ReplayControl {
+        speed: Option<f64>,
+        seek: Option<f64>, //Seconds from the start of the replay.
+        paused: Option<bool>,
+    },
+    #[serde(rename = "stop_replay")]
+    StopReplay {
+
     },
     None,
 
-}
\ No newline at end of file
+}
+
+impl PlayerMessage {
+    pub fn kind(&self) -> &'static str {
+        //The message's "type", for counting messages without trusting whatever a client sent.
+        match self {
+            PlayerMessage::JoinRoom { .. } => "join_room",
+            PlayerMessage::CreatePrivateRoom { .. } => "create_private_room",
+            PlayerMessage::RoomSettings { .. } => "room_settings",
+            PlayerMessage::Kick { .. } => "kick",
+            PlayerMessage::StartMatch {} => "start_match",
+            PlayerMessage::BallMachine { .. } => "ball_machine",
+            PlayerMessage::SetRubber { .. } => "set_rubber",
+            PlayerMessage::Chat { .. } => "chat",
+            PlayerMessage::Emote { .. } => "emote",
+            PlayerMessage::Mute { .. } => "mute",
+            PlayerMessage::SetName { .. } => "set_name",
+            PlayerMessage::FriendRequest { .. } => "friend_request",
+            PlayerMessage::AcceptFriend { .. } => "accept_friend",
+            PlayerMessage::RemoveFriend { .. } => "remove_friend",
+            PlayerMessage::ListFriends {} => "list_friends",
+            PlayerMessage::InviteFriend { .. } => "invite_friend",
+            PlayerMessage::ListTournaments {} => "list_tournaments",
+            PlayerMessage::TournamentSignUp { .. } => "tournament_sign_up",
+            PlayerMessage::TournamentWithdraw { .. } => "tournament_withdraw",
+            PlayerMessage::Move { .. } => "move",
+            PlayerMessage::HitBegin {} => "hit_begin",
+            PlayerMessage::HitEnd {} => "hit_end",
+            PlayerMessage::Spectate { .. } => "spectate",
+            PlayerMessage::ListRooms {} => "list_rooms",
+            PlayerMessage::ListReplays {} => "list_replays",
+            PlayerMessage::WatchReplay { .. } => "watch_replay",
+            PlayerMessage::ReplayControl { .. } => "replay_control",
+            PlayerMessage::StopReplay {} => "stop_replay",
+            PlayerMessage::None => "none",
+        }
+    }
+}
@@ -0,0 +1,692 @@
+//This is the replay file.
+//Each room records the inputs of it's players and a keyframe of the world every second into a replay file.
+//Files are JSON lines with short keys to keep them small, one entry per line.
+//Playback re-applies the inputs to a room seeded the same way, so it produces the same snapshot messages as live play.
+//Because the simulation is fixed-step and seeded, playing from the start reproduces the match exactly, keyframes are only used to seek.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
+use uuid::Uuid;
+use tracing::{error, warn, Instrument, Span};
+
+use crate::config::{self, ReplaysConfig};
+use crate::Player;
+use crate::room_controller::room::{tick_rate, Room, DEFAULT_ROOM_TYPE};
+use crate::room_controller::room::mode::DEFAULT_MODE;
+use crate::room_controller::room::rules::MatchRules;
+use crate::room_controller::room::match_stats::MatchStats;
+use crate::room_controller::room::chat;
+use crate::room_controller::room::ball_machine::{BallMachine, MachineSettings};
+use crate::room_controller::room::physics_world::bat::LEVEL_BAT;
+
+pub const REPLAY_DIR: &str = "replays";
+pub const REPLAY_VERSION: u32 = 1;
+pub const REPLAY_SPEEDS: [f64; 3] = [0.5, 1.0, 2.0];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerFrame {
+    pub id: Uuid,
+    pub num: i32, //Player index, inputs refer to players by this to keep lines short.
+    pub pos: [f32; 3],
+    #[serde(default = "level_bat")]
+    pub rot: [f32; 4], //Bat rotation, keyframes from before bats could turn have them all level.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rubber: Option<String>, //Only if the player picked one, otherwise it's the default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub moving: Option<[f64; 3]>, //Move asked for but not stepped yet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub turning: Option<[f32; 4]>, //Bat rotation asked for but not reached yet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swing: Option<Duration>, //Wind-up so far of a swing in progress.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub touching: bool, //Bat on the ball, so staying on it after a seek isn't taken as a new touch.
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "e")]
+pub enum ReplayEntry {
+    #[serde(rename = "h")]
+    Header {
+        version: u32,
+        room_id: Uuid,
+        tick_rate: u32,
+        seed: u64,
+        #[serde(default = "default_room_type")]
+        room_type: String,
+        #[serde(default = "default_mode")]
+        mode: String,
+    },
+    #[serde(rename = "k")]
+    Keyframe {
+        t: u64,
+        ball: [f32; 6], //Position then velocity.
+        #[serde(default)]
+        spin: [f32; 3], //Ball angular velocity.
+        #[serde(default)]
+        bounces: [u32; 2], //Bounces on each side since the last touch, for spotting double bounces.
+        players: Vec<PlayerFrame>,
+        rng: u64, //Random generator state at this tick.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rules: Option<MatchRules>, //Score and serve, once the match has started.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        machine: Option<BallMachine>, //Practice rooms only.
+        #[serde(default)]
+        stats: Box<MatchStats>, //Shots and rallies so far, so the summary comes out the same after a seek. Boxed so it doesn't make every entry bigger.
+        #[serde(default)]
+        acc: f64, //Time the room hadn't simulated yet.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        len: Option<u32>, //Points to win, older keyframes leave it at the mode's.
+    },
+    #[serde(rename = "ml")]
+    MatchLength {
+        t: u64,
+        n: u32, //Points to win, set for private rooms and tournament fixtures.
+    },
+    #[serde(rename = "bm")]
+    Machine {
+        t: u64,
+        s: MachineSettings,
+    },
+    #[serde(rename = "s")]
+    Start {
+        t: u64,
+    },
+    #[serde(rename = "e")]
+    End {
+        t: u64, //Match ended early by an admin.
+    },
+    #[serde(rename = "j")]
+    Join {
+        t: u64,
+        id: Uuid,
+        p: i32,
+    },
+    #[serde(rename = "l")]
+    Leave {
+        t: u64,
+        p: i32,
+    },
+    #[serde(rename = "m")]
+    Move {
+        t: u64,
+        p: i32,
+        d: [f64; 3],
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        r: Option<[f64; 4]>, //Bat rotation, if the move turned the bat.
+    },
+    #[serde(rename = "rb")]
+    Rubber {
+        t: u64,
+        p: i32,
+        n: String, //Rubber profile name.
+    },
+    #[serde(rename = "c")]
+    Chat {
+        t: u64,
+        id: Uuid, //Spectators can chat too, so senders are kept by id rather than player index.
+        n: String, //Display name.
+        m: String, //The message after filtering.
+    },
+    #[serde(rename = "em")]
+    Emote {
+        t: u64,
+        id: Uuid,
+        n: String,
+        em: String,
+    },
+    #[serde(rename = "sum")]
+    Summary {
+        t: u64,
+        stats: MatchStats, //Written when the match ends, so the stats are kept with the match.
+    },
+    #[serde(rename = "hb")]
+    HitBegin {
+        t: u64,
+        p: i32,
+    },
+    #[serde(rename = "he")]
+    HitEnd {
+        t: u64,
+        p: i32,
+    },
+}
+
+impl ReplayEntry {
+    pub fn tick(&self) -> u64 {
+        match self {
+            ReplayEntry::Header { .. } => 0,
+            ReplayEntry::Keyframe { t, .. }
+            | ReplayEntry::Start { t }
+            | ReplayEntry::End { t }
+            | ReplayEntry::MatchLength { t, .. }
+            | ReplayEntry::Machine { t, .. }
+            | ReplayEntry::Join { t, .. }
+            | ReplayEntry::Leave { t, .. }
+            | ReplayEntry::Move { t, .. }
+            | ReplayEntry::Rubber { t, .. }
+            | ReplayEntry::Summary { t, .. }
+            | ReplayEntry::Chat { t, .. }
+            | ReplayEntry::Emote { t, .. }
+            | ReplayEntry::HitBegin { t, .. }
+            | ReplayEntry::HitEnd { t, .. } => *t,
+        }
+    }
+}
+
+fn level_bat() -> [f32; 4] {
+    LEVEL_BAT
+}
+
+fn default_room_type() -> String {
+    //Replays recorded before room types existed are all singles.
+    DEFAULT_ROOM_TYPE.to_string()
+}
+
+fn default_mode() -> String {
+    DEFAULT_MODE.to_string()
+}
+
+pub fn keyframe_interval() -> u64 {
+    //Ticks between keyframes, one a second whatever the tick rate, so seeking costs the same at any rate.
+    tick_rate() as u64
+}
+
+pub fn replay_path(replay_id: Uuid) -> PathBuf {
+    PathBuf::from(REPLAY_DIR).join(format!("{}.replay", replay_id))
+}
+
+pub fn list_replays() -> Vec<String> {
+    //Replay ids are the room ids, taken from the file names.
+    let Ok(dir) = fs::read_dir(REPLAY_DIR) else {
+        return Vec::new();
+    };
+
+    dir.filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "replay"))
+        .filter_map(|path| path.file_stem().map(|stem| stem.to_string_lossy().to_string()))
+        .collect()
+}
+
+pub fn is_valid_speed(speed: f64) -> bool {
+    REPLAY_SPEEDS.contains(&speed)
+}
+
+
+//What a recorder sends it's writer task.
+enum ReplayWrite {
+    Line(String),
+    Flush,
+}
+
+//Writes a room's replay entries to disk as they happen.
+//The writing is done by a task of it's own with tokio::fs, so a slow disk holds up the file and never the room's ticks.
+pub struct ReplayRecorder {
+    lines: UnboundedSender<ReplayWrite>,
+    writer: JoinHandle<()>,
+}
+
+impl ReplayRecorder {
+    pub fn create(room_id: Uuid, seed: u64, room_type: &str, mode: &str) -> Self {
+        let (lines, line_rx) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(write_replay(replay_path(room_id), line_rx).instrument(Span::current()));
+
+        let mut recorder = ReplayRecorder { lines, writer };
+
+        recorder.record(&ReplayEntry::Header {
+            version: REPLAY_VERSION,
+            room_id,
+            tick_rate: tick_rate(),
+            seed,
+            room_type: room_type.to_string(),
+            mode: mode.to_string(),
+        });
+
+        recorder
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.lines.send(ReplayWrite::Flush); //Only fails if the file couldn't be made, which has been logged.
+    }
+
+    pub fn record(&mut self, entry: &ReplayEntry) {
+        if let Ok(line) = serde_json::to_string(entry) {
+            let _ = self.lines.send(ReplayWrite::Line(line));
+        }
+
+        //Flushing on keyframes so a crashed server still leaves a usable replay.
+        if matches!(entry, ReplayEntry::Keyframe { .. }) {
+            self.flush();
+        }
+    }
+
+    pub fn finish(self) -> JoinHandle<()> {
+        //Stops recording, the handle finishes once everything recorded is on disk.
+        drop(self.lines);
+        self.writer
+    }
+}
+
+async fn write_replay(path: PathBuf, mut lines: UnboundedReceiver<ReplayWrite>) {
+    //Old replays are cleared out first, so there's room for this one.
+    let _ = tokio::task::spawn_blocking(|| prune_replays(Path::new(REPLAY_DIR), &config::get().replays)).await;
+
+    let file = match tokio::fs::create_dir_all(REPLAY_DIR).await {
+        Ok(()) => tokio::fs::File::create(&path).await,
+        Err(e) => Err(e),
+    };
+    let mut writer = match file {
+        Ok(file) => tokio::io::BufWriter::new(file),
+        Err(e) => {
+            error!(error = %e, "Failed to start replay");
+            return;
+        }
+    };
+
+    while let Some(write) = lines.recv().await {
+        let result = match write {
+            ReplayWrite::Line(line) => writer.write_all(format!("{}\n", line).as_bytes()).await,
+            ReplayWrite::Flush => writer.flush().await,
+        };
+
+        if let Err(e) = result {
+            error!(error = %e, "Failed to write replay entry");
+        }
+    }
+
+    //The room has finished with it (or gone).
+    if let Err(e) = writer.flush().await {
+        error!(error = %e, "Failed to flush replay");
+    }
+}
+
+pub fn prune_replays(dir: &Path, settings: &ReplaysConfig) {
+    //Deletes replays past the newest max_files - 1 and any older than max_age_days, 0 turns either limit off.
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    let mut replays: Vec<(PathBuf, SystemTime)> = entries.filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "replay"))
+        .filter_map(|path| {
+            let modified = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok()?;
+            Some((path, modified))
+        })
+        .collect();
+    replays.sort_by_key(|(_, modified)| std::cmp::Reverse(*modified)); //Newest first.
+
+    let max_age = Duration::from_secs(settings.max_age_days * 24 * 60 * 60);
+    let now = SystemTime::now();
+
+    for (index, (path, modified)) in replays.iter().enumerate() {
+        let too_many = settings.max_files > 0 && index + 1 >= settings.max_files;
+        let too_old = settings.max_age_days > 0 && now.duration_since(*modified).is_ok_and(|age| age > max_age);
+
+        if (too_many || too_old) && let Err(e) = fs::remove_file(path) {
+            warn!(error = %e, path = %path.display(), "Failed to delete old replay");
+        }
+    }
+}
+
+
+//Plays a recorded match back through a room, one tick at a time.
+pub struct ReplayPlayer {
+    entries: Vec<ReplayEntry>,
+    room: Room,
+    cursor: usize, //Index of the next entry to apply.
+    tick: u64,
+    end_tick: u64,
+}
+
+impl ReplayPlayer {
+    pub async fn load(replay_id: Uuid) -> Result<Self, String> {
+        //Read without blocking, a long replay would otherwise hold up the runtime thread it's loaded on.
+        let contents = tokio::fs::read_to_string(replay_path(replay_id)).await.map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => "Replay not found".to_string(),
+            _ => format!("Failed to read replay: {}", e),
+        })?;
+
+        let mut entries = Vec::new();
+        for line in contents.lines() {
+            let entry = serde_json::from_str::<ReplayEntry>(line).map_err(|e| format!("Corrupt replay: {}", e))?;
+            entries.push(entry);
+        }
+
+        let (seed, room_type, mode) = match entries.first() {
+            Some(ReplayEntry::Header { version, seed, room_type, mode, tick_rate: recorded_rate, .. }) if *version == REPLAY_VERSION => {
+                //Steps are a fixed size, so a replay only plays back the same at the rate it was recorded at.
+                if *recorded_rate != tick_rate() {
+                    return Err(format!("Replay was recorded at {} ticks per second, the server runs at {}", recorded_rate, tick_rate()));
+                }
+                (*seed, room_type.clone(), mode.clone())
+            }
+            _ => return Err("Unsupported replay version".to_string()),
+        };
+
+        let end_tick = entries.iter().map(|entry| entry.tick()).max().unwrap_or(0);
+
+        let mut room = Room::new().await;
+        room.id = replay_id; //Snapshots carry the id of the recorded room.
+        room.reseed(seed);
+        room.set_room_type(&room_type)?;
+        room.set_mode(&mode)?;
+        room.auto_start = false; //Only starts on the recorded start entries, the same as it did live.
+
+        let mut player = ReplayPlayer {
+            entries,
+            room,
+            cursor: 0,
+            tick: 0,
+            end_tick,
+        };
+        player.seek(0).await;
+
+        Ok(player)
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn end_tick(&self) -> u64 {
+        self.end_tick
+    }
+
+    pub async fn seek(&mut self, target_tick: u64) {
+        //Jumps to the last keyframe before the target, then simulates forward to it.
+        let target_tick = target_tick.min(self.end_tick);
+
+        let keyframe_index = self.entries.iter()
+            .rposition(|entry| matches!(entry, ReplayEntry::Keyframe { .. }) && entry.tick() <= target_tick);
+
+        match keyframe_index {
+            Some(index) => {
+                let keyframe = &self.entries[index];
+                self.room.restore_keyframe(keyframe);
+                self.cursor = index + 1;
+                self.tick = keyframe.tick();
+            }
+            None => {
+                self.cursor = 0;
+                self.tick = 0;
+            }
+        }
+
+        while self.tick < target_tick {
+            self.advance().await;
+        }
+    }
+
+    pub async fn advance(&mut self) -> Option<Vec<serde_json::Value>> {
+        //Applies every entry up to the current tick, then steps the room. Returns None once the replay has ended.
+        if self.tick >= self.end_tick {
+            return None;
+        }
+
+        while let Some(entry) = self.entries.get(self.cursor) {
+            if entry.tick() > self.tick {
+                break;
+            }
+
+            match entry {
+                ReplayEntry::Header { .. } | ReplayEntry::Keyframe { .. } => {} //Keyframes are only needed when seeking.
+                ReplayEntry::Summary { .. } => {} //The room works the stats out again as it plays.
+                ReplayEntry::Chat { id, n, m, .. } => {
+                    let sender = Player { id: *id, display_name: n.clone() };
+                    self.room.events.push(chat::chat_msg(self.room.id, &sender, m));
+                }
+                ReplayEntry::Emote { id, n, em, .. } => {
+                    let sender = Player { id: *id, display_name: n.clone() };
+                    self.room.events.push(chat::emote_msg(self.room.id, &sender, em));
+                }
+                ReplayEntry::MatchLength { n, .. } => {
+                    self.room.match_length = *n;
+                }
+                ReplayEntry::Machine { s, .. } => {
+                    let _ = self.room.configure_ball_machine(s.clone());
+                }
+                ReplayEntry::Start { .. } => {
+                    if self.room.state != "In Progress" {
+                        self.room.start_room();
+                    }
+                }
+                ReplayEntry::End { .. } => {
+                    let _ = self.room.force_end();
+                }
+                ReplayEntry::Join { id, .. } => {
+                    self.room.add_player(Player {
+                        id: *id,
+                        display_name: "Replay".to_string(),
+                    });
+                }
+                ReplayEntry::Leave { p, .. } => {
+                    //Leaving mid-match hands it to the other side here too, the same as it did live.
+                    if let Some(player) = self.room.player_by_number(*p) {
+                        self.room.remove_player(player.id);
+                    }
+                }
+                ReplayEntry::Move { p, d, r, .. } => {
+                    if let Some(player) = self.room.player_by_number(*p) {
+                        self.room.player_move(player, d[0], d[1], d[2], *r);
+                    }
+                }
+                ReplayEntry::Rubber { p, n, .. } => {
+                    if let Some(player) = self.room.player_by_number(*p) {
+                        let _ = self.room.set_rubber(player.id, n);
+                    }
+                }
+                ReplayEntry::HitBegin { p, .. } => {
+                    if let Some(player) = self.room.player_by_number(*p) {
+                        self.room.player_hit(player.id);
+                    }
+                }
+                ReplayEntry::HitEnd { p, .. } => {
+                    if let Some(player) = self.room.player_by_number(*p) {
+                        self.room.player_hit_exec(player.id);
+                    }
+                }
+            }
+
+            self.cursor += 1;
+        }
+
+        self.room.tick_room();
+        self.tick += 1;
+
+        let mut states = self.room.take_events();
+        states.extend(self.room.snapshot());
+        Some(states)
+    }
+}
+
+
+pub enum ReplayCommand {
+    Speed(f64),
+    Seek(u64),
+    Pause(bool),
+}
+
+pub async fn stream_replay(mut player: ReplayPlayer, client_tx: UnboundedSender<String>, mut control_rx: UnboundedReceiver<ReplayCommand>, mut speed: f64) {
+    //Sends the replay to a single client, ticking at the live rate scaled by the playback speed.
+    //Stops when the replay ends, the client goes away or the control channel is dropped.
+    let mut paused = false;
+
+    loop {
+        let tick_duration = Duration::from_secs_f64(1.0 / (tick_rate() as f64 * speed));
+
+        tokio::select! {
+            command = control_rx.recv() => match command {
+                Some(ReplayCommand::Speed(new_speed)) => speed = new_speed,
+                Some(ReplayCommand::Pause(pause)) => paused = pause,
+                Some(ReplayCommand::Seek(tick)) => {
+                    player.seek(tick).await;
+                    if client_tx.send(progress_msg(&player)).is_err() {
+                        break;
+                    }
+                }
+                None => break,
+            },
+
+            _ = time::sleep(tick_duration), if !paused => {
+                let Some(states) = player.advance().await else {
+                    let _ = client_tx.send(serde_json::json!({ "type": "replay_end" }).to_string());
+                    break;
+                };
+
+                if states.iter().any(|state| client_tx.send(state.to_string()).is_err()) {
+                    break;
+                }
+
+                if player.tick().is_multiple_of(keyframe_interval()) && client_tx.send(progress_msg(&player)).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn progress_msg(player: &ReplayPlayer) -> String {
+    serde_json::json!({
+        "type": "replay_progress",
+        "tick": player.tick(),
+        "end_tick": player.end_tick(),
+        "tick_rate": tick_rate(),
+    }).to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room_controller::room::bot::BotDifficulty;
+
+    #[test]
+    fn old_replays_are_cleared_out_for_new_ones() {
+        let dir = std::env::temp_dir().join(format!("replays-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        //Four from the last few hours and one from well past the age limit.
+        let hour = Duration::from_secs(60 * 60);
+        for (name, age) in [("a", hour), ("b", hour * 2), ("c", hour * 3), ("d", hour * 4), ("e", hour * 24 * 40)] {
+            let file = fs::File::create(dir.join(format!("{}.replay", name))).unwrap();
+            file.set_modified(SystemTime::now() - age).unwrap();
+        }
+        fs::write(dir.join("notes.txt"), "not a replay").unwrap();
+
+        prune_replays(&dir, &ReplaysConfig { max_files: 3, max_age_days: 30 });
+
+        let mut left: Vec<String> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().to_string()).collect();
+        left.sort();
+        let _ = fs::remove_dir_all(&dir);
+
+        //The newest two stay, leaving room for the one about to be made.
+        assert_eq!(left, ["a.replay", "b.replay", "notes.txt"]);
+    }
+
+    #[tokio::test]
+    async fn seeking_plays_out_the_same_as_playing_from_the_start() {
+        //Two bots rally so there are swings, moves and touches in flight at the keyframes.
+        let mut room = Room::new().await;
+        room.start_recording();
+        room.add_bot(BotDifficulty::preset("hard").unwrap());
+        room.add_bot(BotDifficulty::preset("hard").unwrap());
+        for _ in 0..600 {
+            room.tick_room();
+        }
+        room.finish_recording().unwrap().await.unwrap();
+        let replay_id = room.id;
+
+        let mut straight = ReplayPlayer::load(replay_id).await.unwrap();
+        let mut seeked = ReplayPlayer::load(replay_id).await.unwrap();
+        let _ = fs::remove_file(replay_path(replay_id));
+
+        //Seeking to a keyframe caught mid-swing, then playing on, has to match playing there from the start.
+        let targets: Vec<u64> = straight.entries.iter()
+            .filter(|entry| matches!(entry, ReplayEntry::Keyframe { players, .. } if players.iter().any(|frame| frame.swing.is_some())))
+            .map(|entry| entry.tick())
+            .filter(|tick| *tick > 0)
+            .take(3)
+            .collect();
+        assert!(!targets.is_empty(), "no keyframe caught a swing");
+
+        for target in targets {
+            while straight.tick() < target {
+                straight.advance().await;
+            }
+            seeked.seek(target).await;
+
+            for _ in 0..keyframe_interval() {
+                assert_eq!(seeked.advance().await, straight.advance().await, "differs after seeking to {}", target);
+            }
+            assert_eq!(seeked.room.stats, straight.room.stats);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_set_match_length_plays_back_and_survives_seeking() {
+        let mut room = Room::new().await;
+        room.start_recording();
+        room.make_fixture("ABCDEF".to_string(), 3, Vec::new());
+        room.add_bot(BotDifficulty::preset("hard").unwrap());
+        room.add_bot(BotDifficulty::preset("hard").unwrap());
+        for _ in 0..300 {
+            room.tick_room();
+        }
+        room.finish_recording().unwrap().await.unwrap();
+        let replay_id = room.id;
+
+        let mut straight = ReplayPlayer::load(replay_id).await.unwrap();
+        let mut seeked = ReplayPlayer::load(replay_id).await.unwrap();
+        let _ = fs::remove_file(replay_path(replay_id));
+
+        straight.advance().await;
+        assert_eq!(straight.room.match_length, 3);
+
+        //Seeking lands past the entry that set it, so the keyframe has to carry it.
+        let target = keyframe_interval() * 2;
+        seeked.seek(target).await;
+        assert_eq!(seeked.room.match_length, 3);
+        while straight.tick() < target {
+            straight.advance().await;
+        }
+        assert_eq!(seeked.advance().await, straight.advance().await);
+    }
+
+    #[tokio::test]
+    async fn a_private_room_plays_back_starting_when_its_owner_started_it() {
+        //The owner starts a few ticks after the second player sits down, playback has to wait for that too.
+        let players = [Player::new(), Player::new()];
+        let mut room = Room::new().await;
+        room.start_recording();
+        room.make_private(players[0].id, "ABCDEF".to_string(), 11);
+        for player in &players {
+            room.add_player(player.clone());
+        }
+
+        let mut live = Vec::new();
+        for tick in 0..90 {
+            if tick == 10 {
+                room.start_room();
+            }
+            room.tick_room();
+            let mut states = room.take_events();
+            states.extend(room.snapshot());
+            live.push(states);
+        }
+        room.finish_recording().unwrap().await.unwrap();
+
+        let mut replay = ReplayPlayer::load(room.id).await.unwrap();
+        let _ = fs::remove_file(replay_path(room.id));
+        for (tick, states) in live.into_iter().enumerate() {
+            assert_eq!(replay.advance().await, Some(states), "differs at tick {}", tick);
+        }
+    }
+}
@@ -1,113 +1,410 @@
 //This file is the "Room Controller".
-//It holds all the necessary data to manage room sessions on the server. 
+//It holds all the necessary data to manage room sessions on the server.
 //It will control which player is put in what room, when to start a session, how players join etc.
 
 use std::collections::HashMap;
+
+use serde_json::Value;
 use uuid::Uuid;
+use tracing::info;
+
+pub mod room;
+use room::{seats_for_room_type, Room, DEFAULT_ROOM_TYPE};
+use room::mode::GameMode;
+use room::ball_machine::MachineSettings;
+use room::bot::BotDifficulty;
+
+pub mod room_task;
+use room_task::{ClientSender, MachineChanges, RoomCommand, RoomHandle, RoomInfo};
 
-mod room;
-use room::Room;
+pub const INVITE_CODE_LENGTH: usize = 6;
+const INVITE_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789"; //No 0/O, 1/I/L so codes can be read out loud.
 
+use crate::config;
 use crate::Player;
 
+//Where a player ended up after joining, the commands sender is kept by their connection to send input straight to the room.
+pub struct JoinedRoom {
+    pub room_id: Uuid,
+    pub player_index: i32,
+    pub commands: tokio::sync::mpsc::UnboundedSender<RoomCommand>,
+}
+
 pub struct RoomController {
-    rooms: HashMap<Uuid,Room>,
-    rooms_list: Vec<Room>,
+    pub rooms_list: Vec<RoomHandle>, //Rooms run as their own tasks (see room_task.rs), the controller only keeps a handle to each.
+    pub members: HashMap<Uuid, Uuid>, //Player or spectator id to the room they're in.
+    pub draining: bool, //Set when the server is shutting down, no one can join or create a room after that.
+}
+
+impl Default for RoomController {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RoomController {
     pub fn new() -> Self  {
 
         RoomController { //Instantiating constructor variables.
-            rooms: HashMap::new(),
             rooms_list: Vec::new(),
+            members: HashMap::new(),
+            draining: false,
         }
-        
+
     }
 
-    pub async fn create_room(&mut self) { 
-        let new_room = Room::new(); //Creating new room.
-        self.rooms_list.push(new_room.await);
-        //self.rooms.insert(new_room.await.id,new_room.await);
-        
+    pub async fn create_room(&mut self, room_type: &str, mode: &str) -> Result<Room, String> {
+        //Sets up a room for matchmaking to put players in, it's only spawned once they're added.
+        let mut new_room = Room::new().await; //Creating new room.
+        new_room.set_room_type(room_type)?;
+        new_room.set_mode(mode)?;
+        new_room.start_recording(); //Every live room keeps a replay.
+        new_room.span.in_scope(|| info!(room_type, mode, "Room created"));
+
+        Ok(new_room)
      }
 
-    pub fn add_player_to_room(&mut self, player: Player) {
+    pub fn spawn_room(&mut self, room: Room, members: Vec<(Uuid, ClientSender)>) -> RoomHandle {
+        //Starts the room's task with the players already in it.
+        for (member_id, _) in &members {
+            self.members.insert(*member_id, room.id);
+        }
+
+        let handle = room_task::spawn(room, members.into_iter().collect());
+        self.rooms_list.push(handle.clone());
+        handle
+    }
+
+    fn joined(&mut self, handle: &RoomHandle, player_id: Uuid, player_index: i32) -> JoinedRoom {
+        self.members.insert(player_id, handle.id);
+        JoinedRoom { room_id: handle.id, player_index, commands: handle.commands.clone() }
+    }
+
+    pub async fn add_player_to_room(&mut self, player: Player, sender: ClientSender, room_type: &str, mode: &str) -> Result<JoinedRoom, String> {
 
-        //Search through rooms with room_free == true.
+        //Search through rooms with room_free == true, of the type and mode the player asked for.
         //Add player to room.
+        //If no room is free, a new one is created.
+        //Returns the room id and the player index in that room.
 
-        for room in self.rooms_list.iter_mut() {
-            let room_state = room.state.to_string();
+        self.check_open()?;
 
-            if room.state == "Not Started" {
-                if room.pop < room.capacity {
-                    room.add_player(player);
-                    break; //break function once player has found a room.
-                }
+        if seats_for_room_type(room_type).is_none() {
+            return Err(format!("Unknown room type: {}", room_type));
+        }
+        let mode = GameMode::preset(mode)?;
+        let player_id = player.id;
+
+        let candidates: Vec<RoomHandle> = self.rooms_list.iter()
+            .filter(|handle| {
+                let info = handle.info();
+                info.state == "Not Started" && info.pop < info.capacity && !info.is_private && info.room_type == room_type && info.mode == mode
+            })
+            .cloned()
+            .collect();
+
+        //The room has the final say, it may have filled or started since it last published.
+        for handle in candidates {
+            let (player, sender) = (player.clone(), sender.clone());
+            if let Ok(Ok(player_index)) = handle.request(|reply| RoomCommand::Join { player, sender, reply }).await {
+                return Ok(self.joined(&handle, player_id, player_index)); //return once player has found a room.
             }
         }
+
+        let mut room = self.create_room(room_type, mode.name).await?;
+        let player_index = room.add_player(player);
+        let handle = self.spawn_room(room, vec![(player_id, sender)]);
+
+        Ok(self.joined(&handle, player_id, player_index))
+    }
+
+    pub async fn create_private_room(&mut self, player: Player, sender: ClientSender, room_type: &str, mode: &str, match_length: Option<u32>) -> Result<(JoinedRoom, String), String> {
+        //Creates a room only reachable with it's invite code, the creator becomes it's owner.
+        //The match length defaults to the mode's.
+        self.check_open()?;
+
+        let match_length = match match_length {
+            Some(match_length) => match_length,
+            None => GameMode::preset(mode)?.match_length(),
+        };
+        check_match_length(match_length)?;
+
+        let invite_code = self.generate_invite_code();
+        let owner_id = player.id;
+
+        let mut room = self.create_room(room_type, mode).await?;
+        room.make_private(owner_id, invite_code.clone(), match_length);
+        let player_index = room.add_player(player);
+        let handle = self.spawn_room(room, vec![(owner_id, sender)]);
+
+        Ok((self.joined(&handle, owner_id, player_index), invite_code))
+    }
+
+    pub async fn create_fixture_room(&mut self, mode: &str, match_length: Option<u32>, entrants: Vec<Uuid>) -> Result<(Uuid, String), String> {
+        //Makes an empty room for a tournament fixture, it's players join with the invite code and the match starts once they're both in.
+        self.check_open()?;
+
+        let match_length = match match_length {
+            Some(match_length) => match_length,
+            None => GameMode::preset(mode)?.match_length(),
+        };
+        check_match_length(match_length)?;
+
+        let invite_code = self.generate_invite_code();
+        let mut room = self.create_room(DEFAULT_ROOM_TYPE, mode).await?;
+        room.make_fixture(invite_code.clone(), match_length, entrants);
+        let handle = self.spawn_room(room, Vec::new());
+
+        Ok((handle.id, invite_code))
+    }
+
+    pub async fn join_room_by_code(&mut self, player: Player, sender: ClientSender, code: &str) -> Result<JoinedRoom, String> {
+        self.check_open()?;
+        let code = code.trim().to_uppercase();
+
+        let Some(handle) = self.rooms_list.iter().find(|handle| handle.info().invite_code.as_deref() == Some(code.as_str())).cloned() else {
+            return Err("No room with that invite code".to_string());
+        };
+
+        let player_id = player.id;
+        let player_index = handle.request(|reply| RoomCommand::Join { player, sender, reply }).await??; //Refused if it's started or full.
+
+        Ok(self.joined(&handle, player_id, player_index))
     }
 
-    // pub fn handle_input(&mut self, player:Player) { 
+    pub async fn set_match_length(&mut self, owner_id: Uuid, match_length: u32) -> Result<(), String> {
+        check_match_length(match_length)?;
 
-    //     for room in self.rooms_list.iter_mut() {
+        let handle = self.room_of(owner_id).ok_or("Not in a room")?;
+        handle.request(|reply| RoomCommand::SetMatchLength { owner_id, match_length, reply }).await?
+    }
 
-    //         for room_player in room.players_in_room.iter_mut() {
+    pub async fn kick_player(&mut self, owner_id: Uuid, player_id: Uuid) -> Result<(), String> {
+        let handle = self.room_of(owner_id).ok_or("Not in a room")?;
+        handle.request(|reply| RoomCommand::Kick { owner_id, player_id, reply }).await??;
 
-    //             if room_player.id == player.id {
-    //                 room.handle_player_input(player);
-    //             }
-    //         }
-    //     } 
-    // }
+        self.members.remove(&player_id);
+        Ok(())
+    }
 
-    pub fn delete_room(&mut self) {
+    pub async fn start_private_room(&mut self, owner_id: Uuid) -> Result<(), String> {
+        let handle = self.room_of(owner_id).ok_or("Not in a room")?;
+        handle.request(|reply| RoomCommand::Start { owner_id, reply }).await?
+    }
 
+    pub async fn configure_ball_machine(&mut self, player_id: Uuid, settings: MachineChanges) -> Result<MachineSettings, String> {
+        //Changes only the settings that were given, the rest stay as they are.
+        let handle = self.room_of(player_id).ok_or("Not in a room")?;
+        handle.request(|reply| RoomCommand::ConfigureMachine { player_id, settings, reply }).await?
+    }
 
+    pub async fn set_rubber(&mut self, player_id: Uuid, rubber: String) -> Result<(), String> {
+        let handle = self.room_of(player_id).ok_or("Not in a room")?;
+        handle.request(|reply| RoomCommand::SetRubber { player_id, rubber, reply }).await?
+    }
 
+    pub async fn chat(&mut self, player_id: Uuid, text: String) -> Result<(), String> {
+        let handle = self.room_of(player_id).ok_or("Not in a room")?;
+        handle.request(|reply| RoomCommand::Chat { player_id, text, reply }).await?
+    }
 
+    pub async fn emote(&mut self, player_id: Uuid, emote: String) -> Result<(), String> {
+        let handle = self.room_of(player_id).ok_or("Not in a room")?;
+        handle.request(|reply| RoomCommand::Emote { player_id, emote, reply }).await?
     }
 
-    pub fn process_rooms(&mut self, dt: f32) {
-        if self.rooms_list.is_empty() {
+    pub async fn mute(&mut self, player_id: Uuid, target_id: Uuid, muted: bool) -> Result<(), String> {
+        let handle = self.room_of(player_id).ok_or("Not in a room")?;
+        handle.request(|reply| RoomCommand::Mute { player_id, target_id, muted, reply }).await?
+    }
 
-        } else {
-            for room in &mut self.rooms_list {
-            room.tick_room(dt); //Stepping physics world in room.
+    pub fn send_room_update(&self, player_id: Uuid) {
+        //Tells everyone in the player's room about it's current settings and players.
+        if let Some(handle) = self.room_of(player_id) {
+            handle.send(RoomCommand::SendUpdate);
         }
+    }
+
+    fn generate_invite_code(&self) -> String {
+        //Random code from the readable alphabet, retried until no other room is using it.
+        loop {
+            let code: String = Uuid::new_v4().as_bytes().iter()
+                .take(INVITE_CODE_LENGTH)
+                .map(|byte| INVITE_CODE_ALPHABET[*byte as usize % INVITE_CODE_ALPHABET.len()] as char)
+                .collect();
+
+            if !self.rooms_list.iter().any(|handle| handle.info().invite_code.as_deref() == Some(code.as_str())) {
+                return code;
+            }
+        }
+    }
+
+    pub async fn create_bot_room(&mut self, player: Player, sender: ClientSender, difficulty: BotDifficulty, mode: &str) -> Result<JoinedRoom, String> {
+        //Bot matches get a room of their own, it is full straight away so matchmaking never puts anyone else in it.
+        self.check_open()?;
+
+        if GameMode::preset(mode)?.practice {
+            return Err("Practice is single player".to_string());
         }
 
+        let mut room = self.create_room(DEFAULT_ROOM_TYPE, mode).await?;
+        let player_id = player.id;
+        let player_index = room.add_player(player);
+        room.add_bot(difficulty);
+        let handle = self.spawn_room(room, vec![(player_id, sender)]);
+
+        Ok(self.joined(&handle, player_id, player_index))
     }
 
-    pub fn player_move(&mut self, player: Player, dx: f64, dy: f64, dz: f64) {
+    pub async fn add_spectator_to_room(&mut self, room_id: Uuid, spectator: Player, sender: ClientSender, code: Option<String>) -> Result<(), String> {
+
+        if self.room_of(spectator.id).is_some() {
+            return Err("Already in a room".to_string());
+        }
+
+        let handle = self.find_room_by_id(room_id).ok_or("Room not found")?.clone();
+        let spectator_id = spectator.id;
+        handle.request(|reply| RoomCommand::Spectate { spectator, sender, code, reply }).await??;
+
+        self.members.insert(spectator_id, room_id);
+        Ok(())
+    }
 
-        let cl_player = player.clone();
+    pub fn live_rooms(&self) -> Vec<Value> {
+        //Rooms with a match underway, these are the ones worth watching.
+        self.rooms_list.iter()
+            .map(|handle| handle.info())
+            .filter(|info| info.state == "In Progress" && !info.is_private)
+            .map(|info| info.summary.clone())
+            .collect()
+    }
 
-        if let Some(room) = self.find_room_by_player(cl_player) {
-            room.player_move(player, dx, dy, dz);
+    pub async fn remove_player(&mut self, player_id: Uuid) -> Vec<Uuid> {
+        //Removes a player or spectator from whichever room they are in.
+        //If the last (human) player leaves the room is deleted, the ids of any spectators left behind are returned so they can be told.
+        //Spectators coming and going never close a room.
+        let Some(room_id) = self.members.remove(&player_id) else {
+            return Vec::new();
+        };
+
+        let Some(handle) = self.find_room_by_id(room_id).cloned() else {
+            return Vec::new();
+        };
+
+        match handle.request(|reply| RoomCommand::Leave { player_id, reply }).await {
+            Ok(false) => Vec::new(),
+            _ => self.delete_room(room_id).await,
         }
     }
 
-    pub fn find_room_by_player(&mut self, player:Player) -> Option<&mut Room> {
+    pub async fn delete_room(&mut self, room_id: Uuid) -> Vec<Uuid> {
+        //Stops the room, returning the ids of anyone still inside.
+        let Some(index) = self.rooms_list.iter().position(|handle| handle.id == room_id) else {
+            return Vec::new();
+        };
 
-       
+        let handle = self.rooms_list.remove(index);
+        self.members.retain(|_, member_room| *member_room != room_id);
+        handle.request(|reply| RoomCommand::Close { reply }).await.unwrap_or_default()
+    }
 
-         for room in self.rooms_list.iter_mut() {
-            for room_player in room.players_in_room.iter_mut() {
+    fn check_open(&self) -> Result<(), String> {
+        if self.draining {
+            return Err("Server is shutting down".to_string());
+        }
 
-                if room_player.id == player.id {
-                    return Some(room);
-                    break;
-                    
-                }
-            }
-        } 
+        Ok(())
+    }
 
-        None
+    pub fn room_infos(&self) -> Vec<RoomInfo> {
+        self.rooms_list.iter().map(|handle| handle.info().clone()).collect()
     }
 
+    pub fn matches_in_progress(&self) -> usize {
+        //Matches a shutdown waits for, practice sessions never finish on their own so they aren't counted.
+        self.rooms_list.iter()
+            .filter(|handle| {
+                let info = handle.info();
+                info.state == "In Progress" && !info.mode.practice
+            })
+            .count()
+    }
 
-    
+    pub async fn shutdown_rooms(&mut self) {
+        //Last step of a shutdown, ends any match still going and saves every room's replay.
+        //Each room tells it's own members how their match ended, then stops.
+        for handle in &self.rooms_list {
+            let _ = handle.request(|reply| RoomCommand::Shutdown { reply }).await;
+        }
+    }
+
+    pub fn find_room_by_id(&self, room_id: Uuid) -> Option<&RoomHandle> {
+        self.rooms_list.iter().find(|handle| handle.id == room_id)
+    }
 
-}
\ No newline at end of file
+    pub fn room_of(&self, player_id: Uuid) -> Option<&RoomHandle> {
+        //The room the player is in, as a player or spectator.
+        let room_id = self.members.get(&player_id)?;
+        self.find_room_by_id(*room_id)
+    }
+
+}
+
+pub fn check_match_length(match_length: u32) -> Result<(), String> {
+    let max_match_length = config::get().rules.max_match_length;
+    if !(1..=max_match_length).contains(&match_length) {
+        return Err(format!("Match length must be between 1 and {}", max_match_length));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn draining_refuses_joins_and_ends_the_matches_left() {
+        let mut control = RoomController::new();
+
+        //Built by hand rather than with create_room so the test doesn't write a replay file.
+        let mut room = Room::new().await;
+        room.add_player(Player::new());
+        room.add_player(Player::new());
+
+        let (sender, mut messages) = tokio::sync::mpsc::unbounded_channel();
+        let spectator = Player::new();
+        room.add_spectator(spectator.clone(), None).unwrap();
+        control.spawn_room(room, vec![(spectator.id, sender)]);
+        assert_eq!(control.matches_in_progress(), 1);
+
+        control.draining = true;
+        assert!(matches!(control.add_player_to_room(Player::new(), tokio::sync::mpsc::unbounded_channel().0, DEFAULT_ROOM_TYPE, "classic").await, Err(reason) if reason == "Server is shutting down"));
+
+        control.shutdown_rooms().await;
+        assert_eq!(control.matches_in_progress(), 0);
+
+        //The room's own task tells it's members, after any snapshots it sent while running.
+        let mut ended_early = false;
+        while let Ok(message) = messages.try_recv() {
+            let event: Value = serde_json::from_str(&message).unwrap();
+            ended_early |= event["type"] == "match_over" && event["ended_early"] == true;
+        }
+        assert!(ended_early);
+    }
+
+    #[tokio::test]
+    async fn spectators_leaving_never_close_a_room() {
+        let mut control = RoomController::new();
+
+        //A fixture room nobody has sat down in yet, with someone who has the code watching.
+        let (first, second, spectator) = (Player::new(), Player::new(), Player::new());
+        let mut room = Room::new().await;
+        room.make_fixture("FIX234".to_string(), 11, vec![first.id, second.id]);
+        room.add_spectator(spectator.clone(), Some("FIX234")).unwrap();
+        let handle = control.spawn_room(room, vec![(spectator.id, tokio::sync::mpsc::unbounded_channel().0)]);
+
+        control.remove_player(spectator.id).await;
+        assert!(control.find_room_by_id(handle.id).is_some());
+    }
+}
@@ -1,93 +1,1364 @@
 //This file is the "Room" struct.
-//It holds all the necessary data that a room requires. 
+//It holds all the necessary data that a room requires.
 //A room is a lobby of players, or their "world".
 //It is used to isolate each physics world to it's own instance.
 
 use uuid::Uuid;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, info_span, Span};
 
- mod physics_world; //importing code from physics_world.
- use physics_world::PhysicsWorld;
+pub mod physics_world; //importing code from physics_world.
+ use physics_world::{bat, seat_plane_z, PhysicsWorld, SeededRng};
+ use physics_world::ball_events::BallEvent;
 
-use std::sync::Arc;
-use tokio::sync::Mutex;
+pub mod bot;
+use bot::{Bot, BotAction, BotDifficulty};
 
+pub mod rules;
+use rules::{team_of, Call, MatchRules};
+
+pub mod mode;
+use mode::{GameMode, DEFAULT_MODE};
+
+pub mod ball_machine;
+use ball_machine::{BallMachine, MachineSettings};
+
+pub mod match_stats;
+use match_stats::MatchStats;
+
+pub mod chat;
+use chat::Chat;
+
+use crate::config;
+use crate::metrics;
 use crate::Player;
+use crate::replay::{self, PlayerFrame, ReplayEntry, ReplayRecorder};
+
+pub const ROOM_TYPES: [(&str, i32); 2] = [("singles", 2), ("doubles", 4)]; //Room type and how many seats it has.
+pub const DEFAULT_ROOM_TYPE: &str = "singles";
+pub const SERVE_OFFSET: f32 = 1.0; //How far in front of the server's bat the ball is put for a serve.
+pub const BALL_OUT_MARGIN: f64 = 2.0; //Distance behind the back row before a ball counts as missed.
+pub const SIDE_OUT_LIMIT: f32 = 12.0; //Ball further than this from the centre line (in x or y) is out.
+pub const AIM_HINT_SECS: f32 = 3.0; //How far ahead the practice aim hint looks, a slow ball further out than this gets no hint yet.
+pub const DEAD_BALL_SPEED: f32 = 0.5; //A ball moving towards either end slower than this for a second is lost by the side it's on.
+
+pub fn tick_rate() -> u32 {
+    config::get().tick.tick_rate
+}
+
+pub fn fixed_dt() -> f32 {
+    config::get().tick.fixed_dt()
+}
+
+pub fn seats_for_room_type(room_type: &str) -> Option<i32> {
+    ROOM_TYPES.iter().find(|(name, _)| *name == room_type).map(|(_, seats)| *seats)
+}
 
 pub struct Room {
     pub room_type: String,
+    pub mode: &'static GameMode,
     pub id: Uuid,
     pub capacity: i32,
     pub pop: i32,
     pub is_free: bool,
     pub state: String,
+    pub is_private: bool, //Private rooms are skipped by matchmaking and joined with the invite code.
+    pub invite_code: Option<String>,
+    pub owner_id: Option<Uuid>, //Player who created a private room, they can change settings and kick before the start.
+    pub entrants: Vec<Uuid>, //Only these players can take a seat, set for tournament fixtures.
+    pub auto_start: bool, //Starts the match once every seat is taken, cleared for private rooms whose owner starts it.
+    pub winner_ids: Vec<Uuid>, //Players on the winning side when the match was won, kept even if they leave after.
+    pub match_length: u32,
     pub players_in_room: Vec<Player>,
+    pub spectator_capacity: i32,
+    pub spectators_in_room: Vec<Player>, //Read-only connections, they receive room state but never touch the physics world.
     pub physics_world: PhysicsWorld,
+    pub bots: Vec<Bot>, //Bots also have a seat in players_in_room, this holds their brains.
+    pub tick_count: u64,
+    pub accumulator: f64, //Wall-clock time not yet simulated.
+    pub seed: u64, //Seeds all randomness in the physics world, stored in replays so they play out the same.
+    pub recorder: Option<ReplayRecorder>, //Only live rooms record, rooms used for replay playback leave this empty.
+    pub rules: MatchRules,
+    pub ball_machine: Option<BallMachine>, //Only practice rooms have one.
+    pub stats: MatchStats, //Shots, rallies and points of the current (or last) match.
+    pub chat: Chat, //Rate limits and mutes, the messages themselves go straight out (see room_task.rs).
+    pub events: Vec<serde_json::Value>, //Messages for the room raised during ticks (points, match over), sent out with the next snapshot.
+    pub paused: bool, //Set by an admin, a paused room isn't stepped at all so nothing moves and no time passes in it.
+    pub span: Span, //Lives as long as the room, everything logged while it's entered carries the room id.
 
 }
 
 impl Room {
     pub async fn new() -> Self  {
-        let room_type = "TBC";
+        let room_type = DEFAULT_ROOM_TYPE;
         let id = Uuid::new_v4(); //Creating room_id.
-        let capacity = 2;
+        let capacity = seats_for_room_type(room_type).expect("Default room type exists");
+        let spectator_capacity = config::get().rules.spectator_capacity;
         let state = "Not Started";
         let is_free = true;
         let pop = 0;
 
-        let physics_world = PhysicsWorld::new(); //Creating async phys world, so can be accessed safely across threads.
+        let seed = Uuid::new_v4().as_u64_pair().0;
+
+        let physics_world = PhysicsWorld::new(seed); //Creating async phys world, so can be accessed safely across threads.
 
 
         Room { //Init Room.
             room_type:room_type.to_string(),
+            mode: GameMode::preset(DEFAULT_MODE).expect("Default mode exists"),
             id,
             capacity,
             pop,
             is_free,
             state:state.to_string(),
+            is_private: false,
+            invite_code: None,
+            owner_id: None,
+            entrants: Vec::new(),
+            auto_start: true,
+            winner_ids: Vec::new(),
+            match_length: config::get().rules.default_match_length,
             players_in_room: Vec::new(),
+            spectator_capacity,
+            spectators_in_room: Vec::new(),
             physics_world,
+            bots: Vec::new(),
+            tick_count: 0,
+            accumulator: 0.0,
+            seed,
+            recorder: None,
+            rules: MatchRules::new(capacity),
+            ball_machine: None,
+            stats: MatchStats::new(),
+            chat: Chat::new(),
+            events: Vec::new(),
+            paused: false,
+            span: info_span!(parent: None, "room", room_id = %id), //Not a child of whichever connection made the room, it outlives them.
         }
-        
+
+    }
+
+    pub fn set_room_type(&mut self, room_type: &str) -> Result<(), String> {
+        //Only done before anyone joins, seats are handed out by index.
+        let seats = seats_for_room_type(room_type).ok_or_else(|| format!("Unknown room type: {}", room_type))?;
+
+        self.room_type = room_type.to_string();
+        self.capacity = seats;
+        self.rules = MatchRules::new(seats);
+
+        Ok(())
     }
 
+    pub fn set_mode(&mut self, mode_name: &str) -> Result<(), String> {
+        //Also only done before anyone joins, after the room type. Rebuilds the world with the mode's physics.
+        let mode = GameMode::preset(mode_name)?;
+
+        if mode.practice {
+            if self.room_type != DEFAULT_ROOM_TYPE {
+                return Err("Practice is single player".to_string());
+            }
+            self.capacity = 1;
+            self.ball_machine = Some(BallMachine::new());
+        }
+
+        self.mode = mode;
+        self.match_length = mode.match_length();
+        self.physics_world = PhysicsWorld::with_settings(self.seed, mode.physics());
+
+        Ok(())
+    }
+
+    #[allow(dead_code)]
     pub fn get_room_id(&mut self) -> Uuid {
         self.id
     }
 
-    pub fn add_player(&mut self,player: Player) {
+    pub fn start_recording(&mut self) {
+        //Starts the replay file for this room and writes the starting keyframe.
+        let span = self.span.clone();
+        let _entered = span.enter(); //So anything the writer logs carries the room id.
+
+        self.recorder = Some(ReplayRecorder::create(self.id, self.seed, &self.room_type, self.mode.name));
+        self.record_keyframe();
+    }
+
+    pub fn add_player(&mut self,player: Player) -> i32 {
         //Add player to room logic.
-        //Will add player to world.
+        //Will add player to world and return their player index (used to set sides of the table).
+        let span = self.span.clone();
+        let _entered = span.enter();
+
+        self.physics_world.add_player(player.id);
+        let player_index = self.physics_world.get_player_number(player.id);
+
+        self.record(ReplayEntry::Join { t: self.tick_count, id: player.id, p: player_index });
+
+        info!(player_id = %player.id, player_index, "Player joined room");
         self.players_in_room.push(player);
         self.pop += 1;
 
+        if self.pop >= self.capacity && self.auto_start {
+            self.start_room();
+        }
+
+        player_index
+    }
+
+    pub fn remove_player(&mut self, player_id: Uuid) -> bool {
+        //Removes a player (and their bat) from the room, returns false if they were never in it.
+        let span = self.span.clone();
+        let _entered = span.enter();
 
+        if let Some(index) = self.players_in_room.iter().position(|p| p.id == player_id) {
+            info!(%player_id, "Player left room");
+            let seat = self.physics_world.get_player_number(player_id);
+            self.record(ReplayEntry::Leave { t: self.tick_count, p: seat });
 
+            self.players_in_room.remove(index);
+            self.physics_world.remove_player(player_id);
+            self.chat.forget(player_id);
+            self.pop -= 1;
+
+            //Handing a private room over to whoever is left if the owner goes.
+            if self.owner_id == Some(player_id) {
+                self.owner_id = self.players_in_room.first().map(|p| p.id);
+            }
+
+            //Leaving a match in progress gives it to the side still at the table, so they get their result and summary.
+            if self.state == "In Progress" && !self.mode.practice && !self.players_in_room.is_empty() {
+                self.rules.winner = Some(1 - team_of(seat));
+                self.finish_match();
+            }
+
+            //A tournament fixture is only played once, it keeps it's result for the tournament to take and nobody can take the seat again.
+            if !self.entrants.is_empty() && self.state != "Not Started" {
+                return true;
+            }
+
+            //Room can be filled again by matchmaking once a seat frees up.
+            self.is_free = true;
+            self.state = "Not Started".to_string();
+
+            return true;
+        }
+
+        false
     }
 
-    pub fn handle_player_input(&mut self, player:Player) {
+    pub fn add_bot(&mut self, difficulty: BotDifficulty) -> i32 {
+        //Seats a bot like any other player, it's seed comes from the room's so a room plays out the same for the same seed.
+        let bot = Bot::new(difficulty, self.seed.wrapping_add(self.bots.len() as u64 + 1));
+        let player_index = self.add_player(bot.player.clone());
+        self.bots.push(bot);
+
+        player_index
+    }
+
+    pub fn has_humans(&self) -> bool {
+        self.players_in_room.iter().any(|p| !self.bots.iter().any(|bot| bot.player.id == p.id))
+    }
+
+    pub fn has_player(&self, player_id: Uuid) -> bool {
+        self.players_in_room.iter().any(|p| p.id == player_id)
+    }
+
+    pub fn add_spectator(&mut self, spectator: Player, code: Option<&str>) -> Result<(), String> {
+        //Spectators are only tracked here, they are never added to the physics world so they can't move or hit.
+        if self.has_player(spectator.id) || self.has_spectator(spectator.id) {
+            return Err("Already in room".to_string());
+        }
+
+        //Private rooms (tournament fixtures too) can only be watched by someone who was given the code.
+        if self.is_private && code.map(|code| code.trim().to_uppercase()) != self.invite_code {
+            return Err("This room is private, watching it needs the invite code".to_string());
+        }
+
+        if self.spectators_in_room.len() as i32 >= self.spectator_capacity {
+            return Err("Room spectator limit reached".to_string());
+        }
+
+        let _entered = self.span.enter();
+        info!(spectator_id = %spectator.id, "Spectator joined room");
+
+        self.spectators_in_room.push(spectator);
+        Ok(())
+    }
+
+    pub fn remove_spectator(&mut self, spectator_id: Uuid) -> bool {
+        let spectator_count = self.spectators_in_room.len();
+        self.spectators_in_room.retain(|s| s.id != spectator_id);
+        self.chat.forget(spectator_id);
+
+        spectator_count != self.spectators_in_room.len()
+    }
+
+    pub fn has_spectator(&self, spectator_id: Uuid) -> bool {
+        self.spectators_in_room.iter().any(|s| s.id == spectator_id)
+    }
+
+    pub fn member_ids(&self) -> Vec<Uuid> {
+        //Everyone that should receive room state, players first then spectators.
+        self.players_in_room.iter()
+            .chain(self.spectators_in_room.iter())
+            .map(|p| p.id)
+            .collect()
+    }
+
+    fn member(&self, member_id: Uuid) -> Option<&Player> {
+        self.players_in_room.iter().chain(self.spectators_in_room.iter()).find(|p| p.id == member_id)
+    }
+
+    pub fn chat(&mut self, sender_id: Uuid, text: &str) -> Result<serde_json::Value, String> {
+        //Checks and filters a chat message, returning it ready to send to the room. It's recorded so replays show the chat too.
+        let sender = self.member(sender_id).ok_or("Not in a room")?.clone();
+        let settings = &config::get().chat;
+
+        let text = chat::check_text(text, settings)?;
+        self.chat.check_rate(sender_id, std::time::Instant::now(), settings)?;
+
+        self.record(ReplayEntry::Chat { t: self.tick_count, id: sender.id, n: sender.display_name.clone(), m: text.clone() });
+        Ok(chat::chat_msg(self.id, &sender, &text))
+    }
+
+    pub fn emote(&mut self, sender_id: Uuid, emote: &str) -> Result<serde_json::Value, String> {
+        let sender = self.member(sender_id).ok_or("Not in a room")?.clone();
+
+        chat::check_emote(emote)?;
+        self.chat.check_rate(sender_id, std::time::Instant::now(), &config::get().chat)?;
+
+        self.record(ReplayEntry::Emote { t: self.tick_count, id: sender.id, n: sender.display_name.clone(), em: emote.to_string() });
+        Ok(chat::emote_msg(self.id, &sender, emote))
+    }
+
+    pub fn mute(&mut self, member_id: Uuid, target_id: Uuid, muted: bool) -> Result<(), String> {
+        if self.member(member_id).is_none() {
+            return Err("Not in a room".to_string());
+        }
+
+        if member_id == target_id {
+            return Err("You can't mute yourself".to_string());
+        }
+
+        if self.member(target_id).is_none() {
+            return Err("Player is not in this room".to_string());
+        }
+
+        self.chat.set_muted(member_id, target_id, muted);
+        Ok(())
+    }
+
+    pub fn make_private(&mut self, owner_id: Uuid, invite_code: String, match_length: u32) {
+        self.is_private = true;
+        self.auto_start = false; //Waits for the owner to start it.
+        self.owner_id = Some(owner_id);
+        self.invite_code = Some(invite_code);
+        self.set_match_length(match_length);
+    }
+
+    pub fn make_fixture(&mut self, invite_code: String, match_length: u32, entrants: Vec<Uuid>) {
+        //A private room with no owner, kept for the fixture's players. It starts once both are in.
+        self.is_private = true;
+        self.auto_start = true;
+        self.invite_code = Some(invite_code);
+        self.set_match_length(match_length);
+        self.entrants = entrants;
+    }
+
+    pub fn set_match_length(&mut self, match_length: u32) {
+        //Recorded, playback sets the mode's length and would otherwise end the match at a different score.
+        self.match_length = match_length;
+        self.record(ReplayEntry::MatchLength { t: self.tick_count, n: match_length });
+    }
+
+    pub fn check_owner(&self, player_id: Uuid) -> Result<(), String> {
+        //Settings and kicks are only allowed for the owner of a private room that hasn't started.
+        if self.owner_id != Some(player_id) {
+            return Err("Only the room owner can do that".to_string());
+        }
+
+        if self.state != "Not Started" {
+            return Err("Match has already started".to_string());
+        }
 
+        Ok(())
     }
-    
+
     pub fn start_room(&mut self) {
+        let span = self.span.clone();
+        let _entered = span.enter();
+        info!(room_type = %self.room_type, mode = self.mode.name, players = self.pop, "Match started");
 
+        self.is_free = false;
+        self.state = "In Progress".to_string();
+        self.record(ReplayEntry::Start { t: self.tick_count });
+
+        //Every start is a fresh match, seat 0 serves first (practice starts with the far end feeding the player).
+        self.rules = MatchRules::new(self.capacity);
+        self.rules.started_at = self.tick_count;
+        self.stats = MatchStats::new();
+        self.winner_ids.clear();
+
+        if let Some(machine) = self.ball_machine.as_mut() {
+            //Fresh counts, but the player's machine settings carry over.
+            *machine = BallMachine { settings: machine.settings.clone(), next_feed_tick: self.tick_count, ..BallMachine::new() };
+        } else {
+            self.serve();
+        }
     }
 
+    #[allow(dead_code)]
     pub fn end_room(&mut self) {
         //Disconnect all players from room.
 
     }
 
-    pub fn tick_room(&mut self, dt:f32) { //Function to process world state of room.
-        let mut world = &mut self.physics_world;
-        world.step(dt);
+    pub fn reseed(&mut self, seed: u64) {
+        //Used by replay playback so the room starts from the same seed as the recording.
+        self.seed = seed;
+        self.physics_world.rng = SeededRng::new(seed);
     }
-    
-    pub fn player_move(&mut self, player:Player,dx: f64, dy: f64, dz: f64) { //Function to move player in room.
 
-        let mut world = &mut self.physics_world;
+    pub fn advance(&mut self, elapsed: f32) -> u32 {
+        //Adds wall-clock time to the accumulator and runs as many fixed steps as it covers.
+        //Jitter in the tick loop changes how many steps run per call, never the size of a step.
+        let span = self.span.clone();
+        let _entered = span.enter();
+
+        if self.paused {
+            self.accumulator = 0.0; //So the room doesn't rush to catch up on the pause when it resumes.
+            return 0;
+        }
+
+        self.accumulator += elapsed as f64;
+
+        let fixed_dt = fixed_dt() as f64;
+        let max_steps = config::get().tick.max_steps_per_advance;
+
+        let mut steps = 0;
+        while self.accumulator >= fixed_dt && steps < max_steps {
+            self.tick_room();
+            self.accumulator -= fixed_dt;
+            steps += 1;
+        }
+
+        if steps == max_steps {
+            //Dropping any backlog left after a stall instead of trying to catch up on it.
+            self.accumulator = self.accumulator.min(fixed_dt);
+        }
+
+        steps
+    }
+
+    pub fn tick_room(&mut self) { //Function to process world state of room, one fixed step.
+        self.run_bots();
+
+        let step_timer = metrics::get().physics_step_seconds.start_timer();
+        self.physics_world.step();
+        step_timer.observe_duration();
+        self.tick_count += 1;
+        let ball_events = self.report_ball_events();
+
+        if self.state == "In Progress" {
+            if self.mode.practice {
+                self.run_practice();
+            } else {
+                self.apply_rules(&ball_events);
+                self.check_time_limit();
+            }
+        }
+
+        if self.tick_count.is_multiple_of(replay::keyframe_interval()) {
+            self.record_keyframe();
+        }
+    }
+
+    pub fn player_move(&mut self, player:Player,dx: f64, dy: f64, dz: f64, rot: Option<[f64; 4]>) { //Function to move player in room.
+
+        //A rotation that isn't a usable quaternion is dropped (the server has already told the player), the move still goes ahead.
+        let rotation = rot.and_then(|rot| bat::check_rotation(rot).ok());
+
+        let entry = ReplayEntry::Move { t: self.tick_count, p: self.physics_world.get_player_number(player.id), d: [dx, dy, dz], r: rotation.and(rot) };
+        self.record(entry);
+
+        let world = &mut self.physics_world;
         world.add_move_to_queue(player.id,dx,dy,dz);
-        
-        
+
+        if let Some(rotation) = rotation {
+            world.add_turn_to_queue(player.id, rotation);
+        }
+
+
+    }
+
+    pub fn set_rubber(&mut self, player_id: Uuid, rubber: &str) -> Result<(), String> {
+        if !self.has_player(player_id) {
+            return Err("Not in a room".to_string());
+        }
+
+        self.physics_world.set_rubber(player_id, rubber)?;
+
+        let entry = ReplayEntry::Rubber { t: self.tick_count, p: self.physics_world.get_player_number(player_id), n: rubber.to_string() };
+        self.record(entry);
+        Ok(())
+    }
+
+    pub fn player_hit(&mut self, player_id: Uuid) {
+        let entry = ReplayEntry::HitBegin { t: self.tick_count, p: self.physics_world.get_player_number(player_id) };
+        self.record(entry);
+
+        self.physics_world.player_hit(player_id);
+    }
+
+    pub fn player_hit_exec(&mut self, player_id: Uuid) {
+        let span = self.span.clone();
+        let _entered = span.enter();
+
+        let entry = ReplayEntry::HitEnd { t: self.tick_count, p: self.physics_world.get_player_number(player_id) };
+        self.record(entry);
+
+        let quality = self.physics_world.player_hit_exec(player_id);
+
+        if let (Some(quality), Some(machine)) = (quality, self.ball_machine.as_mut()) {
+            machine.on_swing(quality);
+        } else if let Some(quality) = quality.filter(|_| self.state == "In Progress") {
+            self.stats.on_swing(team_of(self.physics_world.get_player_number(player_id)), quality);
+        }
+    }
+
+    fn run_bots(&mut self) {
+        //Bots read the world first, then their actions go through the same path as player messages (so they are recorded too).
+        let mut actions = Vec::new();
+        for bot in self.bots.iter_mut() {
+            for action in bot.think(&self.physics_world) {
+                actions.push((bot.player.clone(), action));
+            }
+        }
+
+        for (player, action) in actions {
+            match action {
+                BotAction::Move([dx, dy, dz], rot) => self.player_move(player, dx, dy, dz, Some(rot)),
+                BotAction::HitBegin => self.player_hit(player.id),
+                BotAction::HitEnd => self.player_hit_exec(player.id),
+            }
+        }
+    }
+
+    fn seat_position(&self, seat: i32) -> [f32; 3] {
+        //Where a seat's bat is, or the middle of it's plane if nobody has moved there yet.
+        let world = &self.physics_world;
+        let bat = world.get_player_by_number(seat)
+            .and_then(|player_id| world.player_map.get(&player_id))
+            .and_then(|handle| world.world.get(*handle));
+
+        match bat {
+            Some(body) => [body.translation().x, body.translation().y, seat_plane_z(seat) as f32],
+            None => [0.0, 0.0, seat_plane_z(seat) as f32],
+        }
+    }
+
+    fn serve(&mut self) {
+        self.launch_ball(self.rules.server, self.rules.receiver());
+        self.rules.serve();
+        self.stats.on_serve(team_of(self.rules.server), self.physics_world.settings.serve_speed);
+    }
+
+    fn launch_ball(&mut self, from_seat: i32, to_seat: i32) {
+        //Puts the ball in front of one seat's bat and sends it towards another, with a small random spread.
+        let server_pos = self.seat_position(from_seat);
+        let receiver_pos = self.seat_position(to_seat);
+
+        let towards_net = -server_pos[2].signum();
+        let start = [server_pos[0], server_pos[1], server_pos[2] + towards_net * SERVE_OFFSET];
+
+        let rng = &mut self.physics_world.rng;
+        let aim = [receiver_pos[0] + rng.range_f32(-0.3, 0.3), receiver_pos[1] + rng.range_f32(-0.3, 0.3), receiver_pos[2]];
+
+        self.send_ball(start, aim, self.physics_world.settings.serve_speed);
+    }
+
+    fn send_ball(&mut self, start: [f32; 3], aim: [f32; 3], speed: f32) {
+        let direction = [aim[0] - start[0], aim[1] - start[1], aim[2] - start[2]];
+        let length = (direction[0] * direction[0] + direction[1] * direction[1] + direction[2] * direction[2]).sqrt();
+        let velocity = direction.map(|d| d / length * speed);
+
+        self.physics_world.set_ball_state(start, velocity);
+    }
+
+    fn run_practice(&mut self) {
+        //The ball machine at the far end feeds the player, a shot ends when the return gets past the machine or the player misses.
+        let touches = std::mem::take(&mut self.physics_world.ball_touches);
+        let Some(machine) = self.ball_machine.as_mut() else {
+            return;
+        };
+
+        if !touches.is_empty() {
+            machine.on_touch();
+        }
+
+        let Some(ball) = self.physics_world.world.get(self.physics_world.ball_handle) else {
+            return;
+        };
+        let (pos, vel) = (*ball.translation(), *ball.linvel());
+
+        if machine.shot.is_some() {
+            self.rules.still_ticks = if vel.z.abs() < DEAD_BALL_SPEED { self.rules.still_ticks + 1 } else { 0 };
+
+            let returned = pos.z < seat_plane_z(1) as f32 && vel.z < 0.0;
+            let missed = pos.z as f64 > seat_plane_z(0) + BALL_OUT_MARGIN
+                || pos.x.abs() > SIDE_OUT_LIMIT
+                || pos.y.abs() > SIDE_OUT_LIMIT
+                || self.rules.still_ticks >= tick_rate();
+
+            if returned || missed {
+                let report = machine.finish_shot(returned.then_some([pos.x, pos.y]));
+                self.events.extend(report);
+                self.rules.still_ticks = 0;
+
+                //Machine catches the ball and holds it until the next feed.
+                self.physics_world.set_ball_state([0.0, 0.0, seat_plane_z(1) as f32 - 1.0], [0.0, 0.0, 0.0]);
+                self.physics_world.set_ball_spin([0.0, 0.0, 0.0]);
+            }
+        }
+
+        if machine.ready_to_feed(self.tick_count) {
+            let placement = machine.feed(self.tick_count, tick_rate(), &mut self.physics_world.rng);
+            let (speed, spin) = (machine.settings.speed, machine.settings.spin);
+
+            let start = self.seat_position(1);
+            let start = [start[0], start[1], start[2] + SERVE_OFFSET];
+            let aim = [placement[0], placement[1], seat_plane_z(0) as f32];
+
+            self.send_ball(start, aim, speed);
+            self.physics_world.set_ball_spin([spin[0], spin[1], 0.0]);
+        }
+    }
+
+    pub fn configure_ball_machine(&mut self, settings: MachineSettings) -> Result<(), String> {
+        let Some(machine) = self.ball_machine.as_mut() else {
+            return Err("Only practice rooms have a ball machine".to_string());
+        };
+
+        settings.validate()?;
+        if machine.settings.drill != settings.drill {
+            machine.drill_index = 0; //A new drill starts from its first placement.
+        }
+        machine.settings = settings.clone();
+        self.record(ReplayEntry::Machine { t: self.tick_count, s: settings });
+
+        Ok(())
+    }
+
+    fn check_time_limit(&mut self) {
+        //Timed modes end as soon as the clock has run out and someone is ahead.
+        let Some(limit) = self.mode.time_limit_secs else {
+            return;
+        };
+
+        let elapsed_ticks = self.tick_count - self.rules.started_at;
+        let [team_0, team_1] = self.rules.score;
+
+        if elapsed_ticks >= limit as u64 * tick_rate() as u64 && team_0 != team_1 && self.rules.winner.is_none() {
+            self.rules.winner = Some(if team_0 > team_1 { 0 } else { 1 });
+            self.finish_match();
+        }
+    }
+
+    fn finish_match(&mut self) {
+        let Some(winner) = self.rules.winner else {
+            return;
+        };
+
+        info!(winner_team = winner, score = ?self.rules.score, "Match over");
+        self.state = "Finished".to_string();
+        self.winner_ids = self.players_in_room.iter()
+            .filter(|player| team_of(self.physics_world.get_player_number(player.id)) == winner)
+            .map(|player| player.id)
+            .collect();
+        self.physics_world.set_ball_state([0.0, 0.0, 0.0], [0.0, 0.0, 0.0]);
+
+        self.events.push(serde_json::json!({
+            "type": "match_over",
+            "room_id": self.id.to_string(),
+            "winner_team": winner,
+            "score": self.rules.score,
+        }));
+        self.send_summary();
+    }
+
+    fn send_summary(&mut self) {
+        //Post-match stats for everyone in the room, and kept with the replay.
+        let mut summary = self.stats.summary();
+        summary["type"] = "match_summary".into();
+        summary["room_id"] = self.id.to_string().into();
+        summary["score"] = serde_json::json!(self.rules.score);
+        self.events.push(summary);
+
+        self.record(ReplayEntry::Summary { t: self.tick_count, stats: self.stats.clone() });
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        let _entered = self.span.enter();
+        info!(paused, "Room pause changed");
+
+        self.paused = paused;
+        self.events.push(serde_json::json!({
+            "type": "room_paused",
+            "room_id": self.id.to_string(),
+            "paused": paused,
+        }));
+    }
+
+    pub fn force_end(&mut self) -> Result<(), String> {
+        //Ends the match where it stands with no winner, used by admins.
+        if self.state != "In Progress" {
+            return Err("Match is not in progress".to_string());
+        }
+
+        let span = self.span.clone();
+        let _entered = span.enter();
+        info!(score = ?self.rules.score, "Match ended early");
+
+        self.record(ReplayEntry::End { t: self.tick_count });
+        self.state = "Finished".to_string();
+        self.paused = false;
+        self.physics_world.set_ball_state([0.0, 0.0, 0.0], [0.0, 0.0, 0.0]);
+
+        self.events.push(serde_json::json!({
+            "type": "match_over",
+            "room_id": self.id.to_string(),
+            "winner_team": null,
+            "score": self.rules.score,
+            "ended_early": true,
+        }));
+        self.send_summary();
+
+        Ok(())
+    }
+
+    fn report_ball_events(&mut self) -> Vec<BallEvent> {
+        //Sends this step's bounces, net and table contacts to the room, each as it's own message type.
+        let ball_events = std::mem::take(&mut self.physics_world.ball_events);
+
+        for event in &ball_events {
+            if let Ok(mut message) = serde_json::to_value(event) {
+                message["room_id"] = self.id.to_string().into();
+                self.events.push(message);
+            }
+        }
+
+        ball_events
+    }
+
+    fn apply_rules(&mut self, ball_events: &[BallEvent]) {
+        //Checks this step's touches, ball events and where the ball ended up, and awards a point if the rally is over.
+        let touches = std::mem::take(&mut self.physics_world.ball_touches);
+
+        let mut point = None;
+        for player_id in touches {
+            let seat = self.physics_world.get_player_number(player_id);
+            let hits = self.rules.hits;
+            point = point.or(self.rules.on_touch(seat));
+
+            if self.rules.hits > hits {
+                let speed = self.physics_world.ball_state().map_or(0.0, |ball| ball.vel.iter().map(|v| v * v).sum::<f32>().sqrt());
+                self.stats.on_shot(team_of(seat), speed);
+            }
+        }
+
+        let mut let_called = false;
+        for event in ball_events {
+            if let (BallEvent::Bounce { side, pos, .. }, Some(table)) = (event, self.physics_world.table.as_ref()) {
+                self.stats.on_bounce(*side, *pos, table);
+            }
+
+            match self.rules.on_ball_event(event) {
+                Some(Call::Point(team)) => point = point.or(Some(team)),
+                Some(Call::Let) => let_called = true,
+                None => (),
+            }
+        }
+
+        if point.is_none() && let_called {
+            self.call_let();
+            return;
+        }
+
+        if point.is_none()
+            && let Some(ball) = self.physics_world.world.get(self.physics_world.ball_handle) {
+                let (pos, speed) = (*ball.translation(), ball.linvel().z.abs()); //Drifting sideways doesn't keep a rally going.
+                let back_row = (self.capacity - 1) / 2 * 2; //First seat of the last row, it's plane is the deepest.
+                let end_depth = seat_plane_z(back_row) + BALL_OUT_MARGIN;
+                let side_team = if pos.z >= 0.0 { 0 } else { 1 };
+
+                self.rules.still_ticks = if speed < DEAD_BALL_SPEED { self.rules.still_ticks + 1 } else { 0 };
+
+                if pos.z.abs() as f64 > end_depth || self.rules.still_ticks >= tick_rate() {
+                    //Without gravity pulling it down the ball flies flat over the table, so shots only have to land in modes that have some.
+                    let must_land = self.physics_world.gravity.y < 0.0;
+                    point = self.rules.on_ball_not_returned(side_team, must_land);
+                } else if pos.x.abs() > SIDE_OUT_LIMIT || pos.y.abs() > SIDE_OUT_LIMIT {
+                    point = self.rules.on_ball_out_wide();
+                }
+        }
+
+        if let Some(team) = point {
+            self.score_point(team);
+        }
+    }
+
+    fn call_let(&mut self) {
+        debug!(server = self.rules.server, "Let");
+
+        self.events.push(serde_json::json!({
+            "type": "let",
+            "room_id": self.id.to_string(),
+            "server": self.rules.server,
+        }));
+
+        self.serve();
+    }
+
+    fn score_point(&mut self, team: usize) {
+        self.stats.on_point(team);
+        self.rules.award_point(team, self.match_length);
+        debug!(team, score = ?self.rules.score, "Point scored");
+
+        self.events.push(serde_json::json!({
+            "type": "point",
+            "room_id": self.id.to_string(),
+            "team": team,
+            "score": self.rules.score,
+            "server": self.rules.server,
+            "receiver": self.rules.receiver(),
+        }));
+
+        if self.rules.winner.is_some() {
+            self.finish_match();
+        } else {
+            self.serve();
+        }
+    }
+
+    pub fn take_events(&mut self) -> Vec<serde_json::Value> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn player_by_number(&self, player_index: i32) -> Option<Player> {
+        let player_id = self.physics_world.get_player_by_number(player_index)?;
+        self.players_in_room.iter().find(|p| p.id == player_id).cloned()
+    }
+
+    fn record(&mut self, entry: ReplayEntry) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(&entry);
+        }
+    }
+
+    pub fn finish_recording(&mut self) -> Option<JoinHandle<()>> {
+        //Writes a last keyframe (with the final score) and stops recording, the handle finishes once it's all on disk.
+        self.record_keyframe();
+
+        self.recorder.take().map(ReplayRecorder::finish)
+    }
+
+    fn record_keyframe(&mut self) {
+        if self.recorder.is_none() {
+            return;
+        }
+
+        let keyframe = self.keyframe();
+        self.record(keyframe);
+    }
+
+    pub fn keyframe(&self) -> ReplayEntry {
+        //Captures everything needed to rebuild the world at this tick.
+        let world = &self.physics_world;
+
+        let (ball, spin) = match world.world.get(world.ball_handle) {
+            Some(ball_body) => {
+                let pos = ball_body.translation();
+                let vel = ball_body.linvel();
+                let angvel = ball_body.angvel();
+                ([pos.x, pos.y, pos.z, vel.x, vel.y, vel.z], [angvel.x, angvel.y, angvel.z])
+            }
+            None => ([0.0; 6], [0.0; 3]),
+        };
+
+        let players = self.players_in_room.iter()
+            .filter_map(|player| {
+                let body = world.player_map.get(&player.id).and_then(|handle| world.world.get(*handle))?;
+                let pos = body.translation();
+
+                Some(PlayerFrame {
+                    id: player.id,
+                    num: world.get_player_number(player.id),
+                    pos: [pos.x, pos.y, pos.z],
+                    rot: world.bat_rotation(player.id),
+                    rubber: world.bat_rubbers.get(&player.id).cloned(),
+                    moving: world.move_intents.get(&player.id).map(|d| [d.x, d.y, d.z]),
+                    turning: world.turn_intents.get(&player.id).map(|r| [r.i, r.j, r.k, r.w]),
+                    swing: world.player_shot_timer.get(&player.id).map(|timer| timer.timer_value()),
+                    touching: world.touching_ball.contains(&player.id),
+                })
+            })
+            .collect();
+
+        //Rules only matter once a match has started.
+        let rules = (self.state != "Not Started").then(|| self.rules.clone());
+
+        ReplayEntry::Keyframe {
+            t: self.tick_count,
+            ball,
+            spin,
+            bounces: world.bounces,
+            players,
+            rng: world.rng.state(),
+            rules,
+            machine: self.ball_machine.clone(),
+            stats: Box::new(self.stats.clone()),
+            acc: self.accumulator,
+            len: Some(self.match_length),
+        }
+    }
+
+    pub fn restore_keyframe(&mut self, keyframe: &ReplayEntry) {
+        //Rebuilds the physics world from a keyframe, used by replay playback when seeking.
+        let ReplayEntry::Keyframe { t: tick, ball, spin, bounces, players, rng, rules, machine, stats, acc, len } = keyframe else {
+            return;
+        };
+
+        self.physics_world = PhysicsWorld::with_settings(*rng, self.mode.physics());
+        self.players_in_room.clear();
+        self.tick_count = *tick;
+        self.accumulator = *acc;
+        self.stats = (**stats).clone();
+        if let Some(match_length) = len {
+            self.match_length = *match_length;
+        }
+
+        for frame in players {
+            self.physics_world.restore_player(frame.id, frame.num, frame.pos, frame.rot);
+            if let Some(rubber) = &frame.rubber {
+                let _ = self.physics_world.set_rubber(frame.id, rubber); //A rubber since taken out of the config is played with the default.
+            }
+            self.physics_world.restore_inputs(frame.id, frame.moving, frame.turning, frame.swing);
+            if frame.touching {
+                self.physics_world.touching_ball.insert(frame.id);
+            }
+            self.players_in_room.push(Player {
+                id: frame.id,
+                display_name: "Replay".to_string(),
+            });
+        }
+
+        self.pop = self.players_in_room.len() as i32;
+        self.physics_world.set_ball_state([ball[0], ball[1], ball[2]], [ball[3], ball[4], ball[5]]);
+        self.physics_world.set_ball_spin(*spin);
+        self.physics_world.bounces = *bounces;
+
+        if machine.is_some() {
+            self.ball_machine = machine.clone();
+        }
+
+        match rules {
+            Some(rules) => {
+                self.rules = rules.clone();
+                self.state = if rules.winner.is_some() { "Finished" } else { "In Progress" }.to_string();
+            }
+            None => {
+                self.rules = MatchRules::new(self.capacity);
+                self.state = "Not Started".to_string();
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<serde_json::Value> {
+        //Builds the state messages sent to everyone in the room each tick.
+        let world = &self.physics_world;
+        let mut states = Vec::new();
+
+        if let Some(ball_body) = world.world.get(world.ball_handle) { //Getting the ball handle from the rigid body set in the physics world of the room.
+            let pos = ball_body.translation();
+            let vel = ball_body.linvel();
+
+            states.push(serde_json::json!({
+                "type": "ball_state",
+                "room_id": self.id.to_string(),
+                "pos": [pos.x, pos.y, pos.z],
+                "vel": [vel.x, vel.y, vel.z]
+            }));
+        }
+
+        states.extend(self.aim_hint());
+
+        for player in &self.players_in_room {
+            if let Some(player_body) = world.player_map.get(&player.id).and_then(|handle| world.world.get(*handle)) {
+                let position = player_body.translation();
+
+                states.push(serde_json::json!({
+                    "type": "player_state", //Used to determine client-side action.
+                    "player_id": player.id.to_string(),
+                    "player_num": world.player_order_map.get(&player.id).copied().unwrap_or(-1), //Used to determine what "player number" the player is, to depict position in world space.
+                    "team": team_of(world.get_player_number(player.id)),
+                    "pos": [position.x, position.y, position.z],
+                    "rot": world.bat_rotation(player.id), //Quaternion [x, y, z, w], level is [0, 0, 0, 1].
+                    "rubber": world.rubber_name(player.id),
+                }));
+            }
+        }
+
+        states
     }
 
-}
\ No newline at end of file
+    fn aim_hint(&self) -> Option<serde_json::Value> {
+        //Practice rooms with aim assist on show the player where the machine's ball will reach their bat (and where it bounces on the way).
+        //Only while the ball is on it's way to them, once they've touched it there's nothing left to aim at.
+        let machine = self.ball_machine.as_ref().filter(|machine| machine.settings.aim_assist)?;
+        machine.shot.as_ref().filter(|shot| !shot.touched)?;
+
+        let ball = self.physics_world.ball_state()?;
+        let trajectory = self.physics_world.predict_ball(AIM_HINT_SECS)?;
+        let intercept = trajectory.intercept(&ball, seat_plane_z(0) as f32);
+
+        Some(serde_json::json!({
+            "type": "aim_hint",
+            "room_id": self.id.to_string(),
+            "intercept": intercept, //Null if the ball won't get there within AIM_HINT_SECS.
+            "bounces": trajectory.bounces,
+        }))
+    }
+
+    pub fn summary(&self) -> serde_json::Value {
+        //Short description of the room, used for room listings.
+        serde_json::json!({
+            "room_id": self.id.to_string(),
+            "room_type": self.room_type,
+            "mode": self.mode.name,
+            "time_limit_secs": self.mode.time_limit_secs,
+            "state": self.state,
+            "capacity": self.capacity,
+            "match_length": self.match_length,
+            "score": self.rules.score,
+            "invite_code": self.invite_code, //Only set for private rooms, which are never listed publicly.
+            "owner_id": self.owner_id.map(|id| id.to_string()),
+            "players": self.players_in_room.iter().map(|p| serde_json::json!({
+                "player_id": p.id.to_string(),
+                "display_name": p.display_name,
+            })).collect::<Vec<_>>(),
+            "spectators": self.spectators_in_room.len(),
+            "spectator_capacity": self.spectator_capacity,
+            "paused": self.paused,
+        })
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rapier3d::prelude::*;
+    use ball_machine::Drill;
+
+    #[tokio::test]
+    async fn jittered_ticks_run_the_same_fixed_steps() {
+        //Two seconds of wall-clock time split evenly and split unevenly should simulate the same number of steps.
+        let mut steady = Room::new().await;
+        let mut jittery = Room::new().await;
+
+        for _ in 0..60 {
+            steady.advance(fixed_dt());
+        }
+
+        let jitter: [f32; 6] = [0.010, 0.050, 0.021, 0.040, 0.012, 0.067];
+        let mut remaining = 60.0 * fixed_dt();
+        for dt in jitter.iter().cycle() {
+            if remaining <= 0.0 {
+                break;
+            }
+            jittery.advance(dt.min(remaining));
+            remaining -= dt;
+        }
+
+        assert!((59..=60).contains(&steady.tick_count));
+        assert!((steady.tick_count as i64 - jittery.tick_count as i64).abs() <= 1);
+    }
+
+    #[tokio::test]
+    async fn hard_bot_intercepts_the_ball() {
+        let mut room = Room::new().await;
+        room.add_player(Player::new());
+        room.add_bot(BotDifficulty::preset("hard").unwrap());
+
+        //Bot sits at index 1, on the negative z side, so send the ball that way slightly off centre.
+        room.physics_world.set_ball_state([0.0, 0.0, -2.0], [0.3, 0.2, -8.0]);
+
+        for _ in 0..60 {
+            room.tick_room();
+        }
+
+        //Any change in velocity means the bot got it's bat in the way (there is nothing else to hit).
+        let ball = room.physics_world.world.get(room.physics_world.ball_handle).unwrap();
+        assert_ne!(*ball.linvel(), vector![0.3, 0.2, -8.0], "bot never reached the ball");
+    }
+
+    #[tokio::test]
+    async fn doubles_room_seats_both_teams_and_scores_a_missed_serve() {
+        let mut room = Room::new().await;
+        room.reseed(5);
+        room.set_room_type("doubles").unwrap();
+
+        let players: Vec<Player> = (0..4).map(|_| Player::new()).collect();
+        let seats: Vec<i32> = players.iter().map(|player| room.add_player(player.clone())).collect();
+        assert_eq!(seats, [0, 1, 2, 3]);
+        assert_eq!(room.state, "In Progress");
+
+        //Partners share an end, one row apart.
+        assert_eq!([0, 1, 2, 3].map(seat_plane_z), [9.0, -9.0, 10.5, -10.5]);
+
+        //The receiving team stands well out of the way, so seat 0's serve is never returned and team 0 takes the point.
+        for player in [&players[1], &players[3]] {
+            room.player_move(player.clone(), 5.0, 0.0, 0.0, None);
+        }
+
+        for _ in 0..(tick_rate() * 15) {
+            room.tick_room();
+            if room.rules.score != [0, 0] {
+                break;
+            }
+        }
+
+        assert_eq!(room.rules.score, [1, 0]);
+        assert!(room.take_events().iter().any(|event| event["type"] == "point"));
+
+        //Nobody got a bat to the serve, so it's a winner and a serve point won.
+        assert_eq!(room.stats.rallies, [1]);
+        assert_eq!((room.stats.teams[0].winners, room.stats.teams[0].serve_points_won), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn serve_clipping_the_net_is_a_let_and_a_low_return_loses_the_point() {
+        let mut room = Room::new().await;
+        let players = [Player::new(), Player::new()];
+        for player in &players {
+            room.add_player(player.clone());
+            room.player_move(player.clone(), 5.0, 0.0, 0.0, None); //Out of the ball's way.
+        }
+
+        //Over the cord on the serve, the ball is served again and nobody scores.
+        let net_top = room.physics_world.settings.table_height + room.physics_world.settings.net_height;
+        room.physics_world.set_ball_state([0.0, net_top + 0.02, 0.5], [0.0, 0.0, -8.0]);
+        room.tick_room();
+        room.tick_room();
+
+        let events = room.take_events();
+        assert!(events.iter().any(|event| event["type"] == "net_cord"));
+        assert!(events.iter().any(|event| event["type"] == "let"));
+        assert_eq!(room.rules.score, [0, 0]);
+
+        //Into the net after the receiver's return, the receiver loses it.
+        room.rules.on_touch(1);
+        room.physics_world.set_ball_state([0.0, net_top - 0.3, -0.5], [0.0, 0.0, 8.0]);
+        room.tick_room();
+        room.tick_room();
+
+        assert!(room.take_events().iter().any(|event| event["type"] == "net"));
+        assert_eq!(room.rules.score, [1, 0]);
+    }
+
+    #[tokio::test]
+    async fn shots_angled_down_bounce_off_the_table_in_the_default_mode() {
+        //Classic has no gravity, so the table is only reached by a shot a bat sends downward.
+        let mut room = Room::new().await;
+        assert_eq!(room.mode.name, mode::DEFAULT_MODE);
+        assert_eq!(room.physics_world.gravity.y, 0.0);
+
+        let players = [Player::new(), Player::new()];
+        for player in &players {
+            room.add_player(player.clone());
+            room.player_move(player.clone(), 5.0, 0.0, 0.0, None); //Out of the ball's way.
+        }
+
+        room.physics_world.set_ball_state([0.0, 0.0, -2.0], [0.0, -2.0, -6.0]);
+        let mut bounce = None;
+        for _ in 0..tick_rate() {
+            room.tick_room();
+            if let Some(event) = room.take_events().into_iter().find(|event| event["type"] == "bounce") {
+                bounce = Some(event);
+                break;
+            }
+        }
+
+        //It lands on team 1's half and comes off rising, as fast as the table's material gives back.
+        let bounce = bounce.expect("the ball never reached the table");
+        assert_eq!((bounce["side"].as_u64(), bounce["edge"].as_bool()), (Some(1), Some(false)));
+        let restitution = config::get().materials.table().restitution;
+        let ball = room.physics_world.ball_state().unwrap();
+        assert!((ball.vel[1] - 2.0 * restitution).abs() < 1e-3, "ball came off at {:?}", ball.vel);
+    }
+
+    #[tokio::test]
+    async fn edge_balls_play_on_and_side_hits_lose_the_point_in_the_default_mode() {
+        //Double bounces and volleys need gravity to bring the ball back down, the table rules that can come up in classic are these.
+        let mut room = Room::new().await;
+        let players = [Player::new(), Player::new()];
+        for player in &players {
+            room.add_player(player.clone());
+            room.player_move(player.clone(), 5.0, 0.0, 0.0, None); //Out of the ball's way.
+        }
+
+        //The serve clips the edge of team 1's half, that's good and the rally goes on.
+        let edge_x = room.physics_world.settings.table_half_width + 0.05;
+        room.physics_world.set_ball_state([edge_x, 0.0, -2.0], [0.0, -2.0, -6.0]);
+        let mut events = Vec::new();
+        for _ in 0..tick_rate() / 2 {
+            room.tick_room();
+            events.append(&mut room.take_events());
+        }
+        assert!(events.iter().any(|event| event["type"] == "bounce" && event["edge"] == true), "{:?}", events);
+        assert_eq!(room.rules.score, [0, 0]);
+
+        //The receiver's return runs into the side of the table below the top, they lose the point.
+        room.rules.on_touch(1);
+        let below_top = room.physics_world.settings.table_height - 0.05;
+        room.physics_world.set_ball_state([edge_x + 0.5, below_top, 3.0], [-6.0, 0.0, 0.0]);
+        let mut events = Vec::new();
+        for _ in 0..tick_rate() / 2 {
+            room.tick_room();
+            events.append(&mut room.take_events());
+        }
+
+        assert!(events.iter().any(|event| event["type"] == "side_hit"), "{:?}", events);
+        assert_eq!(room.rules.score, [1, 0]);
+    }
+
+    #[tokio::test]
+    async fn spectators_chat_too_and_chat_is_kept_in_the_replay() {
+        let mut room = Room::new().await;
+        let (player, spectator) = (Player::new(), Player::new());
+        room.add_player(player.clone());
+        room.add_spectator(spectator.clone(), None).unwrap();
+
+        let message = room.chat(spectator.id, "nice rally").unwrap();
+        assert_eq!(message["display_name"], spectator.display_name.as_str());
+        assert!(room.chat(Uuid::new_v4(), "hi").is_err()); //Not in the room.
+
+        assert!(room.mute(player.id, player.id, true).is_err());
+        room.mute(player.id, spectator.id, true).unwrap();
+        assert!(room.chat.has_muted(player.id, spectator.id));
+
+        //Leaving forgets the mutes a member made.
+        room.remove_player(player.id);
+        assert!(!room.chat.has_muted(player.id, spectator.id));
+
+        let entry = serde_json::to_value(ReplayEntry::Chat { t: 3, id: spectator.id, n: "Spec".to_string(), m: "hi".to_string() }).unwrap();
+        assert!(matches!(serde_json::from_value(entry).unwrap(), ReplayEntry::Chat { t: 3, .. }));
+    }
+
+    #[tokio::test]
+    async fn private_rooms_are_only_watched_with_the_code() {
+        let mut room = Room::new().await;
+        let owner = Player::new();
+        room.add_player(owner.clone());
+        room.make_private(owner.id, "ABC234".to_string(), 11);
+
+        assert!(room.add_spectator(Player::new(), None).is_err());
+        assert!(room.add_spectator(Player::new(), Some("XYZ789")).is_err());
+        assert!(room.add_spectator(Player::new(), Some(" abc234")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn leaving_mid_match_gives_it_to_the_other_side() {
+        let mut room = Room::new().await;
+        let players = [Player::new(), Player::new()];
+        for player in &players {
+            room.add_player(player.clone());
+        }
+        room.rules.score = [6, 2];
+        room.take_events();
+
+        //The leader walks off, the player left behind wins and gets their summary before the room opens up again.
+        room.remove_player(players[0].id);
+
+        let events = room.take_events();
+        let over = events.iter().find(|event| event["type"] == "match_over").expect("no match over");
+        assert_eq!(over["winner_team"], 1);
+        assert!(events.iter().any(|event| event["type"] == "match_summary"));
+        assert_eq!((room.state.as_str(), room.is_free), ("Not Started", true));
+    }
+
+    #[tokio::test]
+    async fn blitz_ends_when_time_runs_out_with_a_leader() {
+        let mut room = Room::new().await;
+        room.set_mode("blitz").unwrap();
+        room.add_player(Player::new());
+        room.add_player(Player::new());
+
+        //Level when the clock runs out, so it plays on.
+        room.tick_count = room.rules.started_at + 120 * tick_rate() as u64;
+        room.tick_room();
+        assert_eq!(room.state, "In Progress");
+
+        room.rules.score = [3, 2];
+        room.tick_room();
+        assert_eq!(room.state, "Finished");
+        assert_eq!(room.rules.winner, Some(0));
+
+        let events = room.take_events();
+        let summary = events.iter().find(|event| event["type"] == "match_summary").expect("no match summary");
+        assert_eq!(summary["score"], serde_json::json!([3, 2]));
+    }
+
+    #[tokio::test]
+    async fn ball_machine_feeds_the_player_and_reports_each_shot() {
+        let mut room = Room::new().await;
+        room.set_mode("practice").unwrap();
+        let player = Player::new();
+        room.add_player(player.clone());
+        assert_eq!(room.state, "In Progress");
+
+        room.configure_ball_machine(MachineSettings { interval_secs: 1.0, ..MachineSettings::default() }).unwrap();
+
+        //Player stands out of the way, so every ball is missed.
+        room.player_move(player, 5.0, 0.0, 0.0, None);
+        for _ in 0..(tick_rate() * 10) {
+            room.tick_room();
+        }
+
+        let shots: Vec<_> = room.take_events().into_iter().filter(|event| event["type"] == "practice_shot").collect();
+        assert!(shots.len() >= 3, "machine only fed {} balls", shots.len());
+        assert!(shots.iter().all(|shot| shot["hit"] == false));
+        assert_eq!(room.rules.score, [0, 0]);
+    }
+
+    #[tokio::test]
+    async fn aim_hint_points_at_where_the_ball_meets_the_bat() {
+        let mut room = Room::new().await;
+        room.set_mode("practice").unwrap();
+        room.add_player(Player::new());
+
+        let hint = |room: &Room| room.snapshot().into_iter().find(|state| state["type"] == "aim_hint");
+
+        let settings = MachineSettings { drill: Drill::Fixed([0.5, 0.25]), ..MachineSettings::default() };
+        room.configure_ball_machine(settings.clone()).unwrap();
+        while room.ball_machine.as_ref().unwrap().shot.is_none() {
+            room.tick_room();
+        }
+        assert!(hint(&room).is_none()); //Off unless the player asks for it.
+
+        room.configure_ball_machine(MachineSettings { aim_assist: true, ..settings }).unwrap();
+        room.tick_room();
+
+        let intercept = &hint(&room).expect("no hint while the ball is coming")["intercept"];
+        let pos: Vec<f64> = intercept["pos"].as_array().unwrap().iter().map(|v| v.as_f64().unwrap()).collect();
+        assert!((pos[0] - 0.5).abs() < 0.05 && (pos[1] - 0.25).abs() < 0.05, "hint at {:?}", pos);
+        assert!((pos[2] - seat_plane_z(0)).abs() < 1e-3);
+    }
+
+    #[tokio::test]
+    async fn stalls_are_capped() {
+        let mut room = Room::new().await;
+
+        assert_eq!(room.advance(10.0), config::get().tick.max_steps_per_advance);
+        assert!(room.accumulator <= fixed_dt() as f64);
+    }
+}
@@ -0,0 +1,228 @@
+//This is the ball machine file.
+//Practice rooms have a machine at the far end that feeds balls to the player, following a drill for where each ball goes.
+//Each ball fed is a shot, it ends when the player returns it past the machine (a hit) or lets it by (a miss),
+//and is reported with the contact quality of the player's swing and where the return reached the machine's end.
+//The machine only decides what to feed and keeps score, the room launches the ball and tells it what happened.
+
+use serde::{Deserialize, Serialize};
+
+use super::physics_world::SeededRng;
+
+pub const MIN_SPEED: f32 = 1.0;
+pub const MAX_SPEED: f32 = 30.0;
+pub const MAX_SPIN: f32 = 100.0; //Radians per second.
+pub const MIN_INTERVAL_SECS: f32 = 0.5;
+pub const MAX_INTERVAL_SECS: f32 = 10.0;
+pub const MAX_PLACEMENT: f32 = 2.0; //Furthest a ball can be aimed from the centre of the player's plane, in x and y.
+pub const FOREHAND_X: f32 = 0.6; //Forehand is +x for a right-handed player, backhand is -x.
+
+//Where the machine aims each ball, as an [x, y] offset on the player's bat plane.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Drill {
+    Fixed([f32; 2]),
+    Alternate, //Forehand then backhand.
+    Random,
+    Sequence(Vec<[f32; 2]>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MachineSettings {
+    pub speed: f32,
+    pub spin: [f32; 2], //Topspin (negative for backspin) and sidespin.
+    pub interval_secs: f32, //Shortest time between balls.
+    pub drill: Drill,
+    #[serde(default)]
+    pub aim_assist: bool, //Sends the player where each ball will reach their bat, see Room::aim_hint.
+}
+
+impl Default for MachineSettings {
+    fn default() -> Self {
+        MachineSettings {
+            speed: 8.0,
+            spin: [0.0, 0.0],
+            interval_secs: 2.0,
+            drill: Drill::Fixed([0.0, 0.0]),
+            aim_assist: false,
+        }
+    }
+}
+
+impl MachineSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(MIN_SPEED..=MAX_SPEED).contains(&self.speed) {
+            return Err(format!("Ball machine speed must be between {} and {}", MIN_SPEED, MAX_SPEED));
+        }
+
+        if self.spin.iter().any(|spin| !(-MAX_SPIN..=MAX_SPIN).contains(spin)) {
+            return Err(format!("Ball machine spin must be between -{} and {}", MAX_SPIN, MAX_SPIN));
+        }
+
+        if !(MIN_INTERVAL_SECS..=MAX_INTERVAL_SECS).contains(&self.interval_secs) {
+            return Err(format!("Ball machine interval_secs must be between {} and {}", MIN_INTERVAL_SECS, MAX_INTERVAL_SECS));
+        }
+
+        let placements = match &self.drill {
+            Drill::Fixed(placement) => vec![*placement],
+            Drill::Sequence(placements) if placements.is_empty() => return Err("Drill sequence can't be empty".to_string()),
+            Drill::Sequence(placements) => placements.clone(),
+            Drill::Alternate | Drill::Random => Vec::new(),
+        };
+
+        if placements.iter().flatten().any(|offset| !(-MAX_PLACEMENT..=MAX_PLACEMENT).contains(offset)) {
+            return Err(format!("Ball machine placements must be within {} of the centre", MAX_PLACEMENT));
+        }
+
+        Ok(())
+    }
+}
+
+//The ball currently out, from being fed until it's returned or missed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Shot {
+    pub number: u32,
+    pub placement: [f32; 2],
+    pub touched: bool,
+    pub quality: Option<f32>, //Best swing the player made at this ball.
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BallMachine {
+    pub settings: MachineSettings,
+    pub shot: Option<Shot>,
+    pub next_feed_tick: u64,
+    pub drill_index: usize,
+    pub shots: u32,
+    pub hits: u32,
+    pub quality_total: f32, //Summed over hits, for the average.
+}
+
+impl Default for BallMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BallMachine {
+    pub fn new() -> Self {
+        BallMachine {
+            settings: MachineSettings::default(),
+            shot: None,
+            next_feed_tick: 0,
+            drill_index: 0,
+            shots: 0,
+            hits: 0,
+            quality_total: 0.0,
+        }
+    }
+
+    pub fn ready_to_feed(&self, tick: u64) -> bool {
+        self.shot.is_none() && tick >= self.next_feed_tick
+    }
+
+    pub fn feed(&mut self, tick: u64, tick_rate: u32, rng: &mut SeededRng) -> [f32; 2] {
+        //Starts a new shot and returns where it's aimed.
+        let placement = match &self.settings.drill {
+            Drill::Fixed(placement) => *placement,
+            Drill::Alternate => if self.drill_index.is_multiple_of(2) { [FOREHAND_X, 0.0] } else { [-FOREHAND_X, 0.0] },
+            Drill::Random => [rng.range_f32(-FOREHAND_X, FOREHAND_X), rng.range_f32(-FOREHAND_X, FOREHAND_X)],
+            Drill::Sequence(placements) => placements[self.drill_index % placements.len()],
+        };
+
+        self.drill_index += 1;
+        self.shots += 1;
+        self.next_feed_tick = tick + (self.settings.interval_secs * tick_rate as f32).round() as u64;
+        self.shot = Some(Shot {
+            number: self.shots,
+            placement,
+            touched: false,
+            quality: None,
+        });
+
+        placement
+    }
+
+    pub fn on_touch(&mut self) {
+        if let Some(shot) = self.shot.as_mut() {
+            shot.touched = true;
+        }
+    }
+
+    pub fn on_swing(&mut self, quality: f32) {
+        if let Some(shot) = self.shot.as_mut() {
+            shot.quality = Some(shot.quality.map_or(quality, |best| best.max(quality)));
+        }
+    }
+
+    pub fn finish_shot(&mut self, landing: Option<[f32; 2]>) -> Option<serde_json::Value> {
+        //Ends the shot in play, a landing spot means the player got it back to the machine's end.
+        let shot = self.shot.take()?;
+        let hit = landing.is_some();
+
+        if hit {
+            self.hits += 1;
+            self.quality_total += shot.quality.unwrap_or(0.0);
+        }
+
+        Some(serde_json::json!({
+            "type": "practice_shot",
+            "shot": shot.number,
+            "placement": shot.placement,
+            "hit": hit,
+            "touched": shot.touched,
+            "quality": shot.quality,
+            "landing": landing,
+            "totals": self.totals(),
+        }))
+    }
+
+    pub fn totals(&self) -> serde_json::Value {
+        let average_quality = if self.hits > 0 { self.quality_total / self.hits as f32 } else { 0.0 };
+
+        serde_json::json!({
+            "shots": self.shots,
+            "hits": self.hits,
+            "misses": self.shots - self.hits - self.shot.is_some() as u32,
+            "average_quality": average_quality,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alternate_drill_switches_between_forehand_and_backhand() {
+        let mut machine = BallMachine::new();
+        machine.settings.drill = Drill::Alternate;
+        let mut rng = SeededRng::new(1);
+
+        let mut placements = Vec::new();
+        for tick in 0..4 {
+            placements.push(machine.feed(tick * 100, 30, &mut rng));
+            machine.finish_shot(None);
+        }
+
+        assert_eq!(placements, [[FOREHAND_X, 0.0], [-FOREHAND_X, 0.0], [FOREHAND_X, 0.0], [-FOREHAND_X, 0.0]]);
+    }
+
+    #[test]
+    fn totals_count_hits_and_misses() {
+        let mut machine = BallMachine::new();
+        let mut rng = SeededRng::new(1);
+
+        machine.feed(0, 30, &mut rng);
+        machine.on_touch();
+        machine.on_swing(0.5);
+        machine.on_swing(0.8);
+        let report = machine.finish_shot(Some([0.1, 0.2])).unwrap();
+        assert_eq!(report["quality"], 0.8f32 as f64);
+
+        machine.feed(60, 30, &mut rng);
+        machine.finish_shot(None);
+
+        let totals = machine.totals();
+        assert_eq!((totals["shots"].as_u64(), totals["hits"].as_u64(), totals["misses"].as_u64()), (Some(2), Some(1), Some(1)));
+    }
+}
@@ -0,0 +1,207 @@
+//This is the bot file.
+//A bot is a headless player that occupies a seat in a room, so a match can be played (or tested) by one person.
+//Each tick it looks at the ball in the room's physics world, predicts (with the trajectory predictor) where it will cross the bot's bat plane,
+//moves towards that point, angles it's blade to send the ball somewhere on the far end and swings, using the same move/hit path as a connected player.
+
+use std::collections::VecDeque;
+
+use rapier3d::prelude::nalgebra::{UnitQuaternion, Vector3};
+use serde::Deserialize;
+
+use super::physics_world::{seat_plane_z, PhysicsWorld, SeededRng};
+use super::physics_world::trajectory::{BallState, Intercept, Trajectory, MAX_PREDICTION_SECS};
+use super::physics_world::bat::LEVEL_BAT;
+use super::fixed_dt;
+use crate::Player;
+
+pub const BOT_MAX_SPEED: f64 = 12.0; //Units per second the bot can move it's bat.
+pub const BOT_MAX_REACTION_MS: u32 = 2000;
+pub const BOT_MAX_POSITIONAL_ERROR: f32 = 2.0;
+pub const BOT_AIM_SPREAD: [f32; 2] = [4.0, 2.0]; //Furthest from the centre of the far end (in x and y) a fully aggressive bot aims.
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BotDifficulty {
+    pub reaction_ms: u32, //How stale the bot's view of the ball is.
+    pub positional_error: f32, //Largest miss (in world units) added to each predicted intercept.
+    pub aggression: f32, //0 to 1, how long the bot winds up a hit before contact.
+}
+
+impl BotDifficulty {
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "easy" => Some(BotDifficulty { reaction_ms: 400, positional_error: 0.6, aggression: 0.2 }),
+            "medium" => Some(BotDifficulty { reaction_ms: 200, positional_error: 0.3, aggression: 0.5 }),
+            "hard" => Some(BotDifficulty { reaction_ms: 66, positional_error: 0.05, aggression: 0.9 }),
+            _ => None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.reaction_ms > BOT_MAX_REACTION_MS {
+            return Err(format!("Bot reaction_ms must be at most {}", BOT_MAX_REACTION_MS));
+        }
+
+        if !(0.0..=BOT_MAX_POSITIONAL_ERROR).contains(&self.positional_error) {
+            return Err(format!("Bot positional_error must be between 0 and {}", BOT_MAX_POSITIONAL_ERROR));
+        }
+
+        if !(0.0..=1.0).contains(&self.aggression) {
+            return Err("Bot aggression must be between 0 and 1".to_string());
+        }
+
+        Ok(())
+    }
+
+    fn reaction_ticks(&self) -> usize {
+        (self.reaction_ms as f32 / 1000.0 / fixed_dt()).round() as usize
+    }
+
+    fn wind_up_secs(&self) -> f64 {
+        0.1 + 0.5 * self.aggression as f64
+    }
+}
+
+//What the player picked in join_room, either a preset name or their own settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum BotChoice {
+    Preset(String),
+    Custom(BotDifficulty),
+}
+
+impl BotChoice {
+    pub fn difficulty(&self) -> Result<BotDifficulty, String> {
+        let difficulty = match self {
+            BotChoice::Preset(name) => BotDifficulty::preset(name).ok_or_else(|| format!("Unknown bot difficulty: {}", name))?,
+            BotChoice::Custom(difficulty) => difficulty.clone(),
+        };
+
+        difficulty.validate()?;
+        Ok(difficulty)
+    }
+}
+
+pub enum BotAction {
+    Move([f64; 3], [f64; 4]), //Bat position and rotation.
+    HitBegin,
+    HitEnd,
+}
+
+pub struct Bot {
+    pub player: Player,
+    pub difficulty: BotDifficulty,
+    rng: SeededRng, //Separate from the world's generator, bot inputs are recorded so replays never re-run the bot.
+    observations: VecDeque<BallState>, //Recent ball states, oldest first.
+    aim_error: [f64; 2],
+    aim_target: [f32; 2], //Where on the far end this return is meant to go.
+    tracking: bool,
+    swinging: bool,
+}
+
+impl Bot {
+    pub fn new(difficulty: BotDifficulty, seed: u64) -> Self {
+        Bot {
+            player: Player {
+                id: uuid::Uuid::new_v4(),
+                display_name: "Bot".to_string(),
+            },
+            difficulty,
+            rng: SeededRng::new(seed),
+            observations: VecDeque::new(),
+            aim_error: [0.0, 0.0],
+            aim_target: [0.0, 0.0],
+            tracking: false,
+            swinging: false,
+        }
+    }
+
+    pub fn think(&mut self, world: &PhysicsWorld) -> Vec<BotAction> {
+        let mut actions = Vec::new();
+
+        let Some(ball) = world.ball_state() else {
+            return actions;
+        };
+        let Some(bat) = world.player_map.get(&self.player.id).and_then(|handle| world.world.get(*handle)) else {
+            return actions;
+        };
+
+        //Only acting on what the ball was doing reaction_ms ago.
+        self.observations.push_back(ball);
+        while self.observations.len() > self.difficulty.reaction_ticks() + 1 {
+            self.observations.pop_front();
+        }
+        let seen = self.observations[0];
+
+        let plane_z = seat_plane_z(world.get_player_number(self.player.id));
+        let approaching = (plane_z as f32 - seen.pos[2]) * seen.vel[2] > 0.0;
+
+        //Predicting from the stale state with the world's own gravity and table, so a bouncing or dropping ball is met where it will be.
+        let intercept = approaching.then(|| {
+            let gravity = [world.gravity.x, world.gravity.y, world.gravity.z];
+            Trajectory::predict(seen, gravity, world.settings.ball_radius, world.table.as_ref(), MAX_PREDICTION_SECS, fixed_dt())
+                .intercept(&seen, plane_z as f32)
+        }).flatten();
+
+        let (target, rotation) = if let Some(intercept) = intercept {
+            let time_to_plane = intercept.t as f64;
+
+            if !self.tracking {
+                //New shot coming in, picking how far off this attempt will be.
+                let error = self.difficulty.positional_error;
+                self.aim_error = [self.rng.range_f32(-error, error) as f64, self.rng.range_f32(-error, error) as f64];
+
+                //And where to send it, the more aggressive the bot the further from the middle.
+                let spread = BOT_AIM_SPREAD.map(|spread| spread * self.difficulty.aggression);
+                self.aim_target = [self.rng.range_f32(-spread[0], spread[0]), self.rng.range_f32(-spread[1], spread[1])];
+                self.tracking = true;
+            }
+
+            //The swing is let go once the ball is on it's way back (below), so the blade is still moving through it at contact.
+            if !self.swinging && time_to_plane <= self.difficulty.wind_up_secs() {
+                actions.push(BotAction::HitBegin);
+                self.swinging = true;
+            }
+
+            let target = [intercept.pos[0] as f64 + self.aim_error[0], intercept.pos[1] as f64 + self.aim_error[1], plane_z];
+            (target, self.blade_for(&intercept, -plane_z as f32))
+        } else {
+            //Ball heading away, recover to the middle of the table.
+            self.tracking = false;
+            if self.swinging {
+                actions.push(BotAction::HitEnd);
+                self.swinging = false;
+            }
+
+            ([0.0, 0.0, plane_z], LEVEL_BAT)
+        };
+
+        //Moving towards the target no faster than a player could.
+        let bat_pos = bat.translation();
+        let offset = [target[0] - bat_pos.x as f64, target[1] - bat_pos.y as f64];
+        let distance = (offset[0] * offset[0] + offset[1] * offset[1]).sqrt();
+        let max_step = BOT_MAX_SPEED * fixed_dt() as f64;
+        let scale = if distance > max_step { max_step / distance } else { 1.0 };
+
+        let position = [bat_pos.x as f64 + offset[0] * scale, bat_pos.y as f64 + offset[1] * scale, plane_z];
+        actions.push(BotAction::Move(position, rotation.map(f64::from)));
+
+        actions
+    }
+
+    fn blade_for(&self, intercept: &Intercept, far_end_z: f32) -> [f32; 4] {
+        //A blade reflects the ball, so it faces halfway between where the ball comes from and where it should go.
+        let incoming = Vector3::from(intercept.vel).normalize();
+        let outgoing = (Vector3::new(self.aim_target[0], self.aim_target[1], far_end_z) - Vector3::from(intercept.pos)).normalize();
+
+        //Either face hits the same, using the one facing +z keeps the turn from level small.
+        let mut normal = outgoing - incoming;
+        if normal.z < 0.0 {
+            normal = -normal;
+        }
+
+        match UnitQuaternion::rotation_between(&Vector3::z(), &normal) {
+            Some(rotation) => [rotation.i, rotation.j, rotation.k, rotation.w],
+            None => LEVEL_BAT,
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//This is the chat file.
+//Everyone in a room (players and spectators) can send chat messages and emotes to the rest of the room.
+//Messages are length checked and have blocked words starred out, and each member can only send so many in a while.
+//Members can mute each other, a muted member's chat and emotes are just not sent to whoever muted them.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::config::ChatConfig;
+use crate::Player;
+
+pub const EMOTES: [&str; 6] = ["wave", "gg", "nice_shot", "unlucky", "oops", "laugh"];
+
+#[derive(Debug, Default)]
+pub struct Chat {
+    sent: HashMap<Uuid, VecDeque<Instant>>, //When each member last sent something, for the rate limit.
+    muted: HashMap<Uuid, HashSet<Uuid>>, //Who each member has muted.
+}
+
+impl Chat {
+    pub fn new() -> Self {
+        Chat::default()
+    }
+
+    pub fn check_rate(&mut self, member_id: Uuid, now: Instant, settings: &ChatConfig) -> Result<(), String> {
+        //Counts this message if it's allowed, the window slides so a burst is only held back until the oldest one is old enough.
+        let window = Duration::from_secs(settings.rate_limit_secs);
+        let sent = self.sent.entry(member_id).or_default();
+
+        while sent.front().is_some_and(|at| now.duration_since(*at) >= window) {
+            sent.pop_front();
+        }
+
+        if sent.len() >= settings.rate_limit_messages {
+            return Err("You're sending messages too fast".to_string());
+        }
+
+        sent.push_back(now);
+        Ok(())
+    }
+
+    pub fn set_muted(&mut self, member_id: Uuid, target_id: Uuid, muted: bool) {
+        let mutes = self.muted.entry(member_id).or_default();
+        if muted {
+            mutes.insert(target_id);
+        } else {
+            mutes.remove(&target_id);
+        }
+    }
+
+    pub fn has_muted(&self, member_id: Uuid, target_id: Uuid) -> bool {
+        self.muted.get(&member_id).is_some_and(|mutes| mutes.contains(&target_id))
+    }
+
+    pub fn forget(&mut self, member_id: Uuid) {
+        //A member leaving takes their mutes with them.
+        self.sent.remove(&member_id);
+        self.muted.remove(&member_id);
+    }
+}
+
+pub fn check_text(text: &str, settings: &ChatConfig) -> Result<String, String> {
+    let text = text.trim();
+
+    if text.is_empty() {
+        return Err("Chat message is empty".to_string());
+    }
+
+    if text.chars().count() > settings.max_length {
+        return Err(format!("Chat messages can be at most {} characters", settings.max_length));
+    }
+
+    Ok(filter(text, &settings.blocked_words))
+}
+
+pub fn filter(text: &str, blocked_words: &[String]) -> String {
+    //Stars out blocked words where they're a whole word, so "scrap" is left alone but "CRAP!" isn't.
+    let mut filtered = String::with_capacity(text.len());
+    let mut word = String::new();
+
+    let flush = |word: &mut String, filtered: &mut String| {
+        if blocked_words.iter().any(|blocked| blocked.eq_ignore_ascii_case(word)) {
+            filtered.extend(word.chars().map(|_| '*'));
+        } else {
+            filtered.push_str(word);
+        }
+        word.clear();
+    };
+
+    for character in text.chars() {
+        if character.is_alphanumeric() || character == '\'' {
+            word.push(character);
+        } else {
+            flush(&mut word, &mut filtered);
+            filtered.push(character);
+        }
+    }
+    flush(&mut word, &mut filtered);
+
+    filtered
+}
+
+pub fn check_emote(emote: &str) -> Result<(), String> {
+    if !EMOTES.contains(&emote) {
+        return Err(format!("Unknown emote: {}", emote));
+    }
+
+    Ok(())
+}
+
+pub fn chat_msg(room_id: Uuid, sender: &Player, text: &str) -> serde_json::Value {
+    serde_json::json!({
+        "type": "chat",
+        "room_id": room_id.to_string(),
+        "player_id": sender.id.to_string(),
+        "display_name": sender.display_name,
+        "text": text,
+    })
+}
+
+pub fn emote_msg(room_id: Uuid, sender: &Player, emote: &str) -> serde_json::Value {
+    serde_json::json!({
+        "type": "emote",
+        "room_id": room_id.to_string(),
+        "player_id": sender.id.to_string(),
+        "display_name": sender.display_name,
+        "emote": emote,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_checked_filtered_and_rate_limited() {
+        let settings = ChatConfig { max_length: 20, ..ChatConfig::default() };
+
+        assert_eq!(check_text("  oh CRAP, scrap that ", &settings).unwrap(), "oh ****, scrap that");
+        assert!(check_text("   ", &settings).is_err());
+        assert!(check_text(&"a".repeat(21), &settings).is_err());
+
+        let member = Uuid::from_u128(1);
+        let mut chat = Chat::new();
+        let start = Instant::now();
+        for _ in 0..settings.rate_limit_messages {
+            chat.check_rate(member, start, &settings).unwrap();
+        }
+        assert!(chat.check_rate(member, start, &settings).is_err());
+        assert!(chat.check_rate(Uuid::from_u128(2), start, &settings).is_ok()); //Limits are per member.
+        assert!(chat.check_rate(member, start + Duration::from_secs(settings.rate_limit_secs), &settings).is_ok());
+    }
+}
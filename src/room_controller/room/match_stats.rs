@@ -0,0 +1,150 @@
+//This is the match stats file.
+//The room tells it about every shot, swing, bounce and point of a match, and when the match is over it's summed up
+//into a match_summary message for the players and stored in the match's replay.
+//A point is a winner for the team that won it if their shot was the last one, otherwise it was an unforced error by the losing team.
+
+use serde::{Deserialize, Serialize};
+
+use super::physics_world::trajectory::TableGeometry;
+
+pub const HEATMAP_SIZE: [usize; 2] = [4, 4]; //Rows along (from the net to the end) and columns across each half of the table.
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TeamStats {
+    pub shots: u32, //Serves included.
+    pub fastest_shot: f32, //Speed of the ball straight off the bat.
+    pub swings: u32,
+    pub quality_total: f32, //Contact quality of every swing, for the average.
+    pub winners: u32,
+    pub unforced_errors: u32,
+    pub serve_points: u32,
+    pub serve_points_won: u32,
+    pub placement: [[u32; HEATMAP_SIZE[1]]; HEATMAP_SIZE[0]], //Where the team's shots bounced on the other half, row 0 is by the net.
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MatchStats {
+    pub rallies: Vec<u32>, //Shots in each finished rally.
+    pub teams: [TeamStats; 2],
+    rally_shots: u32,
+    serving_team: usize,
+    last_team: Option<usize>, //Team whose shot is in play.
+}
+
+impl MatchStats {
+    pub fn new() -> Self {
+        MatchStats::default()
+    }
+
+    pub fn on_serve(&mut self, team: usize, speed: f32) {
+        //Starts a rally, a let starts it over without counting the first one.
+        self.rally_shots = 0;
+        self.serving_team = team;
+        self.on_shot(team, speed);
+    }
+
+    pub fn on_shot(&mut self, team: usize, speed: f32) {
+        let stats = &mut self.teams[team];
+        stats.shots += 1;
+        stats.fastest_shot = stats.fastest_shot.max(speed);
+
+        self.rally_shots += 1;
+        self.last_team = Some(team);
+    }
+
+    pub fn on_swing(&mut self, team: usize, quality: f32) {
+        self.teams[team].swings += 1;
+        self.teams[team].quality_total += quality;
+    }
+
+    pub fn on_bounce(&mut self, side: usize, pos: [f32; 3], table: &TableGeometry) {
+        //Only bounces on the far half count as placement, the near half is where a serve (or a bad shot) first lands.
+        let Some(team) = self.last_team.filter(|team| *team != side) else {
+            return;
+        };
+
+        let cell = |along: f32, size: usize| ((along * size as f32) as usize).min(size - 1);
+        let row = cell(pos[2].abs() / table.half_length, HEATMAP_SIZE[0]);
+        let column = cell((pos[0] + table.half_width) / (2.0 * table.half_width), HEATMAP_SIZE[1]);
+
+        self.teams[team].placement[row][column] += 1;
+    }
+
+    pub fn on_point(&mut self, team: usize) {
+        self.rallies.push(self.rally_shots);
+
+        self.teams[self.serving_team].serve_points += 1;
+        if team == self.serving_team {
+            self.teams[team].serve_points_won += 1;
+        }
+
+        if self.last_team == Some(team) {
+            self.teams[team].winners += 1;
+        } else {
+            self.teams[1 - team].unforced_errors += 1;
+        }
+
+        self.rally_shots = 0;
+        self.last_team = None;
+    }
+
+    pub fn summary(&self) -> serde_json::Value {
+        let longest = self.rallies.iter().max().copied().unwrap_or(0);
+        let average = if self.rallies.is_empty() { 0.0 } else { self.rallies.iter().sum::<u32>() as f32 / self.rallies.len() as f32 };
+
+        let teams: Vec<serde_json::Value> = self.teams.iter().map(|stats| serde_json::json!({
+            "shots": stats.shots,
+            "fastest_shot": stats.fastest_shot,
+            "average_quality": (stats.swings > 0).then(|| stats.quality_total / stats.swings as f32),
+            "winners": stats.winners,
+            "unforced_errors": stats.unforced_errors,
+            "serve_points": stats.serve_points,
+            "serve_points_won": stats.serve_points_won,
+            "placement": stats.placement,
+        })).collect();
+
+        serde_json::json!({
+            "rallies": self.rallies.len(),
+            "rally_lengths": self.rallies,
+            "longest_rally": longest,
+            "average_rally": average,
+            "teams": teams,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn points_are_split_into_winners_and_errors() {
+        let table = TableGeometry { surface_y: 0.0, half_width: 4.0, half_length: 8.0, net_height: 0.5, restitution: 0.9, grip: 0.5 };
+        let mut stats = MatchStats::new();
+
+        //Team 0 serves, team 1 returns into the far right corner of team 0's half, team 0 can't get it back.
+        stats.on_serve(0, 8.0);
+        stats.on_shot(1, 12.5);
+        stats.on_swing(1, 0.5);
+        stats.on_bounce(0, [3.9, 0.1, 7.9], &table);
+        stats.on_bounce(1, [0.0, 0.1, -1.0], &table); //Team 1's shot on their own half, not placement.
+        stats.on_point(1);
+
+        //Then team 0 serves into the net.
+        stats.on_serve(0, 8.0);
+        stats.on_point(1);
+
+        assert_eq!(stats.rallies, [2, 1]);
+        assert_eq!(stats.teams[1].winners, 1);
+        assert_eq!(stats.teams[0].unforced_errors, 1);
+        assert_eq!((stats.teams[0].serve_points, stats.teams[0].serve_points_won), (2, 0));
+        assert_eq!(stats.teams[1].fastest_shot, 12.5);
+        assert_eq!(stats.teams[1].placement[3][3], 1);
+        assert_eq!(stats.teams[1].placement.iter().flatten().sum::<u32>(), 1);
+
+        let summary = stats.summary();
+        assert_eq!(summary["longest_rally"], 2);
+        assert_eq!(summary["teams"][1]["average_quality"], 0.5);
+        assert_eq!(summary["teams"][0]["average_quality"], serde_json::Value::Null);
+    }
+}
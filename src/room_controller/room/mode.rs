@@ -0,0 +1,91 @@
+//This is the game mode file.
+//A mode is a named preset picked when joining, it sets the scoring, time limit and physics of a room.
+//Modes are separate from the room type (singles/doubles), which only decides the seats. Matchmaking pairs players on both.
+
+use crate::config::{self, PhysicsConfig};
+
+pub const DEFAULT_MODE: &str = "classic";
+
+#[derive(Debug, PartialEq)]
+pub struct GameMode {
+    pub name: &'static str,
+    pub match_length: Option<u32>, //None uses the server's default match length.
+    pub time_limit_secs: Option<u32>, //When time runs out whoever is ahead wins, a tied score plays on to the next point.
+    pub practice: bool, //Single player, the far end returns every ball and nothing is scored.
+    pub gravity: Option<[f32; 3]>,
+    pub serve_speed: Option<f32>,
+    pub ball_radius: Option<f32>,
+}
+
+pub const GAME_MODES: [GameMode; 5] = [
+    GameMode {
+        name: "classic",
+        match_length: None,
+        time_limit_secs: None,
+        practice: false,
+        gravity: None,
+        serve_speed: None,
+        ball_radius: None,
+    },
+    GameMode {
+        name: "quick",
+        match_length: Some(5),
+        time_limit_secs: None,
+        practice: false,
+        gravity: None,
+        serve_speed: None,
+        ball_radius: None,
+    },
+    GameMode {
+        name: "blitz",
+        match_length: Some(21),
+        time_limit_secs: Some(120),
+        practice: false,
+        gravity: None,
+        serve_speed: Some(11.0),
+        ball_radius: None,
+    },
+    GameMode {
+        name: "practice",
+        match_length: None,
+        time_limit_secs: None,
+        practice: true,
+        gravity: None,
+        serve_speed: None,
+        ball_radius: None,
+    },
+    GameMode {
+        name: "fun",
+        match_length: Some(7),
+        time_limit_secs: None,
+        practice: false,
+        gravity: Some([0.0, -1.5, 0.0]), //Gentle drop, bats have to follow the ball down.
+        serve_speed: Some(5.0),
+        ball_radius: Some(0.2), //Beach ball.
+    },
+];
+
+impl GameMode {
+    pub fn preset(name: &str) -> Result<&'static GameMode, String> {
+        GAME_MODES.iter()
+            .find(|mode| mode.name == name)
+            .ok_or_else(|| format!("Unknown mode: {}", name))
+    }
+
+    pub fn match_length(&self) -> u32 {
+        //Kept within the server's limit, in case it's been set lower than the preset.
+        let rules = &config::get().rules;
+        self.match_length.unwrap_or(rules.default_match_length).min(rules.max_match_length)
+    }
+
+    pub fn physics(&self) -> PhysicsConfig {
+        //The server's physics settings with this mode's changes on top.
+        let mut physics = config::get().physics.clone();
+
+        if let Some(gravity) = self.gravity { physics.gravity = gravity; }
+        if let Some(serve_speed) = self.serve_speed { physics.serve_speed = serve_speed; }
+        if let Some(ball_radius) = self.ball_radius { physics.ball_radius = ball_radius; }
+
+        physics
+    }
+}
@@ -1,19 +1,40 @@
 //This is the physics world file.
 //Within this file is where all the physics calcs can be made and all the required data is organised.
 //Within each instance is a list of the players, their physics bodies, the ball, table.
+//What the ball, table and bats are made of comes from the config's material profiles.
 
 
-use std::sync::Arc;
-use tokio::sync::Mutex;
-
 use rapier3d::prelude::*;
-use crate::nalgebra::Vector3;
-use crate::nalgebra::distance;
-use std::collections::HashMap;
+use rapier3d::prelude::nalgebra::{Quaternion, UnitQuaternion, Vector3};
+use rapier3d::prelude::nalgebra::distance;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Duration;
 use uuid::Uuid;
+use tracing::{debug, trace, warn};
 
 mod game_state;
-use game_state::Timer;
+use game_state::{contact_quality, swing_timing, Timer};
+
+mod seeded_rng;
+pub use seeded_rng::SeededRng;
+
+pub mod trajectory;
+use trajectory::{BallState, Contact, TableGeometry, Trajectory};
+
+pub mod bat;
+
+pub mod ball_events;
+use ball_events::{side_of, BallEvent};
+
+use crate::config::{self, PhysicsConfig, RubberMaterial};
+
+pub fn seat_plane_z(seat: i32) -> f64 {
+    //Even seats play from the positive z end, odd seats from the negative end, with each pair of seats a row further back
+    //so the two bats on a doubles side don't overlap.
+    let physics = &config::get().physics;
+    let side = if seat.rem_euclid(2) == 0 { 1.0 } else { -1.0 };
+    side * (physics.paddle_plane_z + physics.partner_depth_offset * (seat / 2) as f64)
+}
 
 
 // Simple physics world struct to hold the rapier world
@@ -34,18 +55,32 @@ pub struct PhysicsWorld {
     pub physics_hooks: Box<dyn PhysicsHooks + Send + Sync>,
     pub event_handler: Box<dyn EventHandler + Send + Sync>,
     pub gravity: Vector<f32>,
+    pub integration_parameters: IntegrationParameters, //Built once, every step uses the same fixed dt.
+    pub settings: PhysicsConfig, //Server physics settings with the room's mode applied.
+    pub table: Option<TableGeometry>, //Bounces off the table are worked out by the trajectory code, so predictions and the world agree.
+    pub bat_rubbers: HashMap<Uuid, String>, //Rubber each player picked, anyone not in here plays with the default.
+    pub rng: SeededRng,
     pub player_map: HashMap<Uuid,RigidBodyHandle>,
     pub collider_map: HashMap<ColliderHandle, Uuid>, 
     pub player_collider_map: HashMap<Uuid, ColliderHandle>,//Reverse lookup for collision detect.
-    pub move_intents: HashMap<Uuid,Vector3<f64>>,
+    pub move_intents: BTreeMap<Uuid,Vector3<f64>>, //Ordered so intents are always applied in the same order.
+    pub turn_intents: BTreeMap<Uuid, UnitQuaternion<f32>>, //Bat rotations asked for since the last step.
     pub ball_handle: RigidBodyHandle,
+    pub ball_collider_handle: ColliderHandle,
+    pub touching_ball: BTreeSet<Uuid>, //Bats in contact with the ball after the last step.
+    pub ball_touches: Vec<Uuid>, //Bats that started touching the ball during the last step, read by the room's rules.
+    pub ball_events: Vec<BallEvent>, //Table, net and volley events from the last step, also read by the rules.
+    pub bounces: [u32; 2], //Bounces on each side since a bat last touched the ball (or it was put somewhere new).
     pub player_order_map: HashMap<Uuid, i32>,
-    pub player_shot_timer: HashMap<Uuid,Arc<Mutex<Timer>>>
+    pub player_shot_timer: HashMap<Uuid,Timer> //The world is only ever touched by one thread at a time, so timers need no lock of their own.
 }
 
 impl PhysicsWorld {
-    pub fn new() -> Self {
+    pub fn new(seed: u64) -> Self {
+        PhysicsWorld::with_settings(seed, config::get().physics.clone())
+    }
 
+    pub fn with_settings(seed: u64, settings: PhysicsConfig) -> Self {
 
         let mut world = RigidBodySet::new(); //creating empty collections.
         let mut colliders = ColliderSet::new();
@@ -56,9 +91,14 @@ impl PhysicsWorld {
         let ball = RigidBodyBuilder::dynamic()
             .translation(Vector3::new(0.0, 0.0, 0.0))
             .build();
-        let ball_collider = ColliderBuilder::ball(0.1).build();
-        colliders.insert(ball_collider);
+        let material = config::get().materials.ball();
+        let ball_collider = ColliderBuilder::ball(settings.ball_radius)
+            .mass(material.mass)
+            .restitution(material.restitution)
+            .friction(material.friction)
+            .build();
         let ball_handle = world.insert(ball);
+        let ball_collider_handle = colliders.insert_with_parent(ball_collider, ball_handle, &mut world); //Attaching collider to the ball so it can actually hit things.
 
 
         let island_manager = IslandManager::new();
@@ -74,7 +114,7 @@ impl PhysicsWorld {
         //let physics_hooks = <dyn PhysicsHooks>::new();
         //let event_handler = <dyn EventHandler>::new();
 
-        println!("Outputting physics world.");
+        debug!(seed, "Outputting physics world.");
 
         //This is outputting the physics world as it's return variable using the struct.
         PhysicsWorld {
@@ -90,13 +130,26 @@ impl PhysicsWorld {
             query_pipeline,
             physics_hooks,
             event_handler,
-            gravity: Vector3::new(0.0, 0.0, 0.0), //No gravity as all the directions are controlled programatically.
-            //Could potentially add gravity later though.
+            gravity: Vector3::from(settings.gravity), //Zero by default, all the directions are controlled programatically.
+            integration_parameters: IntegrationParameters {
+                dt: config::get().tick.fixed_dt(),
+                ..Default::default()
+            },
+            table: Some(TableGeometry::new(&settings, config::get().materials.table())),
+            settings,
+            rng: SeededRng::new(seed),
             player_map: HashMap::new(),
             collider_map: HashMap::new(),
             player_collider_map: HashMap::new(),
-            move_intents: HashMap::new(),
+            move_intents: BTreeMap::new(),
+            turn_intents: BTreeMap::new(),
+            bat_rubbers: HashMap::new(),
             ball_handle,
+            ball_collider_handle,
+            touching_ball: BTreeSet::new(),
+            ball_touches: Vec::new(),
+            ball_events: Vec::new(),
+            bounces: [0, 0],
             player_order_map: HashMap::new(),
             player_shot_timer: HashMap::new(),
         }
@@ -105,25 +158,25 @@ impl PhysicsWorld {
 
     }
 
-    pub fn step(&mut self, dt: f32) {
+    pub fn step(&mut self) {
 
-        //Creating/Accessing all the step parameters.
+        //Accessing all the step parameters. The world always advances by the fixed dt in integration_parameters,
+        //callers deal with wall-clock time (see Room::advance).
 
         let gravity = self.gravity;
-        let integration_parameters = IntegrationParameters {
-            dt: dt,
-            ..Default::default()
-        };
+        let before = self.ball_state(); //For the table and net, which look at the whole step.
+        self.ball_events.clear();
+        let integration_parameters = &self.integration_parameters;
 
-        let mut island_manager = &mut self.island_manager;
-        let mut broad_phase = &mut self.broad_phase;
-        let mut narrow_phase = &mut self.narrow_phase;
-        let mut rigid_body_set = &mut self.world;
-        let mut collider_set = &mut self.colliders;
-        let mut impulse_joint_set = &mut self.impulse_joint_set;
-        let mut multibody_joint_set = &mut self.multibody_joint_set;
-        let mut ccd_solver =  &mut self.ccd_solver;
-        let mut query_pipeline = &mut self.query_pipeline;
+        let island_manager = &mut self.island_manager;
+        let broad_phase = &mut self.broad_phase;
+        let narrow_phase = &mut self.narrow_phase;
+        let rigid_body_set = &mut self.world;
+        let collider_set = &mut self.colliders;
+        let impulse_joint_set = &mut self.impulse_joint_set;
+        let multibody_joint_set = &mut self.multibody_joint_set;
+        let ccd_solver =  &mut self.ccd_solver;
+        let query_pipeline = &mut self.query_pipeline;
 
 
         // if !self.move_intents.is_empty() {
@@ -140,21 +193,32 @@ impl PhysicsWorld {
         let intents = std::mem::take(&mut self.move_intents);
         for (player_id, pos) in intents { //For each movement intent that is queued.
             if let Some(&body_handle) = self.player_map.get(&player_id) //Access the rigid body handle.
-            {
-
-                if let Some(rigid_body) = rigid_body_set.get_mut(body_handle) {
+                && let Some(rigid_body) = rigid_body_set.get_mut(body_handle) {
                     assert_eq!(rigid_body.body_type(), RigidBodyType::KinematicPositionBased);
 
                     rigid_body.set_enabled(true);
                     rigid_body.set_next_kinematic_translation(vector![pos.x as f32,pos.y as f32,pos.z as f32]);
 
-                    let position = rigid_body.translation();
+                  //  let position = rigid_body.translation();
                   //  println!("Player: {} position in world space: x = {}, y = {}, z = {}", player_id, position.x, position.y, position.z);
-                }
             }
         }
         }
 
+        //Turning bats no faster than max_bat_angular_speed, a bigger turn is finished over the next steps if it's still asked for.
+        let max_turn = self.settings.max_bat_angular_speed * integration_parameters.dt;
+        for (player_id, target) in std::mem::take(&mut self.turn_intents) {
+            if let Some(rigid_body) = self.player_map.get(&player_id).and_then(|handle| rigid_body_set.get_mut(*handle)) {
+                let rotation = bat::limit_turn(*rigid_body.rotation(), target, max_turn);
+                if rotation != target {
+                    self.turn_intents.insert(player_id, target);
+                }
+
+                rigid_body.set_enabled(true);
+                rigid_body.set_next_kinematic_rotation(rotation);
+            }
+        }
+
         //These are wrong, need to use something different.
         //let physics_hooks = &self.physics_hooks;
 
@@ -178,22 +242,127 @@ impl PhysicsWorld {
 
         self.physics_pipeline.step(
             &gravity, 
-            &integration_parameters,
-            &mut island_manager,
-            &mut broad_phase,
-            &mut narrow_phase,
-            &mut rigid_body_set,
-            &mut collider_set,
-            &mut impulse_joint_set,
-            &mut multibody_joint_set,
-            &mut ccd_solver,
-            Some(&mut query_pipeline),
+            integration_parameters,
+            island_manager,
+            broad_phase,
+            narrow_phase,
+            rigid_body_set,
+            collider_set,
+            impulse_joint_set,
+            multibody_joint_set,
+            ccd_solver,
+            Some(query_pipeline),
             hooks_ref,
             event_ref,
         );
 
         //println!("Physics world Step!");
 
+        //Swing timers run on simulated time.
+        for timer in self.player_shot_timer.values_mut() {
+            timer.advance(self.integration_parameters.dt);
+        }
+
+        self.hit_table(before);
+        self.detect_ball_touches();
+        self.return_ball();
+    }
+
+    fn hit_table(&mut self, before: Option<BallState>) {
+        let (Some(before), Some(mut after), Some(table)) = (before, self.ball_state(), self.table) else {
+            return;
+        };
+
+        let Some(contact) = trajectory::table_contact(&before, &mut after, self.settings.ball_radius, &table) else {
+            return;
+        };
+        self.put_ball(&after);
+
+        let (pos, side) = (after.pos, side_of(after.pos[2]));
+        match contact {
+            Contact::Top { edge } => {
+                self.ball_events.push(BallEvent::Bounce { side, edge, pos });
+
+                self.bounces[side] += 1;
+                if self.bounces[side] == 2 {
+                    self.ball_events.push(BallEvent::DoubleBounce { side, pos });
+                }
+            }
+            Contact::Side => self.ball_events.push(BallEvent::SideHit { side, pos }),
+            Contact::NetCord => self.ball_events.push(BallEvent::NetCord { pos }),
+            Contact::Net => self.ball_events.push(BallEvent::Net { pos }),
+        }
+    }
+
+    fn detect_ball_touches(&mut self) {
+        //Compares bat overlaps with the last step, so a bat resting against the ball only counts as one touch.
+        let touching: BTreeSet<Uuid> = self.player_collider_map.iter()
+            .filter(|(_, collider_handle)| {
+                self.narrow_phase.intersection_pair(self.ball_collider_handle, **collider_handle) == Some(true)
+            })
+            .map(|(player_id, _)| *player_id)
+            .collect();
+
+        self.ball_touches = touching.difference(&self.touching_ball).copied().collect();
+        self.touching_ball = touching;
+
+        if self.ball_touches.is_empty() {
+            return;
+        }
+
+        //Taking the ball while it's still over the table, before it's bounced on the bat's own side, is a volley.
+        if let (Some(ball), Some(table)) = (self.ball_state(), self.table.as_ref())
+            && table.is_over(&ball.pos) {
+                for player_id in &self.ball_touches {
+                    let Some(bat_body) = self.player_map.get(player_id).and_then(|handle| self.world.get(*handle)) else {
+                        continue;
+                    };
+
+                    let side = side_of(bat_body.translation().z);
+                    if self.bounces[side] == 0 {
+                        self.ball_events.push(BallEvent::Volley { side, pos: ball.pos });
+                    }
+                }
+        }
+
+        self.bounces = [0, 0];
+    }
+
+    fn return_ball(&mut self) {
+        //Bats are sensors, so the ball's bounce off a blade is worked out here from the blade's angle rather than by rapier.
+        //A bat still being swung (hit_begin sent, hit_end not yet) is driven through the ball, harder the better timed the swing.
+        let Some(mut ball) = self.ball_state() else {
+            return;
+        };
+
+        for player_id in &self.ball_touches {
+            let Some(bat_body) = self.player_map.get(player_id).and_then(|handle| self.world.get(*handle)) else {
+                continue;
+            };
+
+            let normal = bat::blade_normal(bat_body.rotation());
+            let settings = &self.settings;
+
+            let mut bat_vel = *bat_body.linvel();
+            if let Some(timer) = self.player_shot_timer.get(player_id) {
+                let towards_net = if normal.z * bat_body.translation().z > 0.0 { -normal } else { normal };
+                bat_vel += towards_net * settings.swing_speed * swing_timing(timer);
+            }
+
+            let rubber = self.rubber(*player_id);
+            bat::strike(&mut ball, normal, bat_vel, settings.ball_radius, rubber.restitution, rubber.grip);
+        }
+
+        self.put_ball(&ball);
+    }
+
+    fn put_ball(&mut self, ball: &BallState) {
+        //Changes the ball's state from inside a step, unlike set_ball_state this doesn't start a new shot.
+        if let Some(ball_rb) = self.world.get_mut(self.ball_handle) {
+            ball_rb.set_translation(vector![ball.pos[0], ball.pos[1], ball.pos[2]], true);
+            ball_rb.set_linvel(vector![ball.vel[0], ball.vel[1], ball.vel[2]], true);
+            ball_rb.set_angvel(vector![ball.spin[0], ball.spin[1], ball.spin[2]], true);
+        }
     }
 
     pub fn add_player(&mut self, player_id: Uuid)   {
@@ -201,24 +370,26 @@ impl PhysicsWorld {
         //This allows us to make changes to the collections such as world, and colliders etc
         //player_id is just the unique player id and it's type.
 
+        //Taking the lowest free seat, so a seat left by someone is filled again instead of doubling up an index.
+        let player_index_num = (0..).find(|index| !self.player_order_map.values().any(|taken| taken == index)).unwrap_or(0);
+
         //Creating and inserting player rigidBody to rigidBodySet (world).
         let player_body = RigidBodyBuilder::kinematic_position_based()
-            .translation(vector![0.0, 0.0, 0.0]) // Starting position
+            .translation(vector![0.0, 0.0, seat_plane_z(player_index_num) as f32]) // Starting position, on their seat's plane.
             .build();
         let player_body_handle = self.world.insert(player_body);
 
         //Creating bat collider, cylinder in shape. This is what will follow the player mouse and communicate "hits".
+        //It's stood on it's edge so the blade faces down the table, and is a sensor as the bounce comes from the blade's angle (see return_ball).
    
-        let player_collider = ColliderBuilder::cylinder(0.05, 0.75).rotation(vector![0.0, 0.0, std::f32::consts::FRAC_PI_2]).build();
+        let player_collider = ColliderBuilder::cylinder(0.05, 0.75)
+            .rotation(vector![std::f32::consts::FRAC_PI_2, 0.0, 0.0])
+            .sensor(true)
+            .build();
         //Adding collider to collider set.
         let player_collider_handle = self.colliders.insert_with_parent(player_collider, player_body_handle, &mut self.world);
 
-        let mut player_index_num = 0;
-
-        for (player_id, p_body_handle) in &self.player_map {
-            player_index_num += 1; //Determining index of player_order using world rigid_bodies.
-            println!("Adding to index: {}", player_index_num);
-        }
+        debug!(%player_id, seat = player_index_num, "Adding to index");
 
         //Tracking player_id and their player order, which will be used to set sides of board.
         self.player_order_map.insert(player_id,player_index_num);
@@ -233,21 +404,93 @@ impl PhysicsWorld {
         //Tracking the player_collider handles, this enables us to remove the collider_map entry for the player_id when disconnecting. 
         self.player_collider_map.insert(player_id,player_collider_handle);
 
-        println!("Player added to physics world: {}",player_id);
+        debug!(%player_id, "Player added to physics world");
 
         // You could store paddle_handle in a map if you want to track per-player paddles
     }
 
-    pub fn remove_player(&mut self, player_id:Uuid) {
+    pub fn restore_player(&mut self, player_id: Uuid, player_index: i32, pos: [f32; 3], rot: [f32; 4]) {
+        //Used by replays, adds the player back with the index, position and bat rotation they had when the keyframe was recorded.
+        self.add_player(player_id);
+        self.player_order_map.insert(player_id, player_index);
+
+        let rotation = bat::check_rotation(rot.map(f64::from)).unwrap_or_else(|_| UnitQuaternion::identity());
+        if let Some(rigid_body) = self.player_map.get(&player_id).and_then(|handle| self.world.get_mut(*handle)) {
+            rigid_body.set_translation(vector![pos[0], pos[1], pos[2]], true);
+            rigid_body.set_rotation(rotation, true);
+        }
+    }
+
+    pub fn restore_inputs(&mut self, player_id: Uuid, moving: Option<[f64; 3]>, turning: Option<[f32; 4]>, swing: Option<Duration>) {
+        //Used by replays, puts back the moves, turns and swing a player had waiting when the keyframe was recorded.
+        if let Some(d) = moving {
+            self.move_intents.insert(player_id, Vector3::new(d[0], d[1], d[2]));
+        }
+        if let Some(r) = turning {
+            //Kept exactly as it was recorded, it was already checked when it was first asked for.
+            self.turn_intents.insert(player_id, UnitQuaternion::new_unchecked(Quaternion::new(r[3], r[0], r[1], r[2])));
+        }
+        if let Some(elapsed) = swing {
+            self.player_shot_timer.insert(player_id, Timer::resume(self.settings.hit_timer_secs, elapsed));
+        }
+    }
+
+    pub fn bat_rotation(&self, player_id: Uuid) -> [f32; 4] {
+        //As [x, y, z, w], the same way players send it.
+        self.player_map.get(&player_id)
+            .and_then(|handle| self.world.get(*handle))
+            .map(|body| {
+                let rotation = body.rotation();
+                [rotation.i, rotation.j, rotation.k, rotation.w]
+            })
+            .unwrap_or(bat::LEVEL_BAT)
+    }
+
+    pub fn set_ball_state(&mut self, pos: [f32; 3], vel: [f32; 3]) {
+        //Teleports the ball, used for serves, feeds and restoring a replay keyframe. Bounces counted so far are forgotten.
+        self.bounces = [0, 0];
+        if let Some(ball_rb) = self.world.get_mut(self.ball_handle) {
+            ball_rb.set_translation(vector![pos[0], pos[1], pos[2]], true);
+            ball_rb.set_linvel(vector![vel[0], vel[1], vel[2]], true);
+        }
+    }
+
+    pub fn set_ball_spin(&mut self, spin: [f32; 3]) {
+        //Angular velocity in radians per second, it changes how the ball comes off a bat.
+        if let Some(ball_rb) = self.world.get_mut(self.ball_handle) {
+            ball_rb.set_angvel(vector![spin[0], spin[1], spin[2]], true);
+        }
+    }
+
+    pub fn ball_state(&self) -> Option<BallState> {
+        let ball_rb = self.world.get(self.ball_handle)?;
+        let (pos, vel, spin) = (ball_rb.translation(), ball_rb.linvel(), ball_rb.angvel());
 
-        //Accessing player_body_handle using player_id
-        let player_body_handle = self.player_map.get(&player_id).copied().unwrap();
+        Some(BallState { pos: [pos.x, pos.y, pos.z], vel: [vel.x, vel.y, vel.z], spin: [spin.x, spin.y, spin.z] })
+    }
+
+    pub fn predict_ball(&self, secs: f32) -> Option<Trajectory> {
+        //Where the ball goes over the next few seconds if nothing touches it, using this world's gravity, table and tick.
+        let ball = self.ball_state()?;
+        let gravity = [self.gravity.x, self.gravity.y, self.gravity.z];
+
+        Some(Trajectory::predict(ball, gravity, self.settings.ball_radius, self.table.as_ref(), secs, self.integration_parameters.dt))
+    }
+
+    pub fn get_player_by_number(&self, player_index: i32) -> Option<Uuid> {
+        //Reverse of get_player_number.
+        self.player_order_map.iter()
+            .find(|(_, index)| **index == player_index)
+            .map(|(player_id, _)| *player_id)
+    }
+
+    pub fn remove_player(&mut self, player_id:Uuid) {
 
         //Accessing player_body_handle using player_id, check exist, Then remove from rigidBodySet of physics world.
         if let Some(&body_handle) = self.player_map.get(&player_id) 
         {
             // Remove the rigid body and associated colliders
-            let colliders_removed = self.world.remove(
+            self.world.remove(
                 body_handle,
                 &mut self.island_manager,
                 &mut self.colliders,
@@ -261,38 +504,39 @@ impl PhysicsWorld {
 
             //Remove info for the player_index.
             self.player_order_map.remove(&player_id);
+            self.bat_rubbers.remove(&player_id);
 
             //Accessing collider handle, using result to remove from collider map, then removing from the joining map (player_collider_map)
             let player_collider_handle = self.player_collider_map.get(&player_id).copied().unwrap();
             self.collider_map.remove(&player_collider_handle);
             self.player_collider_map.remove(&player_id);
        
-           //Prints all colliders left in collider_map.
+           //Logs all colliders left in collider_map.
             for (key, value) in &self.collider_map {
-                    println!("Key: {:?}, Value: {:?}", key, value);
+                    trace!(collider = ?key, player_id = %value, "Collider left in world");
             }
     
-            //Print if successful.
-            println!("Player removed from physics world: {}", player_id);
+            debug!(%player_id, "Player removed from physics world");
         } else {
-            println!("Tried to remove non-existent player: {}", player_id);
+            warn!(%player_id, "Tried to remove non-existent player");
         }
 
 
     }
 
+    #[allow(dead_code)]
     pub fn set_player_position(&mut self, player_id: Uuid, dx: f64, dy: f64, dz: f64) {
 
          if let Some(&body_handle) = self.player_map.get(&player_id) 
         {
 
-            let mut rigid_body = self.world.get_mut(body_handle).unwrap();
+            let rigid_body = self.world.get_mut(body_handle).unwrap();
             assert_eq!(rigid_body.body_type(), RigidBodyType::KinematicPositionBased);
 
             rigid_body.set_enabled(true);
             rigid_body.set_next_kinematic_translation(vector![dx as f32,dy as f32,dz as f32]);
 
-            let position = rigid_body.translation();
+            //let position = rigid_body.translation();
             //println!("Player position in world space: x = {}, y = {}, z = {}", position.x, position.y, position.z);
 
 
@@ -311,41 +555,49 @@ impl PhysicsWorld {
         if let Some(&body_handle) = self.player_map.get(&player_id) 
         {
 
-            let mut rigid_body = self.world.get_mut(body_handle).unwrap();
+            let rigid_body = self.world.get_mut(body_handle).unwrap();
             
             assert_eq!(rigid_body.body_type(), RigidBodyType::KinematicPositionBased);
 
             if let Some(&player_index_num) = self.player_order_map.get(&player_id) { //used to verify position in world space.
 
-                if player_index_num == 0 {
+                dz = seat_plane_z(player_index_num); //ensuring the user stays on their seat's plane, on their team's side.
 
-                    dz = 9.0; //ensuring that the user is on a z level of 9.
-        
-                } else if player_index_num == 1 {
+            }
+            //rigid_body.set_enabled(true);
+           // rigid_body.set_next_kinematic_translation(vector![dx as f32,dy as f32,dz as f32]);
+           //Adding player insert to hashmap so it can be processed in the physics world step function.
+            self.move_intents.insert(player_id,vector![dx,dy,dz]); 
+        }
 
-                    if dz < 0.0 && dz > -9.001 && dz < -8.999 { //ensuring player order are opposite in physics world.
-                               // println!("dz is within the target negative range");
-                    } else {
-                        dz = 9.0;
-                        dz *= -1.0; //flipping the user so that they are on opposing sides.
-                    }
+    }
 
+    pub fn set_rubber(&mut self, player_id: Uuid, name: &str) -> Result<(), String> {
+        config::get().materials.rubber(name)?;
+        if !self.player_map.contains_key(&player_id) {
+            return Err("Not in a room".to_string());
+        }
 
-                   // println!("Flipping User: {} for dz: {}",player_id,dz);
+        self.bat_rubbers.insert(player_id, name.to_string());
+        Ok(())
+    }
 
-                }
+    pub fn rubber_name(&self, player_id: Uuid) -> &str {
+        self.bat_rubbers.get(&player_id).unwrap_or(&config::get().materials.default_rubber)
+    }
 
+    fn rubber(&self, player_id: Uuid) -> &'static RubberMaterial {
+        let materials = &config::get().materials;
+        materials.rubber(self.rubber_name(player_id)).unwrap_or_else(|_| &materials.rubbers[&materials.default_rubber])
+    }
 
-            }
-            //rigid_body.set_enabled(true);
-           // rigid_body.set_next_kinematic_translation(vector![dx as f32,dy as f32,dz as f32]);
-           //Adding player insert to hashmap so it can be processed in the physics world step function.
-            self.move_intents.insert(player_id,vector![dx as f64,dy as f64,dz as f64]); 
+    pub fn add_turn_to_queue(&mut self, player_id: Uuid, rotation: UnitQuaternion<f32>) {
+        if self.player_map.contains_key(&player_id) {
+            self.turn_intents.insert(player_id, rotation);
         }
-
     }
 
-    pub fn get_player_number(&mut self, player_id: Uuid) -> i32 {
+    pub fn get_player_number(&self, player_id: Uuid) -> i32 {
         match self.player_order_map.get(&player_id) {
             Some(&index) => index, //return index value.
             None => -1, // returning an impossible order value if the hash doesn't exist yet.
@@ -354,62 +606,53 @@ impl PhysicsWorld {
 
     pub fn player_hit(&mut self, player_id:Uuid) { //Called when player hits "mouse down" to start hit.
 
-        if let Some(&body_handle) = self.player_map.get(&player_id) 
+        if self.player_map.contains_key(&player_id)
         {
 
             //When creating timer, we must verify that there is no existing timer already for an id.
             //This ensures that the user cannot spam timer creation and destroy the server.
 
-            let timer = Arc::new(Mutex::new(Timer::new(3)));
+            let timer = Timer::new(self.settings.hit_timer_secs);
             self.player_shot_timer.insert(player_id,timer);
 
-            println!("Hit timer started.");
+            trace!(%player_id, "Hit timer started.");
 
         }
 
 
     }
 
-    pub async fn player_hit_exec(&mut self, player_id:Uuid) {
+    pub fn player_hit_exec(&mut self, player_id:Uuid) -> Option<f32> {
+        //Finishes a swing, returning it's contact quality (0 to 1) if one was started with player_hit.
+
+        let mut quality = None;
 
-        println!("Hit timer started.");
         if let Some(&body_handle) = self.player_map.get(&player_id) 
         {
 
-            if let Some(timer) = self.player_shot_timer.get(&player_id) {
-                println!("Timer is here");
-                let is_done = timer.lock().await.timer_value().as_millis(); //Retrieving timer value as millis.
-                
-                println!("{:?} shot time", is_done);
-                
-            }
+            let rigid_body = self.world.get(body_handle).unwrap(); //Getting rigidBody of player
 
-            let mut rigid_body = self.world.get_mut(body_handle).unwrap(); //Getting rigidBody of player
-
-            let mut p_position = rigid_body.position(); //retrieving position of hit location.
+            let p_position = rigid_body.position(); //retrieving position of hit location.
 
             let p_pos = Point::from(p_position.translation.vector); //Getting the point from the position.
 
             let ball_handle = self.ball_handle; //Accessing ball handle.
 
-           // let ball_rb = self.world.get(ball_handle);
-           let mut ball_rb = self.world.get_mut(ball_handle).unwrap();
-
             if let Some(ball_rb) = self.world.get(ball_handle) {
 
-                let mut b_position = ball_rb.position();
+                let b_position = ball_rb.position();
 
                 let b_pos = Point::from(b_position.translation.vector);
 
                 let dist = distance(&p_pos,&b_pos); //finding distance.
-                println!("hit dist from bat and ball = {}",dist);
+
+                if let Some(timer) = self.player_shot_timer.get(&player_id) {
+                    quality = Some(contact_quality(timer, dist));
+                }
             }
 
 
-            if let Some(timer) = self.player_shot_timer.get(&player_id) { //Removing timer entry for player to prevent duplicates.
-                self.player_shot_timer.remove(&player_id);
-                println!("Removed timer for player.");
-            }
+            self.player_shot_timer.remove(&player_id); //Removing timer entry for player to prevent duplicates.
 
 
         
@@ -417,6 +660,7 @@ impl PhysicsWorld {
 
         }
 
+        quality
     }
 
     // pub fn distance(start: Vector3<T>, other: Vector3<T>) -> f32 {
@@ -426,6 +670,7 @@ impl PhysicsWorld {
     //     (dx * dx + dy * dy + dz * dz).sqrt()
     // }
 
+    #[allow(dead_code)]
     //Used to lerp between 2 vector3 values/positions over time t.
     pub fn lerp_vector3(&mut self, start_vec : Vector3<f64>, end_vec:Vector3<f64>, t : f64) -> Vector3<f64> { 
 
@@ -448,6 +693,7 @@ impl PhysicsWorld {
 
     }
 
+    #[allow(dead_code)]
     //Used to lerp between two values over time, t.
     pub fn lerp_two_vals(a : f64, b : f64, t: f64) -> f64 {
         a + (b - a) * t
@@ -458,3 +704,188 @@ impl PhysicsWorld {
 
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rapier3d::prelude::nalgebra::{Quaternion, Vector4};
+
+    const TEST_TICKS: u64 = 240;
+
+    //Input log entries are (tick, player index, move).
+    type InputLog = Vec<(u64, usize, [f64; 3])>;
+
+    fn rally_inputs() -> InputLog {
+        //Both bats sit in the ball's path, with player 0 drifting sideways so returns come back at an angle.
+        let mut inputs = vec![(0, 0, [0.0, 0.0, 9.0]), (0, 1, [0.0, 0.0, -9.0])];
+        for tick in 30..60 {
+            inputs.push((tick, 0, [(tick - 30) as f64 * 0.01, 0.05, 9.0]));
+        }
+        inputs
+    }
+
+    fn run_input_log(seed: u64, inputs: &InputLog) -> Vec<[u32; 6]> {
+        //Replays the ordered input log through a fresh world and returns the ball's position and velocity bits each tick.
+        let player_ids = [Uuid::from_u128(1), Uuid::from_u128(2)];
+
+        let mut world = PhysicsWorld::new(seed);
+        for player_id in player_ids {
+            world.add_player(player_id);
+        }
+        world.set_ball_state([0.0, 0.0, 0.0], [0.0, 0.0, 8.0]);
+
+        let mut trajectory = Vec::new();
+        let mut next_input = 0;
+
+        for tick in 0..TEST_TICKS {
+            while let Some(&(input_tick, player, [dx, dy, dz])) = inputs.get(next_input) {
+                if input_tick != tick {
+                    break;
+                }
+                world.add_move_to_queue(player_ids[player], dx, dy, dz);
+                next_input += 1;
+            }
+
+            world.step();
+
+            let ball = world.world.get(world.ball_handle).unwrap();
+            let (pos, vel) = (ball.translation(), ball.linvel());
+            trajectory.push([pos.x, pos.y, pos.z, vel.x, vel.y, vel.z].map(f32::to_bits));
+        }
+
+        trajectory
+    }
+
+    #[test]
+    fn same_input_log_gives_bit_identical_trajectory() {
+        let first = run_input_log(42, &rally_inputs());
+        let second = run_input_log(42, &rally_inputs());
+
+        assert_eq!(first.len(), TEST_TICKS as usize);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn input_log_changes_trajectory() {
+        //Guards the test above, the bats have to actually touch the ball for it to mean anything.
+        let mut dodging = rally_inputs();
+        for (_, player, [dx, _, _]) in dodging.iter_mut() {
+            if *player == 0 {
+                *dx += 5.0;
+            }
+        }
+
+        assert_ne!(run_input_log(42, &rally_inputs()), run_input_log(42, &dodging));
+    }
+
+    #[test]
+    fn same_seed_gives_same_random_sequence() {
+        let mut first = PhysicsWorld::new(7);
+        let mut second = PhysicsWorld::new(7);
+        let mut other = PhysicsWorld::new(8);
+
+        let first_values: Vec<u64> = (0..16).map(|_| first.rng.next_u64()).collect();
+        let second_values: Vec<u64> = (0..16).map(|_| second.rng.next_u64()).collect();
+        let other_values: Vec<u64> = (0..16).map(|_| other.rng.next_u64()).collect();
+
+        assert_eq!(first_values, second_values);
+        assert_ne!(first_values, other_values);
+    }
+
+    #[test]
+    fn turned_bat_sends_the_ball_off_at_an_angle() {
+        let player_id = Uuid::from_u128(1);
+        let mut world = PhysicsWorld::new(1);
+        world.add_player(player_id);
+
+        //Asking for more than one tick's worth of turn, the bat gets there over a few steps.
+        let target = UnitQuaternion::from_euler_angles(0.0, 1.0, 0.0);
+        world.add_turn_to_queue(player_id, target);
+        world.step();
+        let turned = UnitQuaternion::from_quaternion(Quaternion::from(Vector4::from(world.bat_rotation(player_id))));
+        assert!(turned.angle() < 1.0 && turned.angle() > 0.0);
+
+        //Then back to a smaller angle for the shot.
+        let target = UnitQuaternion::from_euler_angles(0.0, 0.3, 0.0);
+        world.add_turn_to_queue(player_id, target);
+        for _ in 0..10 {
+            world.step();
+        }
+        let turned = UnitQuaternion::from_quaternion(Quaternion::from(Vector4::from(world.bat_rotation(player_id))));
+        assert!(turned.angle_to(&target) < 1e-4);
+
+        world.set_ball_state([0.0, 0.0, 7.0], [0.0, 0.0, 8.0]);
+        for _ in 0..15 {
+            world.step();
+        }
+
+        let ball = world.ball_state().unwrap();
+        assert!(ball.vel[2] < 0.0 && ball.vel[0].abs() > 1.0, "ball came off at {:?}", ball.vel);
+    }
+
+    #[test]
+    fn rubbers_and_table_come_from_the_material_profiles() {
+        //The same closed blade puts far more spin on the ball with inverted rubber than with anti.
+        let spin_off = |rubber: &str| {
+            let player_id = Uuid::from_u128(1);
+            let mut world = PhysicsWorld::new(1);
+            world.add_player(player_id);
+            world.set_rubber(player_id, rubber).unwrap();
+            world.add_turn_to_queue(player_id, UnitQuaternion::from_euler_angles(0.3, 0.0, 0.0));
+            world.step();
+
+            world.set_ball_state([0.0, 0.0, 7.0], [0.0, 0.0, 8.0]);
+            for _ in 0..15 {
+                world.step();
+            }
+            world.ball_state().unwrap().spin[0].abs()
+        };
+        assert!(spin_off("inverted") > 4.0 * spin_off("anti"));
+        assert!(PhysicsWorld::new(1).set_rubber(Uuid::from_u128(1), "sandpaper").is_err());
+
+        //A ball dropped on the table bounces where the predictor says it will.
+        let settings = PhysicsConfig { gravity: [0.0, -9.8, 0.0], ..PhysicsConfig::default() };
+        let mut world = PhysicsWorld::with_settings(1, settings);
+        world.set_ball_state([0.0, 0.5, -3.0], [0.0, 0.0, 2.0]);
+
+        let trajectory = world.predict_ball(1.0).unwrap();
+        assert_eq!(trajectory.bounces.len(), 1);
+        for predicted in &trajectory.states {
+            world.step();
+            let actual = world.ball_state().unwrap();
+            assert!((0..3).all(|axis| (predicted.pos[axis] - actual.pos[axis]).abs() < 1e-4), "{:?} vs {:?}", predicted, actual);
+        }
+    }
+
+    #[test]
+    fn second_bounce_and_volleys_are_reported() {
+        let steps = |world: &mut PhysicsWorld, count: usize| {
+            let mut events = Vec::new();
+            for _ in 0..count {
+                world.step();
+                events.append(&mut world.ball_events);
+            }
+            events
+        };
+
+        //Left to bounce on one side, the second bounce is a double bounce on that side.
+        let settings = PhysicsConfig { gravity: [0.0, -9.8, 0.0], ..PhysicsConfig::default() };
+        let mut world = PhysicsWorld::with_settings(1, settings);
+        world.set_ball_state([0.0, 0.5, -3.0], [0.0, 0.0, 0.5]);
+
+        let events = steps(&mut world, 60);
+        assert!(matches!(events[..2], [BallEvent::Bounce { side: 1, edge: false, .. }, BallEvent::Bounce { side: 1, .. }]), "{:?}", events);
+        assert!(matches!(events[2], BallEvent::DoubleBounce { side: 1, .. }));
+
+        //With a table long enough to reach the bat, taking the ball over it before it bounces is a volley.
+        let player_id = Uuid::from_u128(1);
+        let settings = PhysicsConfig { table_half_length: 10.0, ..PhysicsConfig::default() };
+        let mut world = PhysicsWorld::with_settings(1, settings);
+        world.add_player(player_id);
+        world.set_ball_state([0.0, 0.0, 7.0], [0.0, 0.0, 8.0]);
+
+        let events = steps(&mut world, 15);
+        assert!(matches!(events[..], [BallEvent::Volley { side: 0, .. }]), "{:?}", events);
+        assert_eq!(world.bounces, [0, 0]);
+    }
+}
@@ -0,0 +1,23 @@
+//This is the ball events file.
+//Each step the physics world sorts out what the ball did besides touching bats: bounces (edge balls included), side hits,
+//clipping or running into the net, a second bounce on one side and a bat taking the ball over the table before it bounced.
+//The last two need gravity, in the default modes the ball only meets the table when a bat angles it down and then flies on upward.
+//The room hands these to the rules, which decide on points and lets, and sends them on to the players.
+
+use serde::Serialize;
+
+//Sides are numbered the same as teams, side 0 is positive z.
+pub fn side_of(z: f32) -> usize {
+    if z >= 0.0 { 0 } else { 1 }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BallEvent {
+    Bounce { side: usize, edge: bool, pos: [f32; 3] },
+    SideHit { side: usize, pos: [f32; 3] },
+    NetCord { pos: [f32; 3] },
+    Net { pos: [f32; 3] },
+    DoubleBounce { side: usize, pos: [f32; 3] }, //Second bounce on a side since a bat last touched the ball.
+    Volley { side: usize, pos: [f32; 3] }, //Side of the bat that took the ball over the table before it bounced there.
+}
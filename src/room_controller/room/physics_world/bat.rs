@@ -0,0 +1,98 @@
+//This is the bat file.
+//Bats can be turned as well as moved, players send the rotation they want as a quaternion [x, y, z, w] along with each move.
+//A level bat (no rotation) has it's blade facing straight down the table, tilting it angles the returns.
+//The world turns each bat towards what was asked no faster than max_bat_angular_speed, and when the ball touches a blade
+//it's reflected off the blade's face and picks up spin from the way the blade brushes it.
+
+use rapier3d::prelude::nalgebra::{Quaternion, UnitQuaternion, Vector3};
+
+use super::trajectory::{apply_grip, BallState};
+
+pub const LEVEL_BAT: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
+
+pub fn check_rotation(rot: [f64; 4]) -> Result<UnitQuaternion<f32>, String> {
+    //Any non-zero quaternion is fine, it's normalised here so clients don't have to be exact.
+    let [x, y, z, w] = rot.map(|part| part as f32);
+    let quaternion = Quaternion::new(w, x, y, z);
+
+    if !quaternion.coords.iter().all(|part| part.is_finite()) || quaternion.norm() < 1e-6 {
+        return Err("Bat rotation must be a non-zero quaternion [x, y, z, w]".to_string());
+    }
+
+    Ok(UnitQuaternion::from_quaternion(quaternion))
+}
+
+pub fn limit_turn(current: UnitQuaternion<f32>, target: UnitQuaternion<f32>, max_angle: f32) -> UnitQuaternion<f32> {
+    //Turns part of the way if the whole turn is faster than a bat can go, a flick across a single tick can't be used to slap the ball.
+    let angle = current.angle_to(&target);
+    if angle <= max_angle {
+        return target;
+    }
+
+    //Exactly opposite rotations have no single shortest turn, the bat stays put until the player asks for something else.
+    current.try_slerp(&target, max_angle / angle, 1e-6).unwrap_or(current)
+}
+
+pub fn blade_normal(rotation: &UnitQuaternion<f32>) -> Vector3<f32> {
+    //The level blade faces along z.
+    rotation * Vector3::z()
+}
+
+pub fn strike(ball: &mut BallState, normal: Vector3<f32>, bat_vel: Vector3<f32>, ball_radius: f32, restitution: f32, grip: f32) {
+    //Bounces the ball off the blade's face, with the bat's own movement added so a bat pushed into the ball hits it harder.
+    let relative = Vector3::from(ball.vel) - bat_vel;
+
+    //Either face of the blade can hit, whichever one the ball is coming at.
+    let normal = if relative.dot(&normal) > 0.0 { -normal } else { normal };
+    let closing = relative.dot(&normal);
+    if closing >= 0.0 {
+        return; //Already heading away from the blade.
+    }
+
+    let vel = Vector3::from(ball.vel) - normal * (1.0 + restitution) * closing;
+    ball.vel = [vel.x, vel.y, vel.z];
+
+    apply_grip(ball, [normal.x, normal.y, normal.z], [bat_vel.x, bat_vel.y, bat_vel.z], ball_radius, grip);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_4;
+
+    fn incoming() -> BallState {
+        BallState { pos: [0.0, 0.0, 9.0], vel: [0.0, 0.0, 8.0], spin: [0.0; 3] }
+    }
+
+    #[test]
+    fn blade_angle_decides_where_the_ball_goes() {
+        //A level blade sends the ball straight back.
+        let mut ball = incoming();
+        strike(&mut ball, blade_normal(&UnitQuaternion::identity()), Vector3::zeros(), 0.1, 1.0, 0.0);
+        assert_eq!(ball.vel, [0.0, 0.0, -8.0]);
+
+        //Turned 45 degrees about y, the blade sends it out sideways.
+        let mut ball = incoming();
+        let turned = UnitQuaternion::from_euler_angles(0.0, FRAC_PI_4, 0.0);
+        strike(&mut ball, blade_normal(&turned), Vector3::zeros(), 0.1, 1.0, 0.0);
+        assert!(ball.vel[0].abs() > 7.9 && ball.vel[2].abs() < 1e-4, "{:?}", ball.vel);
+
+        //Closed (tilted about x) with grip, the blade brushes the ball and puts spin on it.
+        let mut ball = incoming();
+        let closed = UnitQuaternion::from_euler_angles(0.3, 0.0, 0.0);
+        strike(&mut ball, blade_normal(&closed), Vector3::zeros(), 0.1, 0.9, 0.5);
+        assert!(ball.vel[2] < 0.0 && ball.spin[0].abs() > 1.0, "{:?}", ball);
+    }
+
+    #[test]
+    fn turns_are_capped_and_bad_rotations_refused() {
+        let target = UnitQuaternion::from_euler_angles(0.0, 1.0, 0.0);
+        let turned = limit_turn(UnitQuaternion::identity(), target, 0.25);
+        assert!((turned.angle() - 0.25).abs() < 1e-5);
+        assert_eq!(limit_turn(UnitQuaternion::identity(), target, 2.0), target);
+
+        assert!(check_rotation([0.0; 4]).is_err());
+        assert!(check_rotation([f64::NAN, 0.0, 0.0, 1.0]).is_err());
+        assert!((check_rotation([0.0, 0.0, 0.0, 2.0]).unwrap().angle()).abs() < 1e-6);
+    }
+}
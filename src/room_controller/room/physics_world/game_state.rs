@@ -1,47 +1,73 @@
 //This is the timer file.
 //This is where the timer for each player's hit is calculated.
 //This is used to determine hit "quality".
+//Timers count simulated time (advanced by every physics step) rather than wall-clock time, so replays score hits the same.
 
 
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
+pub const IDEAL_WIND_UP_SECS: f32 = 0.3; //Wind-up that gives a full strength swing, shorter is rushed and longer has been held.
+pub const HIT_REACH: f32 = 0.85; //Bat radius plus the ball's, further than this at the end of a swing is a miss.
+
+#[allow(dead_code)]
 pub struct Timer {
-    start: Instant,
+    elapsed: Duration,
     duration: Duration,
 }
 
 impl Timer {
     pub fn new(duration_secs: u64) -> Self  {
          Timer {
-            start: Instant::now(),
+            elapsed: Duration::ZERO,
             duration: Duration::from_secs(duration_secs),
         }
-        
+
     }
 
-    pub fn start_timer(duration_secs: u64) -> Self {
+    pub fn resume(duration_secs: u64, elapsed: Duration) -> Self {
+        //A timer part way through, used when a replay keyframe is restored mid-swing.
         Timer {
-            start: Instant::now(),
+            elapsed,
             duration: Duration::from_secs(duration_secs),
         }
     }
 
+    #[allow(dead_code)]
+    pub fn start_timer(duration_secs: u64) -> Self {
+        Timer::new(duration_secs)
+    }
+
+    pub fn advance(&mut self, dt: f32) {
+        self.elapsed += Duration::from_secs_f32(dt);
+    }
+
+    #[allow(dead_code)]
     pub fn remaining(&self) -> Duration {
-        let elapsed = self.start.elapsed();
-        if elapsed >= self.duration {
-            Duration::from_secs(0)
-        } else {
-            self.duration - elapsed
-        }
+        self.duration.saturating_sub(self.elapsed)
     }
 
     pub fn is_done(&self) -> bool {
-        self.start.elapsed() >= self.duration
+        self.elapsed >= self.duration
     }
 
     pub fn timer_value(&self) -> Duration {
-        self.start.elapsed()
+        self.elapsed
     }
 }
 
+pub fn swing_timing(timer: &Timer) -> f32 {
+    //0 to 1, how close the wind-up so far is to the ideal. A swing held until the timer runs out has no power left in it.
+    if timer.is_done() {
+        return 0.0;
+    }
 
+    let wind_up = timer.timer_value().as_secs_f32();
+    1.0 - ((wind_up - IDEAL_WIND_UP_SECS).abs() / IDEAL_WIND_UP_SECS).min(1.0)
+}
+
+pub fn contact_quality(timer: &Timer, distance: f32) -> f32 {
+    //0 to 1, how well timed the swing was multiplied by how close the bat was to the ball when it finished.
+    let proximity = 1.0 - (distance / HIT_REACH).min(1.0);
+
+    swing_timing(timer) * proximity
+}
@@ -0,0 +1,36 @@
+//This is the seeded random number file.
+//Anything random that can change the simulation must come from here, so a room seeded the same way plays out the same way.
+//It is a SplitMix64 generator, small and fast, and it's whole state is a single u64 which can be stored in replay keyframes.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        SeededRng { state: seed }
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_f32(&mut self) -> f32 {
+        //Uniform in [0, 1), using the top 24 bits so every value is exactly representable.
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    pub fn range_f32(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.next_f32()
+    }
+}
@@ -0,0 +1,306 @@
+//This is the trajectory file.
+//It predicts where the ball will go from it's current state, stepping the same way (and with the same fixed dt) as the physics world,
+//so with nothing in the way the prediction lands exactly where the simulation will.
+//Gravity bends the path and the ball bounces off the table (and runs into the net) if there is one. Spin only matters at a bounce, the world has no air drag or Magnus force.
+//Bots use it to find where to put their bat, practice rooms can send it to the player as an aim hint.
+
+use serde::Serialize;
+
+use crate::config::{PhysicsConfig, TableMaterial};
+
+pub const MAX_PREDICTION_SECS: f32 = 5.0; //Longest path anyone can ask for, past this it's just a guess anyway.
+pub const TABLE_THICKNESS: f32 = 0.15; //Depth of the table's sides and ends, a ball hitting these is a side hit.
+pub const NET_OVERHANG: f32 = 0.75; //How far the net sticks out past each side of the table.
+pub const NET_CORD_PACE: f32 = 0.5; //Share of it's speed a ball keeps when it clips the top of the net.
+pub const NET_RESTITUTION: f32 = 0.1; //A ball that runs into the net just drops off it.
+
+//The ball's state, the starting point of a prediction.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BallState {
+    pub pos: [f32; 3],
+    pub vel: [f32; 3],
+    pub spin: [f32; 3], //Angular velocity, radians per second.
+}
+
+//The playing surface, a flat rectangle centred on the net with it's top at surface_y.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TableGeometry {
+    pub surface_y: f32,
+    pub half_width: f32, //Along x.
+    pub half_length: f32, //Along z, from the net to each end.
+    pub net_height: f32, //Above surface_y, the net runs across z = 0 and past the sides by NET_OVERHANG.
+    pub restitution: f32, //Share of the ball's vertical speed kept after a bounce.
+    pub grip: f32,
+}
+
+impl TableGeometry {
+    pub fn new(settings: &PhysicsConfig, material: &TableMaterial) -> Self {
+        TableGeometry {
+            surface_y: settings.table_height,
+            half_width: settings.table_half_width,
+            half_length: settings.table_half_length,
+            net_height: settings.net_height,
+            restitution: material.restitution,
+            grip: material.grip,
+        }
+    }
+
+    fn distance_outside(&self, pos: &[f32; 3]) -> f32 {
+        //How far the ball's centre is outside the top's rectangle when looked at from above, 0 when it's over the table.
+        let dx = (pos[0].abs() - self.half_width).max(0.0);
+        let dz = (pos[2].abs() - self.half_length).max(0.0);
+        (dx * dx + dz * dz).sqrt()
+    }
+
+    pub fn is_over(&self, pos: &[f32; 3]) -> bool {
+        self.distance_outside(pos) == 0.0 && pos[1] > self.surface_y
+    }
+}
+
+//What the ball ran into during a step, the physics world turns these into ball events for the rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Contact {
+    Top { edge: bool }, //Bounced on the playing surface, edge when it only caught the rim.
+    Side, //Hit a side or end of the table below the top.
+    NetCord, //Clipped the top of the net and went on over.
+    Net, //Ran into the net and dropped back.
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Bounce {
+    pub t: f32, //Seconds from the start of the prediction.
+    pub pos: [f32; 3],
+}
+
+//Where (and when) the ball first reaches a bat plane.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Intercept {
+    pub t: f32,
+    pub pos: [f32; 3],
+    pub vel: [f32; 3],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trajectory {
+    pub dt: f32,
+    pub states: Vec<BallState>, //After each step, states[0] is one dt in.
+    pub bounces: Vec<Bounce>,
+}
+
+impl Trajectory {
+    pub fn predict(ball: BallState, gravity: [f32; 3], ball_radius: f32, table: Option<&TableGeometry>, secs: f32, dt: f32) -> Self {
+        let steps = (secs.clamp(0.0, MAX_PREDICTION_SECS) / dt).ceil() as usize;
+        let mut states = Vec::with_capacity(steps);
+        let mut bounces = Vec::new();
+        let mut state = ball;
+
+        for step in 1..=steps {
+            //Velocity first then position, the order the physics world integrates in.
+            let before = state;
+            for (axis, pull) in gravity.iter().enumerate() {
+                state.vel[axis] += pull * dt;
+                state.pos[axis] += state.vel[axis] * dt;
+            }
+
+            if let Some(table) = table
+                && let Some(Contact::Top { .. }) = table_contact(&before, &mut state, ball_radius, table) {
+                    bounces.push(Bounce { t: step as f32 * dt, pos: state.pos });
+            }
+
+            states.push(state);
+        }
+
+        Trajectory { dt, states, bounces }
+    }
+
+    pub fn intercept(&self, start: &BallState, plane_z: f32) -> Option<Intercept> {
+        //First crossing of the plane, heading towards it's side of the table. The point is interpolated between steps.
+        let towards = plane_z.signum();
+        let mut previous = start;
+
+        for (index, state) in self.states.iter().enumerate() {
+            let crossed = (previous.pos[2] - plane_z) * towards < 0.0 && (state.pos[2] - plane_z) * towards >= 0.0;
+
+            if crossed {
+                let span = state.pos[2] - previous.pos[2];
+                let fraction = if span == 0.0 { 1.0 } else { (plane_z - previous.pos[2]) / span };
+                let lerp = |a: [f32; 3], b: [f32; 3]| [0, 1, 2].map(|axis| a[axis] + (b[axis] - a[axis]) * fraction);
+
+                return Some(Intercept {
+                    t: (index as f32 + fraction) * self.dt,
+                    pos: lerp(previous.pos, state.pos),
+                    vel: lerp(previous.vel, state.vel),
+                });
+            }
+
+            previous = state;
+        }
+
+        None
+    }
+}
+
+pub fn table_contact(before: &BallState, after: &mut BallState, ball_radius: f32, table: &TableGeometry) -> Option<Contact> {
+    //Works out what the ball hit moving from before to after during a step, and changes after to bounce it off. The physics world uses this too.
+    //Checking the whole step means a fast ball can't pass through the net or the top between two steps.
+    if let Some(contact) = net_contact(before, after, ball_radius, table) {
+        return Some(contact);
+    }
+
+    //Coming down onto the top. A ball whose centre is just past the edge but still catches the rim is an edge ball, it bounces the same.
+    let was_above = before.pos[1] - ball_radius > table.surface_y;
+    let reached = after.pos[1] - ball_radius <= table.surface_y;
+    let outside = table.distance_outside(&after.pos);
+
+    if was_above && reached && after.vel[1] < 0.0 && outside <= ball_radius {
+        //The bounce flips (and loses some of) the vertical speed, then the table's grip turns slide into spin.
+        after.pos[1] = table.surface_y + ball_radius;
+        after.vel[1] = -after.vel[1] * table.restitution;
+
+        apply_grip(after, [0.0, 1.0, 0.0], [0.0; 3], ball_radius, table.grip);
+        return Some(Contact::Top { edge: outside > 0.0 });
+    }
+
+    //Coming in from the side below the top, it bounces back off whichever face it went through.
+    let below_top = after.pos[1] < table.surface_y && after.pos[1] + ball_radius > table.surface_y - TABLE_THICKNESS;
+    if below_top && table.distance_outside(&before.pos) > ball_radius && outside <= ball_radius {
+        let through_side = before.pos[0].abs() - table.half_width > before.pos[2].abs() - table.half_length;
+        let axis = if through_side { 0 } else { 2 };
+
+        after.pos[0] = before.pos[0];
+        after.pos[2] = before.pos[2];
+        after.vel[axis] = -after.vel[axis] * table.restitution;
+        return Some(Contact::Side);
+    }
+
+    None
+}
+
+fn net_contact(before: &BallState, after: &mut BallState, ball_radius: f32, table: &TableGeometry) -> Option<Contact> {
+    if table.net_height <= 0.0 || (before.pos[2] < 0.0) == (after.pos[2] < 0.0) {
+        return None;
+    }
+
+    //Where the centre crossed z = 0.
+    let fraction = before.pos[2] / (before.pos[2] - after.pos[2]);
+    let [x, y] = [0, 1].map(|axis| before.pos[axis] + (after.pos[axis] - before.pos[axis]) * fraction);
+    let net_top = table.surface_y + table.net_height;
+
+    let past_the_posts = x.abs() > table.half_width + NET_OVERHANG;
+    if past_the_posts || y - ball_radius >= net_top || y + ball_radius <= table.surface_y {
+        return None; //Cleared it, went round it or went under the table.
+    }
+
+    if y >= net_top {
+        //Centre over the cord, it rolls over losing pace and pops up a little.
+        after.vel[2] *= NET_CORD_PACE;
+        after.vel[1] = after.vel[1].abs() * NET_CORD_PACE;
+        return Some(Contact::NetCord);
+    }
+
+    //Into the net, it's left hanging just on the side it came from.
+    let from_side = if before.pos[2] < 0.0 { -1.0 } else { 1.0 };
+    after.pos = [x, y, from_side * ball_radius];
+    after.vel = [after.vel[0] * NET_RESTITUTION, after.vel[1], -after.vel[2] * NET_RESTITUTION];
+    Some(Contact::Net)
+}
+
+pub fn apply_grip(state: &mut BallState, normal: [f32; 3], surface_vel: [f32; 3], ball_radius: f32, grip: f32) {
+    //Friction from a surface the ball is touching (normal pointing at the ball) pulls it towards rolling over it,
+    //so backspin checks the ball and topspin kicks it on. Uses a thin hollow ball's inertia (2/3 m r²),
+    //a grip of 1 takes out all the sliding and 0 is a surface with no friction at all.
+    let dot = |a: [f32; 3], b: [f32; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+    let cross = |a: [f32; 3], b: [f32; 3]| [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]];
+
+    //How fast the bottom of the ball slides over the surface, ignoring any movement along the normal.
+    let relative = [0, 1, 2].map(|axis| state.vel[axis] - surface_vel[axis]);
+    let along = dot(relative, normal);
+    let spin_at_contact = cross(state.spin, normal.map(|n| -n * ball_radius));
+    let slip = [0, 1, 2].map(|axis| relative[axis] - along * normal[axis] + spin_at_contact[axis]);
+
+    let twist = cross(normal, slip);
+    for axis in 0..3 {
+        state.vel[axis] -= 0.4 * grip * slip[axis];
+        state.spin[axis] += 0.6 * grip * twist[axis] / ball_radius;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room_controller::room::physics_world::PhysicsWorld;
+    use crate::config::PhysicsConfig;
+
+    #[test]
+    fn prediction_follows_the_physics_world() {
+        //With gravity and nothing in the way, each predicted step is where the world puts the ball.
+        let settings = PhysicsConfig { gravity: [0.0, -1.5, 0.0], ..PhysicsConfig::default() };
+        let mut world = PhysicsWorld::with_settings(1, settings);
+        world.set_ball_state([0.2, 1.0, 0.0], [0.5, 1.0, 6.0]);
+
+        let start = world.ball_state().unwrap();
+        let trajectory = world.predict_ball(1.0).unwrap();
+
+        for predicted in &trajectory.states {
+            world.step();
+            let actual = world.ball_state().unwrap();
+
+            for axis in 0..3 {
+                assert!((predicted.pos[axis] - actual.pos[axis]).abs() < 1e-4, "{:?} vs {:?}", predicted, actual);
+            }
+        }
+
+        let intercept = trajectory.intercept(&start, 3.0).unwrap();
+        assert!((intercept.t - 0.5).abs() < 1e-3);
+        assert!((intercept.pos[2] - 3.0).abs() < 1e-4);
+        assert!(trajectory.intercept(&start, -3.0).is_none()); //Behind the ball.
+    }
+
+    #[test]
+    fn table_bounces_lose_height_and_take_spin() {
+        let table = TableGeometry { surface_y: 0.0, half_width: 1.5, half_length: 3.0, net_height: 0.0, restitution: 0.9, grip: 1.0 };
+        let dropped = |spin| BallState { pos: [0.0, 0.5, -1.0], vel: [0.0, 0.0, 4.0], spin };
+
+        let flat = Trajectory::predict(dropped([0.0; 3]), [0.0, -9.8, 0.0], 0.02, Some(&table), 0.5, 1.0 / 120.0);
+        assert_eq!(flat.bounces.len(), 1);
+        let after = flat.states.iter().find(|state| state.vel[1] > 0.0).unwrap();
+        assert!(after.vel[1] < 9.8 * 0.33); //Less than it fell with.
+
+        //Topspin (positive about x when heading to +z, the same as the ball machine's) comes off the table faster than no spin, backspin slower.
+        let speed_after = |spin| {
+            let trajectory = Trajectory::predict(dropped(spin), [0.0, -9.8, 0.0], 0.02, Some(&table), 0.5, 1.0 / 120.0);
+            trajectory.states.iter().find(|state| state.vel[1] > 0.0).unwrap().vel[2]
+        };
+        assert!(speed_after([500.0, 0.0, 0.0]) > after.vel[2]);
+        assert!(speed_after([-500.0, 0.0, 0.0]) < after.vel[2]);
+    }
+
+    #[test]
+    fn net_edge_and_side_contacts_are_told_apart() {
+        let table = TableGeometry { surface_y: 0.0, half_width: 1.5, half_length: 3.0, net_height: 0.2, restitution: 0.9, grip: 0.0 };
+        let contact = |pos: [f32; 3], vel: [f32; 3]| {
+            let before = BallState { pos, vel, spin: [0.0; 3] };
+            let mut after = BallState { pos: [0, 1, 2].map(|axis| pos[axis] + vel[axis] * 0.1), ..before };
+            (table_contact(&before, &mut after, 0.05, &table), after)
+        };
+
+        //Low through the net it's stopped on the side it came from, just over the cord it carries on slower.
+        let (hit, after) = contact([0.0, 0.1, -0.2], [0.0, 0.0, 4.0]);
+        assert_eq!(hit, Some(Contact::Net));
+        assert!(after.pos[2] < 0.0 && after.vel[2] < 0.0);
+
+        let (hit, after) = contact([0.0, 0.22, -0.2], [0.0, 0.0, 4.0]);
+        assert_eq!(hit, Some(Contact::NetCord));
+        assert!(after.pos[2] > 0.0 && after.vel[2] == 2.0);
+
+        assert_eq!(contact([0.0, 0.3, -0.2], [0.0, 0.0, 4.0]).0, None); //Clean over.
+
+        //Catching the rim from above is an edge ball, running into the end below the top is a side hit.
+        assert_eq!(contact([0.0, 0.2, 3.03], [0.0, -2.0, 0.0]).0, Some(Contact::Top { edge: true }));
+        assert_eq!(contact([0.0, 0.2, 2.0], [0.0, -2.0, 0.0]).0, Some(Contact::Top { edge: false }));
+
+        let (hit, after) = contact([0.0, -0.05, 3.3], [0.0, 0.0, -4.0]);
+        assert_eq!(hit, Some(Contact::Side));
+        assert!(after.vel[2] > 0.0);
+    }
+}
@@ -0,0 +1,242 @@
+//This is the rules file.
+//It keeps the score and decides who serves and who has to hit next, using the ball touches the physics world reports each step.
+//Seats alternate sides of the net, even seats are team 0 (positive z) and odd seats are team 1, so singles and doubles share one set of rules.
+//The serve passes to the next seat and each rally is hit in seat order from the server,
+//which in doubles gives the partners-alternate order of server, receiver, server's partner, receiver's partner.
+//Ball events from the table and net can end a rally too, or call a let when a serve clips the net.
+//Double bounces and volleys only come up in modes with gravity, without it a bounced ball keeps rising and the bats can't reach over the table.
+
+use serde::{Deserialize, Serialize};
+
+use super::physics_world::ball_events::BallEvent;
+
+pub const SERVES_PER_TURN: u32 = 2; //Serve passes on every 2 points, and every point once both teams reach deuce.
+
+pub fn team_of(seat: i32) -> usize {
+    (seat.rem_euclid(2)) as usize
+}
+
+//What a ball event means for the rally.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Call {
+    Point(usize), //Team that wins it.
+    Let, //Serve again, nobody scores.
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MatchRules {
+    pub seats: i32,
+    pub score: [u32; 2], //Points for team 0 and team 1.
+    pub server: i32,
+    pub hits: u32, //Touches so far this rally, the serve counts as the first.
+    pub still_ticks: u32, //Ticks the ball has sat nearly still, counted by the room.
+    #[serde(default)]
+    pub landed: bool, //The shot in play has bounced on the side that has to return it.
+    #[serde(default)]
+    pub started_at: u64, //Room tick the match started on, for timed modes.
+    pub winner: Option<usize>,
+}
+
+impl MatchRules {
+    pub fn new(seats: i32) -> Self {
+        MatchRules {
+            seats,
+            score: [0, 0],
+            server: 0,
+            hits: 0,
+            still_ticks: 0,
+            landed: false,
+            started_at: 0,
+            winner: None,
+        }
+    }
+
+    pub fn receiver(&self) -> i32 {
+        (self.server + 1) % self.seats
+    }
+
+    pub fn next_hitter(&self) -> i32 {
+        (self.server + self.hits as i32) % self.seats
+    }
+
+    fn last_hitter(&self) -> Option<i32> {
+        if self.hits == 0 {
+            return None;
+        }
+
+        Some((self.server + self.hits as i32 - 1) % self.seats)
+    }
+
+    pub fn serve(&mut self) {
+        self.hits = 1;
+        self.still_ticks = 0;
+        self.landed = false;
+    }
+
+    pub fn on_touch(&mut self, seat: i32) -> Option<usize> {
+        //Returns the team that wins the point if the touch was out of turn.
+        if self.winner.is_some() || self.hits == 0 {
+            return None;
+        }
+
+        if seat == self.next_hitter() {
+            self.hits += 1;
+            self.landed = false;
+            return None;
+        }
+
+        //The bat staying in contact (or brushing the ball again) isn't a new shot.
+        if Some(seat) == self.last_hitter() {
+            return None;
+        }
+
+        Some(1 - team_of(seat))
+    }
+
+    pub fn on_ball_not_returned(&mut self, side_team: usize, must_land: bool) -> Option<usize> {
+        //Ball got past every bat on a side (or died there), that team missed it.
+        //Unless shots have to land and it never bounced on their half, then it was hit long and the team that hit it loses.
+        if self.winner.is_some() || self.hits == 0 {
+            return None;
+        }
+
+        if must_land && side_team == team_of(self.next_hitter()) && !self.landed {
+            return Some(side_team);
+        }
+
+        Some(1 - side_team)
+    }
+
+    pub fn on_ball_out_wide(&mut self) -> Option<usize> {
+        //Ball left the play area sideways, the last shot was out.
+        if self.winner.is_some() {
+            return None;
+        }
+
+        self.last_hitter().map(|seat| 1 - team_of(seat))
+    }
+
+    pub fn on_ball_event(&mut self, event: &BallEvent) -> Option<Call> {
+        if self.winner.is_some() || self.hits == 0 {
+            return None;
+        }
+
+        let last_shot_lost = || self.last_hitter().map(|seat| Call::Point(1 - team_of(seat)));
+        match event {
+            //Bounces are good, edge balls included. Bounces without a bat between them are reported as a double bounce.
+            BallEvent::Bounce { side, .. } => {
+                self.landed |= *side == team_of(self.next_hitter());
+                None
+            }
+            //Clipping the net is play on, except on a serve where it's served again.
+            BallEvent::NetCord { .. } if self.hits == 1 => Some(Call::Let),
+            BallEvent::NetCord { .. } => None,
+            BallEvent::Net { .. } | BallEvent::SideHit { .. } => last_shot_lost(),
+            //Either the side the ball bounced twice on didn't get to it, or they volleyed it, both lose them the point.
+            BallEvent::DoubleBounce { side, .. } | BallEvent::Volley { side, .. } => Some(Call::Point(1 - side)),
+        }
+    }
+
+    pub fn award_point(&mut self, team: usize, match_length: u32) {
+        self.score[team] += 1;
+        self.hits = 0;
+
+        let lead = self.score[team].saturating_sub(self.score[1 - team]);
+        if self.score[team] >= match_length && lead >= 2 {
+            self.winner = Some(team);
+            return;
+        }
+
+        let deuce = self.score.iter().all(|points| *points + 1 >= match_length);
+        let total: u32 = self.score.iter().sum();
+        if deuce || total.is_multiple_of(SERVES_PER_TURN) {
+            self.server = (self.server + 1) % self.seats;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_partners_alternate_hits() {
+        let mut rules = MatchRules::new(4);
+        rules.serve();
+
+        //Server seat 0, then receiver 1, server's partner 2, receiver's partner 3, then round again.
+        for seat in [1, 2, 3, 0, 1] {
+            assert_eq!(rules.on_touch(seat), None);
+        }
+
+        //Seat 3 (team 1) cutting in ahead of seat 2 loses the point.
+        assert_eq!(rules.on_touch(3), Some(0));
+    }
+
+    #[test]
+    fn net_and_table_events_end_rallies() {
+        let pos = [0.0; 3];
+        let mut rules = MatchRules::new(2);
+        rules.serve(); //Seat 0 (team 0) serves.
+
+        assert_eq!(rules.on_ball_event(&BallEvent::NetCord { pos }), Some(Call::Let));
+        assert_eq!(rules.on_ball_event(&BallEvent::Bounce { side: 1, edge: true, pos }), None);
+
+        rules.on_touch(1);
+        assert_eq!(rules.on_ball_event(&BallEvent::NetCord { pos }), None); //Play on in a rally.
+        assert_eq!(rules.on_ball_event(&BallEvent::Net { pos }), Some(Call::Point(0))); //Seat 1 hit it into the net.
+        assert_eq!(rules.on_ball_event(&BallEvent::SideHit { side: 0, pos }), Some(Call::Point(0)));
+        assert_eq!(rules.on_ball_event(&BallEvent::DoubleBounce { side: 0, pos }), Some(Call::Point(1)));
+        assert_eq!(rules.on_ball_event(&BallEvent::Volley { side: 1, pos }), Some(Call::Point(0)));
+    }
+
+    #[test]
+    fn full_toss_past_the_end_goes_to_the_receiver() {
+        let pos = [0.0; 3];
+        let mut rules = MatchRules::new(2);
+        rules.serve(); //Seat 0 (team 0) serves, seat 1 (team 1) has to return it.
+
+        //Straight off the far end without touching team 1's half, the server hit it long.
+        assert_eq!(rules.on_ball_not_returned(1, true), Some(1));
+        assert_eq!(rules.on_ball_not_returned(1, false), Some(0)); //Without gravity nothing lands, so it's still a miss.
+
+        //Once it's bounced on their half, letting it past is team 1's miss.
+        rules.on_ball_event(&BallEvent::Bounce { side: 1, edge: false, pos });
+        assert_eq!(rules.on_ball_not_returned(1, true), Some(0));
+
+        //The return has to land on team 0's half again, a bounce left over from the serve doesn't count.
+        rules.on_touch(1);
+        assert_eq!(rules.on_ball_not_returned(0, true), Some(0));
+    }
+
+    #[test]
+    fn doubles_serve_rotates_through_all_four_seats() {
+        let mut rules = MatchRules::new(4);
+        let mut servers = Vec::new();
+
+        for _ in 0..8 {
+            servers.push((rules.server, rules.receiver()));
+            rules.award_point(0, 21);
+        }
+
+        //Receiver becomes server and the previous server's partner receives.
+        assert_eq!(servers, [(0, 1), (0, 1), (1, 2), (1, 2), (2, 3), (2, 3), (3, 0), (3, 0)]);
+    }
+
+    #[test]
+    fn deuce_needs_a_two_point_lead() {
+        let mut rules = MatchRules::new(2);
+        for _ in 0..10 {
+            rules.award_point(0, 11);
+            rules.award_point(1, 11);
+        }
+
+        let server = rules.server;
+        rules.award_point(0, 11);
+        assert_eq!(rules.winner, None);
+        assert_ne!(rules.server, server, "serve should change every point at deuce");
+
+        rules.award_point(0, 11);
+        assert_eq!(rules.winner, Some(0));
+    }
+}
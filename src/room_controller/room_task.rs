@@ -0,0 +1,419 @@
+//This is the room task file.
+//Every live room runs as it's own task, which owns the Room (and so it's physics world) and ticks it on it's own timer.
+//Nothing outside the task touches the room, everything goes through a RoomCommand sent down the room's channel, and requests that need an answer carry a oneshot to reply on.
+//Rooms tick in parallel on tokio's worker threads and a busy room never holds up the others, or the matchmaking in room_controller.rs.
+//Each task keeps the senders of it's members' connections, so state goes straight to them without touching the shared clients map.
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, watch};
+use tokio::time::{self, Duration, Instant};
+use tracing::{info, Instrument};
+use uuid::Uuid;
+
+use super::room::ball_machine::{Drill, MachineSettings};
+use super::room::mode::GameMode;
+use super::room::{tick_rate, Room};
+use crate::metrics;
+use crate::Player;
+
+pub type ClientSender = UnboundedSender<String>; //A connection's outgoing message queue.
+pub type Reply<T> = oneshot::Sender<T>;
+
+pub enum RoomCommand {
+    //Player input, dropped if the sender isn't a player in the room (spectators, or someone since kicked).
+    Move { player: Player, dx: f64, dy: f64, dz: f64, rot: Option<[f64; 4]> },
+    HitBegin { player_id: Uuid },
+    HitEnd { player_id: Uuid },
+
+    Join { player: Player, sender: ClientSender, reply: Reply<Result<i32, String>> },
+    Spectate { spectator: Player, sender: ClientSender, code: Option<String>, reply: Reply<Result<(), String>> }, //Private rooms need their invite code.
+    Leave { player_id: Uuid, reply: Reply<bool> }, //Replies whether the room should close, only ever after a player leaves.
+    SendUpdate, //Sends everyone in the room a room_update.
+
+    //Owner controls for private rooms, and the ball machine for practice rooms.
+    SetMatchLength { owner_id: Uuid, match_length: u32, reply: Reply<Result<(), String>> },
+    Kick { owner_id: Uuid, player_id: Uuid, reply: Reply<Result<(), String>> },
+    Start { owner_id: Uuid, reply: Reply<Result<(), String>> },
+    ConfigureMachine { player_id: Uuid, settings: MachineChanges, reply: Reply<Result<MachineSettings, String>> },
+    SetRubber { player_id: Uuid, rubber: String, reply: Reply<Result<(), String>> },
+
+    //Chat for players and spectators alike, sent on to everyone in the room who hasn't muted the sender.
+    Chat { player_id: Uuid, text: String, reply: Reply<Result<(), String>> },
+    Emote { player_id: Uuid, emote: String, reply: Reply<Result<(), String>> },
+    Mute { player_id: Uuid, target_id: Uuid, muted: bool, reply: Reply<Result<(), String>> },
+
+    //Admin controls, these reply with the room's summary.
+    Detail { reply: Reply<Value> },
+    SetPaused { paused: bool, reply: Reply<Value> },
+    ForceEnd { reply: Reply<Result<Value, String>> },
+
+    //Both stop the task. Close replies with everyone still inside, Shutdown ends any match first and saves the replay.
+    Close { reply: Reply<Vec<Uuid>> },
+    Shutdown { reply: Reply<()> },
+}
+
+//Ball machine settings a player asked to change, the rest stay as they are.
+#[derive(Default)]
+pub struct MachineChanges {
+    pub speed: Option<f32>,
+    pub spin: Option<[f32; 2]>,
+    pub interval_secs: Option<f32>,
+    pub drill: Option<Drill>,
+    pub aim_assist: Option<bool>,
+}
+
+//What the room controller needs to know about a room without asking it, republished by the task whenever it changes.
+#[derive(Debug, Clone)]
+pub struct RoomInfo {
+    pub room_type: String,
+    pub mode: &'static GameMode,
+    pub state: String,
+    pub pop: i32,
+    pub capacity: i32,
+    pub is_private: bool,
+    pub invite_code: Option<String>,
+    pub winner_ids: Vec<Uuid>, //Set once a match is won, tournaments take their results from this.
+    pub winner_team: Option<usize>, //So the score can be read from the winner's side.
+    pub summary: Value,
+}
+
+impl RoomInfo {
+    fn of(room: &Room) -> Self {
+        RoomInfo {
+            room_type: room.room_type.clone(),
+            mode: room.mode,
+            state: room.state.clone(),
+            pop: room.pop,
+            capacity: room.capacity,
+            is_private: room.is_private,
+            invite_code: room.invite_code.clone(),
+            winner_ids: if room.state == "Finished" { room.winner_ids.clone() } else { Vec::new() },
+            winner_team: room.rules.winner.filter(|_| room.state == "Finished"),
+            summary: room.summary(),
+        }
+    }
+}
+
+//The controller's side of a running room.
+#[derive(Clone)]
+pub struct RoomHandle {
+    pub id: Uuid,
+    pub commands: UnboundedSender<RoomCommand>,
+    pub info: watch::Receiver<RoomInfo>,
+}
+
+impl RoomHandle {
+    pub fn send(&self, command: RoomCommand) {
+        let _ = self.commands.send(command); //Only fails once the room has stopped, there's nobody left to tell.
+    }
+
+    pub async fn request<T>(&self, command: impl FnOnce(Reply<T>) -> RoomCommand) -> Result<T, String> {
+        let (reply, response) = oneshot::channel();
+        self.commands.send(command(reply)).map_err(|_| "Room closed".to_string())?;
+        response.await.map_err(|_| "Room closed".to_string())
+    }
+
+    pub fn info(&self) -> watch::Ref<'_, RoomInfo> {
+        self.info.borrow()
+    }
+}
+
+pub fn spawn(room: Room, members: HashMap<Uuid, ClientSender>) -> RoomHandle {
+    //Starts ticking the room, it's first tick is straight away.
+    let (commands, command_rx) = mpsc::unbounded_channel();
+    let (info_tx, info) = watch::channel(RoomInfo::of(&room));
+    let id = room.id;
+    let span = room.span.clone();
+
+    let task = RoomTask {
+        published: (room.state.clone(), room.rules.score, room.paused),
+        room,
+        members,
+        info_tx,
+    };
+    tokio::spawn(task.run(command_rx).instrument(span));
+
+    RoomHandle { id, commands, info }
+}
+
+struct RoomTask {
+    room: Room,
+    members: HashMap<Uuid, ClientSender>, //Players and spectators, bots have no connection.
+    info_tx: watch::Sender<RoomInfo>,
+    published: (String, [u32; 2], bool), //State, score and pause last published, checked after each tick so info is only rebuilt when it changes.
+}
+
+impl RoomTask {
+    async fn run(mut self, mut commands: UnboundedReceiver<RoomCommand>) {
+        let mut interval = time::interval(Duration::from_secs_f64(1.0 / tick_rate() as f64)); //This decides how often state is sent (~30fps by default), the simulation itself runs at a fixed rate.
+        let mut last_tick = Instant::now();
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    //Passing the real time since the last tick, the room turns this into fixed-size steps.
+                    let now = Instant::now();
+                    let elapsed = now.duration_since(last_tick);
+                    last_tick = now;
+
+                    self.tick(elapsed.as_secs_f32());
+                }
+                command = commands.recv() => match command {
+                    Some(command) => if !self.handle(command) { break; },
+                    None => break,
+                },
+            }
+        }
+    }
+
+    fn tick(&mut self, elapsed: f32) {
+        let timer = metrics::get().room_tick_seconds.start_timer();
+        self.room.advance(elapsed); //Stepping physics world in room, by as many fixed steps as the elapsed time covers.
+        timer.observe_duration();
+
+        let mut states = self.room.take_events(); //Points and results from this tick, then ball_state and player_state for this room.
+        states.extend(self.room.snapshot());
+        self.send_to_members(&states);
+
+        let current = (self.room.state.clone(), self.room.rules.score, self.room.paused);
+        if current != self.published {
+            self.publish();
+        }
+    }
+
+    fn handle(&mut self, command: RoomCommand) -> bool {
+        //Applies one command, returns false when the room should stop.
+        match command {
+            RoomCommand::Move { player, dx, dy, dz, rot } => {
+                if self.room.has_player(player.id) {
+                    self.room.player_move(player, dx, dy, dz, rot);
+                }
+                return true; //Input doesn't change anything the controller sees.
+            }
+            RoomCommand::HitBegin { player_id } => {
+                if self.room.has_player(player_id) {
+                    self.room.player_hit(player_id);
+                }
+                return true;
+            }
+            RoomCommand::HitEnd { player_id } => {
+                if self.room.has_player(player_id) {
+                    self.room.player_hit_exec(player_id);
+                }
+                return true;
+            }
+
+            RoomCommand::Join { player, sender, reply } => {
+                let result = if self.room.state != "Not Started" {
+                    Err("Match has already started".to_string())
+                } else if self.room.pop >= self.room.capacity {
+                    Err("Room is full".to_string())
+                } else if !self.room.entrants.is_empty() && !self.room.entrants.contains(&player.id) {
+                    Err("This room is kept for a tournament match".to_string())
+                } else {
+                    self.members.insert(player.id, sender);
+                    Ok(self.room.add_player(player))
+                };
+
+                self.publish(); //Before replying, so matchmaking sees the new player straight away.
+                let _ = reply.send(result);
+            }
+            RoomCommand::Spectate { spectator, sender, code, reply } => {
+                let spectator_id = spectator.id;
+                let result = self.room.add_spectator(spectator, code.as_deref());
+                if result.is_ok() {
+                    self.members.insert(spectator_id, sender);
+                }
+
+                self.publish();
+                let _ = reply.send(result);
+            }
+            RoomCommand::Leave { player_id, reply } => {
+                let was_spectator = self.room.remove_spectator(player_id);
+                let was_player = !was_spectator && self.room.remove_player(player_id);
+                if was_spectator || was_player {
+                    self.members.remove(&player_id);
+                }
+
+                //A fixture that's been played waits for the tournament to take it's result, it closes the room itself.
+                let awaiting_result = !self.room.entrants.is_empty() && self.room.state == "Finished";
+
+                self.publish();
+                let _ = reply.send(was_player && !self.room.has_humans() && !awaiting_result);
+            }
+            RoomCommand::SendUpdate => {
+                let update_msg = serde_json::json!({
+                    "type": "room_update",
+                    "room": self.room.summary(),
+                });
+                self.send_to_members(&[update_msg]);
+                return true;
+            }
+
+            RoomCommand::SetMatchLength { owner_id, match_length, reply } => {
+                let result = self.room.check_owner(owner_id).map(|()| self.room.set_match_length(match_length));
+                self.publish();
+                let _ = reply.send(result);
+            }
+            RoomCommand::Kick { owner_id, player_id, reply } => {
+                let _ = reply.send(self.kick(owner_id, player_id));
+                self.publish();
+            }
+            RoomCommand::Start { owner_id, reply } => {
+                let _ = reply.send(self.start(owner_id));
+                self.publish();
+            }
+            RoomCommand::ConfigureMachine { player_id, settings, reply } => {
+                let _ = reply.send(self.configure_ball_machine(player_id, settings));
+                return true;
+            }
+            RoomCommand::SetRubber { player_id, rubber, reply } => {
+                let _ = reply.send(self.room.set_rubber(player_id, &rubber));
+                return true;
+            }
+
+            RoomCommand::Chat { player_id, text, reply } => {
+                let result = self.room.chat(player_id, &text).map(|message| self.send_chat(player_id, &message));
+                let _ = reply.send(result);
+                return true;
+            }
+            RoomCommand::Emote { player_id, emote, reply } => {
+                let result = self.room.emote(player_id, &emote).map(|message| self.send_chat(player_id, &message));
+                let _ = reply.send(result);
+                return true;
+            }
+            RoomCommand::Mute { player_id, target_id, muted, reply } => {
+                let _ = reply.send(self.room.mute(player_id, target_id, muted));
+                return true;
+            }
+
+            RoomCommand::Detail { reply } => {
+                let _ = reply.send(serde_json::json!({
+                    "room": self.room.summary(),
+                    "tick": self.room.tick_count,
+                    "seed": self.room.seed,
+                    "rules": self.room.rules,
+                    "ball_machine": self.room.ball_machine,
+                    "physics": self.room.snapshot(),
+                }));
+                return true;
+            }
+            RoomCommand::SetPaused { paused, reply } => {
+                self.room.set_paused(paused);
+                self.publish();
+                let _ = reply.send(self.room.summary());
+            }
+            RoomCommand::ForceEnd { reply } => {
+                let result = self.room.force_end().map(|()| self.room.summary());
+                self.publish();
+                let _ = reply.send(result);
+            }
+
+            RoomCommand::Close { reply } => {
+                info!("Room closed");
+                let _ = reply.send(self.room.member_ids());
+                return false;
+            }
+            RoomCommand::Shutdown { reply } => {
+                //The match_over (if there was a match to end) goes out now, the task stops before it's next tick.
+                let _ = self.room.force_end();
+                let written = self.room.finish_recording();
+
+                let events = self.room.take_events();
+                self.send_to_members(&events);
+
+                self.publish();
+
+                //Only replying once the replay is on disk, the server exits after every room has.
+                tokio::spawn(async move {
+                    if let Some(written) = written {
+                        let _ = written.await;
+                    }
+                    let _ = reply.send(());
+                });
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn kick(&mut self, owner_id: Uuid, player_id: Uuid) -> Result<(), String> {
+        self.room.check_owner(owner_id)?;
+
+        if owner_id == player_id {
+            return Err("You can't kick yourself".to_string());
+        }
+
+        if !self.room.remove_player(player_id) && !self.room.remove_spectator(player_id) {
+            return Err("Player is not in this room".to_string());
+        }
+
+        self.members.remove(&player_id);
+        Ok(())
+    }
+
+    fn start(&mut self, owner_id: Uuid) -> Result<(), String> {
+        self.room.check_owner(owner_id)?;
+
+        if self.room.pop < self.room.capacity {
+            return Err("Waiting for players".to_string());
+        }
+
+        self.room.start_room();
+        Ok(())
+    }
+
+    fn configure_ball_machine(&mut self, player_id: Uuid, changes: MachineChanges) -> Result<MachineSettings, String> {
+        if !self.room.has_player(player_id) {
+            return Err("Not in a room".to_string());
+        }
+
+        let mut settings = self.room.ball_machine.as_ref().ok_or("Only practice rooms have a ball machine")?.settings.clone();
+
+        if let Some(speed) = changes.speed { settings.speed = speed; }
+        if let Some(spin) = changes.spin { settings.spin = spin; }
+        if let Some(interval_secs) = changes.interval_secs { settings.interval_secs = interval_secs; }
+        if let Some(drill) = changes.drill { settings.drill = drill; }
+        if let Some(aim_assist) = changes.aim_assist { settings.aim_assist = aim_assist; }
+
+        self.room.configure_ball_machine(settings.clone())?;
+        Ok(settings)
+    }
+
+    fn publish(&mut self) {
+        self.published = (self.room.state.clone(), self.room.rules.score, self.room.paused);
+        self.info_tx.send_replace(RoomInfo::of(&self.room));
+    }
+
+    fn send_chat(&self, sender_id: Uuid, message: &Value) {
+        //Like send_to_members, but skipping anyone who has muted the sender.
+        let message = message.to_string();
+
+        for member_id in self.room.member_ids() {
+            if self.room.chat.has_muted(member_id, sender_id) {
+                continue;
+            }
+
+            if let Some(sender) = self.members.get(&member_id) {
+                let _ = sender.send(message.clone());
+            }
+        }
+    }
+
+    fn send_to_members(&self, messages: &[Value]) {
+        //In seat order then spectators, the same for every tick.
+        let messages: Vec<String> = messages.iter().map(Value::to_string).collect();
+
+        for member_id in self.room.member_ids() {
+            if let Some(sender) = self.members.get(&member_id) {
+                for message in &messages {
+                    let _ = sender.send(message.clone());
+                }
+            }
+        }
+    }
+}
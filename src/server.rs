@@ -0,0 +1,991 @@
+//This is the server file.
+//It's the networking side of the game, an axum app serving WebSocket connections for players plus /metrics, /tournaments and the /admin API.
+//Each connection is handled by handle_socket, which turns player messages into room controller calls and room commands.
+//main.rs only loads the config and calls run, tools and tests can build the same app with app().
+
+use axum::{
+    extract::{ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade}, ConnectInfo, Query},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::time::{self, Duration};
+use uuid::Uuid;
+use std::sync::Arc;
+use tokio::sync::{watch, Mutex};
+
+use futures::{sink::SinkExt, stream::StreamExt};
+use serde::Deserialize;
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+
+pub mod admin;
+use admin::AdminState;
+
+pub mod shutdown;
+
+pub mod social;
+use social::{Requested, SocialGraph};
+
+pub mod tournaments;
+use tournaments::TournamentList;
+
+use crate::config::{self, Config};
+use crate::logging::NETWORK_TARGET;
+use crate::metrics;
+use crate::player::Player;
+use crate::player_messages::PlayerMessage;
+use crate::replay::{self, ReplayCommand, ReplayPlayer};
+use crate::room_controller::{JoinedRoom, RoomController};
+use crate::room_controller::room_task::{MachineChanges, RoomCommand};
+use crate::room_controller::room::{tick_rate, DEFAULT_ROOM_TYPE};
+use crate::room_controller::room::mode::DEFAULT_MODE;
+use crate::room_controller::room::physics_world::bat;
+use crate::room_controller::room::chat;
+
+//A connected socket. Room state and replies for it go through the sender.
+pub struct Client {
+    pub sender: UnboundedSender<String>,
+    pub addr: SocketAddr,
+    pub close_tx: watch::Sender<Option<CloseFrame>>, //Set to drop the connection, when an admin kicks the player or the server shuts down.
+}
+
+impl Client {
+    pub fn close(&self, code: u16, reason: &str) {
+        //Closes the connection with this code, after any messages already queued for it.
+        let _ = self.close_tx.send(Some(CloseFrame { code, reason: reason.into() }));
+    }
+}
+
+pub type ClientMap = Arc<Mutex<HashMap<Uuid, Client>>>;
+pub type BanList = Arc<Mutex<HashSet<IpAddr>>>; //Addresses banned by an admin, they can't connect until the server restarts or the ban is lifted.
+
+//Everything connections share, every handler works on the same rooms and clients.
+#[derive(Clone)]
+pub struct AppState {
+    pub room_controller: Arc<Mutex<RoomController>>,
+    pub clients: ClientMap,
+    pub bans: BanList,
+    pub social: SocialGraph, //Display names, friends and ratings, see social.rs.
+    pub tournaments: TournamentList, //See tournaments.rs, run_tournaments has to be running for fixtures to be played.
+    pub tx: broadcast::Sender<String>, //Server-wide messages, each connection subscribes to it.
+}
+
+//Query string of /ws, a session token from an earlier init message reconnects as the same player.
+#[derive(Debug, Deserialize)]
+pub struct ConnectParams {
+    pub session: Option<String>,
+}
+
+//Builds the server's routes, the admin API is only there when a token is given (see admin.rs).
+pub fn app(state: AppState, admin_token: Option<&str>) -> Router {
+    let AppState { room_controller, clients, bans, social, tournaments, tx } = state;
+
+    let mut app = Router::new().route("/ws", get({
+        let tx = tx.clone(); // clone here
+        let room_controller = room_controller.clone();
+        let clients = clients.clone();
+        let bans = bans.clone();
+        let social = social.clone();
+        let tournaments = tournaments.clone();
+
+        move |ws: WebSocketUpgrade, ConnectInfo(addr): ConnectInfo<SocketAddr>, Query(params): Query<ConnectParams>| {
+            let tx = tx.clone(); // clone again here if needed
+            let rx = tx.subscribe();
+            let room_controller_ws = room_controller.clone();
+            let clients_ws = clients.clone();
+            let bans = bans.clone();
+            let social = social.clone();
+            let tournaments = tournaments.clone();
+
+            async move {
+                if bans.lock().await.contains(&addr.ip()) {
+                    info!(target: NETWORK_TARGET, %addr, "Refused connection from banned address");
+                    return StatusCode::FORBIDDEN.into_response();
+                }
+
+                if room_controller_ws.lock().await.draining {
+                    return StatusCode::SERVICE_UNAVAILABLE.into_response(); //Shutting down, see shutdown.rs.
+                }
+
+                //An unknown (or forgotten) session just gets a new player.
+                let resumed = match params.session {
+                    Some(session) => social.lock().await.resume(&session),
+                    None => None,
+                };
+
+                if let Some(player) = &resumed
+                    && clients_ws.lock().await.contains_key(&player.id) {
+                        return StatusCode::CONFLICT.into_response(); //That player is still connected.
+                }
+
+                ws.on_upgrade(move |socket| {
+                    //Everything logged while handling this connection carries it's player id (and room id once they join one).
+                    let player_data = resumed.unwrap_or_else(Player::new);
+                    let span = info_span!(target: NETWORK_TARGET, "connection", player_id = %player_data.id, room_id = field::Empty);
+
+                    handle_socket(socket, player_data, addr, room_controller_ws, clients_ws, social, tournaments, tx, rx).instrument(span)
+                })
+            }
+        }
+    }))
+    .route("/metrics", get(metrics_handler)) //Prometheus scrapes this for server load, see metrics.rs.
+    .merge(tournaments::router(tournaments.clone()));
+
+    if let Some(token) = admin_token {
+        app = app.merge(admin::router(AdminState {
+            room_controller,
+            clients,
+            bans,
+            social,
+            tournaments,
+            tx,
+            token: token.into(),
+        }));
+    }
+
+    app
+}
+
+
+//Runs the server until it's shut down (see shutdown.rs), with the config already loaded and logging set up.
+pub async fn run(config: &'static Config) {
+
+    //'::' is a path seperator, used to access items (functions,structs,traits,constants etc)
+    //within modules,types or namespaces. Very similar to the dot access in js/py.
+    // It is used on types to call something on the type itself, not an instance.
+    //In modules (above), it is used to access items such as structs at the path (e.g. std -> sync -> Arc)
+    //Dot access (.) is used for instance-level method calls. 
+
+    // Create the room controller, each room owns it's own physics world.
+     let room_controller = Arc::new(Mutex::new(RoomController::new()));
+     let clients: ClientMap = Arc::new(Mutex::new(HashMap::new()));
+     let bans: BanList = Arc::new(Mutex::new(HashSet::new()));
+     let social: SocialGraph = Arc::new(Mutex::new(social::Social::new()));
+     let tournaments: TournamentList = Arc::new(Mutex::new(Vec::new()));
+     // Code explained:
+     //RoomController::new() == struct constructor for a new instance of the room controller.
+     //Mutex::new == ensures only one thread can modify or read the rooms at any time.
+     // This is important as it is a shared mutable (editable) environment.
+     //Arc::new == is a thread-safe reference counter. Means that it can allow multiple threads or
+     //async tasks to share ownership of the same data. Without arc, data could not pass between 
+     //threads as Rust's ownership model prevents moving data across threads.
+     //More on ARC. ARC means Atomic Reference Counter. It has a pointer on the stack that points to the heap value and also an atomic integer. This atomic integer
+     // counts every instance of the variable across threads, when it hits 0, it is removed. This allows us to work across threads
+     // as every thread sees the instantaneous value of the atomic integer (thus it can't be interfered incorrectly). Allowing multiple threads
+     // to use the ARC variable.
+     //MUTEX is a mutual exclusion primitive that allows only a singular thread to access the wrapped data at a time.
+     //The clients map holds a sender for every connected socket, so room state can be sent only to the players (and spectators) in that room.
+
+
+     //TL:DR, Rust prevents threads touching same data. Arc allows multiple to touch same data
+     //Mutex ensures only 1 at a time. room controller is a readable/editable list of rooms.
+
+    // Create a router with the WebSocket endpoint
+    let (tx,_rx) = broadcast::channel(config.network.broadcast_capacity); //creating channel
+    //This essential means that the channel has a cap of broadcast_capacity (16 by default) messages, when more come the oldest are dropped.
+    //This is a trade off for performance and memory. Too low and less updates. Too high and too much memory.
+    //The broadcast channel is now only used for server-wide messages (such as player removal), room state goes through the clients map.
+
+    let state = AppState {
+        room_controller: room_controller.clone(),
+        clients: clients.clone(),
+        bans: bans.clone(),
+        social: social.clone(),
+        tournaments: tournaments.clone(),
+        tx: tx.clone(),
+    };
+
+    if config.admin.token.is_none() {
+        info!("Admin API disabled, set admin.token to turn it on");
+    }
+
+    let app = app(state, config.admin.token.as_deref());
+
+     //This creates a new axum router and adds a new route "/ws"
+     //the room controller is cloned so that this closure gets its own copy of Arc
+     //necessary as the closure is move which takes ownership of the vars it uses, but we want
+     //to keep the og room_controller alive elsewhere too.
+     //Async move upgrades the http connection to ws.
+     //move socket is an async closure that gets called with the new websocket connection.
+     //inside this, we call handle_socket, passing it the socket and the cloned room controller.
+
+     //closure is like a function that sees it's surrounding scope, so in this case, async move, can see
+     //the room controller because it is a closure. (this being the move ws: websocket).
+
+     //In this context, get is a func that takes a closure/func, defining how to respond to GET requests.
+     //move tells Rust to move ownership of any used var into the closure.
+
+    // Run the server
+    // let addr = "127.0.0.1:3000".parse().unwrap();
+    let addr = config.network.bind;
+     //IP is localhost using port 3000 by default, set network.bind (or --bind) to change it.
+     // For LAN, use "0.0.0.0:3000" as this is local network.
+     // Other devices can join using local IP address like: 192.168.x.x:3000
+
+     //For public hosting:
+     // Change to 0 as well and ensure port 3000 is open in server firewall.
+
+     //Is the axum server being launched.
+     // .parse() parses string into a socket address
+     //axum::Server::bind, binds the server to the specific IP and port.
+     //.serve(app.into_make_service()) , app is the router, this converts the router into a
+     //format the server can understand for handling incoming http requests.
+     //.await tells rust to wait asynchronously for the server to run.
+     //It doesn't block the thread in synchronous code - other async tasks can still run.
+     //.unwrap() , if something goes wrong, this will panic and print the error.
+
+    //Each room ticks itself in it's own task (see room_task.rs), this just keeps the room gauges up to date.
+    let room_controller_metrics = room_controller.clone();
+
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(1));
+
+        loop {
+            interval.tick().await;
+            let rooms = room_controller_metrics.lock().await.room_infos();
+            metrics::get().update_rooms(&rooms);
+        }
+    });
+
+    //Friends are told when each other come online, go offline or start playing.
+    tokio::spawn(social::watch_presence(social.clone(), room_controller.clone(), clients.clone()));
+
+    //Makes rooms for tournament fixtures and takes their results.
+    tokio::spawn(tournaments::run_tournaments(tournaments.clone(), room_controller.clone(), clients.clone(), social.clone()));
+
+
+    //On ctrl-c or SIGTERM, matches are given time to finish and everyone is disconnected cleanly before the server stops.
+    let server_handle = axum_server::Handle::new();
+
+    tokio::spawn({
+        let server_handle = server_handle.clone();
+        let room_controller = room_controller.clone();
+        let clients = clients.clone();
+        let tx = tx.clone();
+
+        async move {
+            shutdown::signal().await;
+            shutdown::drain(&room_controller, &clients, &tx, Duration::from_secs(config.network.shutdown_grace_secs)).await;
+            server_handle.graceful_shutdown(Some(shutdown::CLOSE_TIMEOUT));
+        }
+    });
+
+    //Server is at bottom because it blocks the main() func from completing as .await and .serve are active until shutdown.
+     info!(target: NETWORK_TARGET, %addr, "Running WebSocket server on ws://{}", addr);
+    axum_server::bind(addr)
+        .handle(server_handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>()) //Connection addresses are needed for bans.
+        .await
+        .unwrap();
+
+    info!("Server stopped");
+}
+
+
+
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_socket(mut socket: WebSocket, mut player_data: Player, addr: SocketAddr, room_controller: Arc<Mutex<RoomController>>, clients: ClientMap, social: SocialGraph, tournaments: TournamentList, tx:broadcast::Sender<String>, mut rx:broadcast::Receiver<String>) {
+
+    //The new player's id was made when the connection was upgraded, so it's span could carry it.
+     let player_id = player_data.id;
+
+    //Registering this connection so room state can be sent directly to it.
+    let (client_tx, mut client_rx) = mpsc::unbounded_channel::<String>();
+    let (close_tx, mut close_rx) = watch::channel(None);
+    {
+        let mut clients = clients.lock().await;
+
+        //Checked again under the same lock as the insert, two connections resuming one session at once both get past the check before the upgrade.
+        if clients.contains_key(&player_id) {
+            drop(clients);
+            info!(target: NETWORK_TARGET, %addr, "Refused a second connection for a session already in use");
+            let _ = socket.send(Message::Close(Some(CloseFrame { code: close_code::POLICY, reason: "Already connected".into() }))).await;
+            return;
+        }
+
+        clients.insert(player_id, Client { sender: client_tx.clone(), addr, close_tx });
+    }
+
+     let metrics = metrics::get();
+     metrics.connected_sockets.inc();
+
+    let (session, rating) = {
+        let mut social = social.lock().await;
+        (social.connect(&player_data), social.rating(player_id))
+    };
+
+    info!(target: NETWORK_TARGET, %addr, "Player connected");
+
+    let (mut sender, mut receiver) = socket.split();
+
+
+    //Sending initial init message when user first connects to server.
+    //The player index is only known once they join a room (see "room_joined").
+    let welcome_msg = serde_json::json!({
+            "type": "init", //sending message type for init.
+            "player_id": player_id.to_string(), //sending player_id.
+            "display_name": player_data.display_name,
+            "session": session, //Connect with /ws?session=<this> to come back as the same player.
+            "rating": rating, //Tournaments seed by this.
+            "player_index": -1, //returning player index to set order.
+            "rubbers": config::get().materials.rubbers.keys().collect::<Vec<_>>(), //Bat rubbers the player can pick with set_rubber.
+            "emotes": chat::EMOTES,
+    });
+    metrics.count_message_out(&welcome_msg.to_string());
+    let _ = sender.send(Message::Text(welcome_msg.to_string().into())).await;
+
+    //The room this connection plays in (if any), input goes straight to it without locking the room controller.
+    let mut room_commands: Option<UnboundedSender<RoomCommand>> = None;
+
+    //Control channel for the replay this connection is watching (if any), dropping it stops the replay.
+    let mut replay_control: Option<mpsc::UnboundedSender<ReplayCommand>> = None;
+
+    //This function creates a background async task that listens for messages on both channels and sends them over a websocket connect.
+    //rx is for server-wide messages, client_rx is for messages meant only for this connection (room state, replies).
+
+    //When the server closes the connection the writer sends the close frame, the reader below stops listening.
+    let mut writer_close_rx = close_rx.clone();
+
+    let writer = tokio::spawn(async move {
+        let close_frame = loop {
+            let message = tokio::select! {
+                biased; //Messages already queued for this connection (like why it's being closed) go out before the close frame.
+
+                client_msg = client_rx.recv() => match client_msg {
+                    Some(message) => message,
+                    None => break None,
+                },
+                server_msg = rx.recv() => match server_msg {
+                    Ok(message) => message,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => { //Missed some messages, carry on with the newest.
+                        metrics.broadcast_lagged.inc_by(missed);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break None,
+                },
+                Ok(()) = writer_close_rx.changed() => break writer_close_rx.borrow().clone(),
+            };
+
+            metrics.count_message_out(&message);
+
+            //The following code converts the message into a websocket and attempts to send.
+            if sender.send(Message::Text(message.into())).await.is_err() {
+                break None;
+            }
+        };
+
+        //Closing with the server's code and reason if it has one, otherwise a plain close frame.
+        let _ = match close_frame {
+            Some(frame) => sender.send(Message::Close(Some(frame))).await,
+            None => sender.close().await,
+        };
+    }.in_current_span());
+
+    
+
+    //Why the connection ended, for the disconnect metrics. A stream that just stops is a dropped connection.
+    let mut disconnect_reason = "dropped";
+
+    loop {
+        let result = tokio::select! {
+            result = receiver.next() => result,
+            Ok(()) = close_rx.changed() => {
+                let shutdown = close_rx.borrow().as_ref().is_some_and(|frame| frame.code == close_code::AWAY);
+                disconnect_reason = if shutdown { "shutdown" } else { "kicked" };
+
+                //Letting the writer get the close frame out before this connection is forgotten.
+                let _ = time::timeout(shutdown::CLOSE_TIMEOUT, writer).await;
+                break;
+            }
+        };
+
+        let Some(result) = result else {
+            break;
+        };
+
+        let Ok(msg) = result else {
+            disconnect_reason = "error";
+            break;
+        };
+
+        match msg {
+            Message::Text(text) => {
+
+                //println!("Received message: {}", text);
+
+                //This area is where we handle player messages.
+                //Here we will control the movement of the player bodies/colliders (their phys objects.)
+                //Chat and emotes go through the room too, see chat.rs.
+                let parsed = serde_json::from_str::<PlayerMessage>(&text);
+                metrics.messages_in.with_label_values(&[parsed.as_ref().map_or("invalid", PlayerMessage::kind)]).inc();
+
+                match parsed {
+                    Ok(PlayerMessage::JoinRoom { bot, code, room_type, mode }) => {
+                        //This runs when player clicks "join room" or "play" button
+                        let mut room_control = room_controller.lock().await;
+
+                        if room_control.room_of(player_id).is_some() {
+                            let _ = client_tx.send(error_msg("Already in a room"));
+                            continue;
+                        }
+
+                        let room_type = room_type.unwrap_or_else(|| DEFAULT_ROOM_TYPE.to_string());
+                        let mode = mode.unwrap_or_else(|| DEFAULT_MODE.to_string());
+                        let sender = client_tx.clone();
+
+                        let joined = match (bot, code) {
+                            (Some(_), Some(_)) => Err("Choose either a bot match or an invite code".to_string()),
+                            (Some(_), None) if room_type != DEFAULT_ROOM_TYPE => Err("Bot matches are singles only".to_string()),
+                            (Some(choice), None) => match choice.difficulty() {
+                                Ok(difficulty) => room_control.create_bot_room(player_data.clone(), sender, difficulty, &mode).await,
+                                Err(reason) => Err(reason),
+                            },
+                            (None, Some(code)) => room_control.join_room_by_code(player_data.clone(), sender, &code).await,
+                            (None, None) => room_control.add_player_to_room(player_data.clone(), sender, &room_type, &mode).await,
+                        };
+
+                        match joined {
+                            Ok(JoinedRoom { room_id, player_index, commands }) => {
+                                Span::current().record("room_id", field::display(room_id));
+                                info!(target: NETWORK_TARGET, player_index, "Joined room");
+                                room_commands = Some(commands);
+
+                                let joined_msg = serde_json::json!({
+                                    "type": "room_joined",
+                                    "room_id": room_id.to_string(),
+                                    "player_index": player_index,
+                                });
+                                let _ = client_tx.send(joined_msg.to_string());
+
+                                room_control.send_room_update(player_id);
+                            }
+                            Err(reason) => {
+                                let _ = client_tx.send(error_msg(&reason));
+                            }
+                        }
+                    }
+
+                    Ok(PlayerMessage::CreatePrivateRoom { match_length, room_type, mode }) => {
+                        let mut room_control = room_controller.lock().await;
+
+                        if room_control.room_of(player_id).is_some() {
+                            let _ = client_tx.send(error_msg("Already in a room"));
+                            continue;
+                        }
+
+                        let room_type = room_type.unwrap_or_else(|| DEFAULT_ROOM_TYPE.to_string());
+                        let mode = mode.unwrap_or_else(|| DEFAULT_MODE.to_string());
+
+                        match room_control.create_private_room(player_data.clone(), client_tx.clone(), &room_type, &mode, match_length).await {
+                            Ok((JoinedRoom { room_id, player_index, commands }, invite_code)) => {
+                                Span::current().record("room_id", field::display(room_id));
+                                info!(target: NETWORK_TARGET, player_index, "Created private room");
+                                room_commands = Some(commands);
+
+                                let created_msg = serde_json::json!({
+                                    "type": "private_room_created",
+                                    "room_id": room_id.to_string(),
+                                    "code": invite_code,
+                                    "player_index": player_index,
+                                });
+                                let _ = client_tx.send(created_msg.to_string());
+                            }
+                            Err(reason) => {
+                                let _ = client_tx.send(error_msg(&reason));
+                            }
+                        }
+                    }
+
+                    Ok(PlayerMessage::RoomSettings { match_length }) => {
+                        let mut room_control = room_controller.lock().await;
+
+                        match room_control.set_match_length(player_id, match_length).await {
+                            Ok(()) => room_control.send_room_update(player_id),
+                            Err(reason) => {
+                                let _ = client_tx.send(error_msg(&reason));
+                            }
+                        }
+                    }
+
+                    Ok(PlayerMessage::Kick { player_id: kicked_id }) => {
+                        let mut room_control = room_controller.lock().await;
+
+                        match room_control.kick_player(player_id, kicked_id).await {
+                            Ok(()) => {
+                                let kicked_msg = serde_json::json!({ "type": "kicked" }).to_string();
+                                send_to_clients(&clients, &[kicked_id], &kicked_msg).await;
+
+                                room_control.send_room_update(player_id);
+                            }
+                            Err(reason) => {
+                                let _ = client_tx.send(error_msg(&reason));
+                            }
+                        }
+                    }
+
+                    Ok(PlayerMessage::StartMatch {}) => {
+                        let mut room_control = room_controller.lock().await;
+
+                        match room_control.start_private_room(player_id).await {
+                            Ok(()) => room_control.send_room_update(player_id),
+                            Err(reason) => {
+                                let _ = client_tx.send(error_msg(&reason));
+                            }
+                        }
+                    }
+
+                    Ok(PlayerMessage::BallMachine { speed, spin, interval_secs, drill, aim_assist }) => {
+                        let mut room_control = room_controller.lock().await;
+
+                        let changes = MachineChanges { speed, spin, interval_secs, drill, aim_assist };
+
+                        match room_control.configure_ball_machine(player_id, changes).await {
+                            Ok(settings) => {
+                                let machine_msg = serde_json::json!({
+                                    "type": "ball_machine",
+                                    "settings": settings,
+                                });
+                                let _ = client_tx.send(machine_msg.to_string());
+                            }
+                            Err(reason) => {
+                                let _ = client_tx.send(error_msg(&reason));
+                            }
+                        }
+                    }
+
+                    Ok(PlayerMessage::SetRubber { rubber }) => {
+                        let mut room_control = room_controller.lock().await;
+
+                        match room_control.set_rubber(player_id, rubber.clone()).await {
+                            Ok(()) => {
+                                let rubber_msg = serde_json::json!({
+                                    "type": "rubber_set",
+                                    "rubber": rubber,
+                                });
+                                let _ = client_tx.send(rubber_msg.to_string());
+                            }
+                            Err(reason) => {
+                                let _ = client_tx.send(error_msg(&reason));
+                            }
+                        }
+                    }
+
+                    Ok(PlayerMessage::Chat { text }) => {
+                        //The room sends the message to everyone in it (this connection included), only a refusal comes back here.
+                        let mut room_control = room_controller.lock().await;
+
+                        if let Err(reason) = room_control.chat(player_id, text).await {
+                            let _ = client_tx.send(error_msg(&reason));
+                        }
+                    }
+
+                    Ok(PlayerMessage::Emote { emote }) => {
+                        let mut room_control = room_controller.lock().await;
+
+                        if let Err(reason) = room_control.emote(player_id, emote).await {
+                            let _ = client_tx.send(error_msg(&reason));
+                        }
+                    }
+
+                    Ok(PlayerMessage::Mute { player_id: target_id, muted }) => {
+                        let mut room_control = room_controller.lock().await;
+                        let muted = muted.unwrap_or(true);
+
+                        match room_control.mute(player_id, target_id, muted).await {
+                            Ok(()) => {
+                                let mute_msg = serde_json::json!({
+                                    "type": "muted",
+                                    "player_id": target_id.to_string(),
+                                    "muted": muted,
+                                });
+                                let _ = client_tx.send(mute_msg.to_string());
+                            }
+                            Err(reason) => {
+                                let _ = client_tx.send(error_msg(&reason));
+                            }
+                        }
+                    }
+
+                    Ok(PlayerMessage::SetName { display_name }) => {
+                        //Shown to friends straight away, rooms see it from the next one joined.
+                        let named = social.lock().await.set_name(player_id, &display_name);
+
+                        match named {
+                            Ok(display_name) => {
+                                player_data.display_name = display_name.clone();
+
+                                let name_msg = serde_json::json!({
+                                    "type": "name_set",
+                                    "display_name": display_name,
+                                });
+                                let _ = client_tx.send(name_msg.to_string());
+                            }
+                            Err(reason) => {
+                                let _ = client_tx.send(error_msg(&reason));
+                            }
+                        }
+                    }
+
+                    Ok(PlayerMessage::FriendRequest { player }) => {
+                        let requested = {
+                            let mut social = social.lock().await;
+                            social.find(&player).and_then(|friend_id| {
+                                social.request(player_id, friend_id).map(|requested| (friend_id, requested, social.name(friend_id)))
+                            })
+                        };
+
+                        match requested {
+                            Ok((friend_id, Requested::Sent, friend_name)) => {
+                                let sent_msg = serde_json::json!({
+                                    "type": "friend_request_sent",
+                                    "player_id": friend_id.to_string(),
+                                    "display_name": friend_name,
+                                });
+                                let _ = client_tx.send(sent_msg.to_string());
+
+                                let request_msg = serde_json::json!({
+                                    "type": "friend_request",
+                                    "player_id": player_id.to_string(),
+                                    "display_name": player_data.display_name,
+                                });
+                                send_to_clients(&clients, &[friend_id], &request_msg.to_string()).await;
+                            }
+                            Ok((friend_id, Requested::Accepted, _)) => {
+                                social::announce_friends(&social, &room_controller, &clients, player_id, friend_id).await;
+                            }
+                            Err(reason) => {
+                                let _ = client_tx.send(error_msg(&reason));
+                            }
+                        }
+                    }
+
+                    Ok(PlayerMessage::AcceptFriend { player }) => {
+                        let accepted = {
+                            let mut social = social.lock().await;
+                            social.find(&player).and_then(|friend_id| social.accept(player_id, friend_id).map(|()| friend_id))
+                        };
+
+                        match accepted {
+                            Ok(friend_id) => social::announce_friends(&social, &room_controller, &clients, player_id, friend_id).await,
+                            Err(reason) => {
+                                let _ = client_tx.send(error_msg(&reason));
+                            }
+                        }
+                    }
+
+                    Ok(PlayerMessage::RemoveFriend { player }) => {
+                        //Unfriending, turning down a request and taking one back are all this, both sides are told.
+                        let removed = {
+                            let mut social = social.lock().await;
+                            social.find(&player).and_then(|friend_id| social.remove(player_id, friend_id).map(|()| friend_id))
+                        };
+
+                        match removed {
+                            Ok(friend_id) => {
+                                for (to, other) in [(player_id, friend_id), (friend_id, player_id)] {
+                                    let removed_msg = serde_json::json!({
+                                        "type": "friend_removed",
+                                        "player_id": other.to_string(),
+                                    });
+                                    send_to_clients(&clients, &[to], &removed_msg.to_string()).await;
+                                }
+                            }
+                            Err(reason) => {
+                                let _ = client_tx.send(error_msg(&reason));
+                            }
+                        }
+                    }
+
+                    Ok(PlayerMessage::ListFriends {}) => {
+                        let ids: Vec<Uuid> = match social.lock().await.profiles.get(&player_id) {
+                            Some(profile) => profile.friends.iter().chain(&profile.requests).chain(&profile.sent).copied().collect(),
+                            None => Vec::new(),
+                        };
+                        let statuses = social::statuses(&ids, &room_controller, &clients).await;
+
+                        let list_msg = social.lock().await.friend_list(player_id, &statuses);
+                        let _ = client_tx.send(list_msg.to_string());
+                    }
+
+                    Ok(PlayerMessage::InviteFriend { player }) => {
+                        //The friend is sent the room's code and joins with join_room like anyone given it.
+                        let friend = {
+                            let social = social.lock().await;
+                            social.find(&player).and_then(|friend_id| {
+                                if social.are_friends(player_id, friend_id) { Ok(friend_id) } else { Err("Not friends with that player".to_string()) }
+                            })
+                        };
+
+                        let invite = match friend {
+                            Ok(friend_id) => {
+                                let room_control = room_controller.lock().await;
+
+                                let room = room_control.room_of(player_id).ok_or("Not in a room".to_string()).and_then(|handle| {
+                                    let info = handle.info();
+                                    if !info.is_private {
+                                        Err("Only private rooms take invites".to_string())
+                                    } else if info.state != "Not Started" || info.pop >= info.capacity {
+                                        Err("Room is full or has started".to_string())
+                                    } else {
+                                        Ok((handle.id, info.invite_code.clone()))
+                                    }
+                                });
+
+                                match room {
+                                    Ok((room_id, invite_code)) => match clients.lock().await.get(&friend_id) {
+                                        None => Err("Friend is offline".to_string()),
+                                        Some(client) => {
+                                            let invite_msg = serde_json::json!({
+                                                "type": "room_invite",
+                                                "player_id": player_id.to_string(),
+                                                "display_name": player_data.display_name,
+                                                "room_id": room_id.to_string(),
+                                                "code": invite_code,
+                                            });
+                                            let _ = client.sender.send(invite_msg.to_string());
+                                            Ok(friend_id)
+                                        }
+                                    },
+                                    Err(reason) => Err(reason),
+                                }
+                            }
+                            Err(reason) => Err(reason),
+                        };
+
+                        match invite {
+                            Ok(friend_id) => {
+                                let sent_msg = serde_json::json!({
+                                    "type": "invite_sent",
+                                    "player_id": friend_id.to_string(),
+                                });
+                                let _ = client_tx.send(sent_msg.to_string());
+                            }
+                            Err(reason) => {
+                                let _ = client_tx.send(error_msg(&reason));
+                            }
+                        }
+                    }
+
+                    Ok(PlayerMessage::ListTournaments {}) => {
+                        let list_msg = serde_json::json!({
+                            "type": "tournament_list",
+                            "tournaments": tournaments.lock().await.iter().map(|tournament| tournament.summary()).collect::<Vec<_>>(),
+                        });
+                        let _ = client_tx.send(list_msg.to_string());
+                    }
+
+                    Ok(PlayerMessage::TournamentSignUp { tournament_id }) => {
+                        //Every entrant (this one included) is sent the new bracket, only a refusal comes back here.
+                        if let Err(reason) = tournaments::sign_up(&tournaments, &social, &clients, player_id, tournament_id).await {
+                            let _ = client_tx.send(error_msg(&reason));
+                        }
+                    }
+
+                    Ok(PlayerMessage::TournamentWithdraw { tournament_id }) => {
+                        if let Err(reason) = tournaments::withdraw(&tournaments, &social, &clients, player_id, tournament_id).await {
+                            let _ = client_tx.send(error_msg(&reason));
+                        }
+                    }
+
+                    Ok(PlayerMessage::Move { dx, dy, dz, rot }) => {
+                        if let Some(reason) = rot.and_then(|rot| bat::check_rotation(rot).err()) {
+                            let _ = client_tx.send(error_msg(&reason));
+                        }
+
+                        if let Some(commands) = &room_commands {
+                            let _ = commands.send(RoomCommand::Move { player: player_data.clone(), dx, dy, dz, rot });
+                        }
+                    }
+
+                    Ok(PlayerMessage::HitBegin {}) => {
+                        //Triggering hit receive function.
+                        if let Some(commands) = &room_commands {
+                            let _ = commands.send(RoomCommand::HitBegin { player_id });
+                        }
+                        debug!(target: NETWORK_TARGET, "Received Hit Start");
+                    }
+
+                    Ok(PlayerMessage::HitEnd {}) => {
+                        if let Some(commands) = &room_commands {
+                            let _ = commands.send(RoomCommand::HitEnd { player_id });
+                        }
+                        debug!(target: NETWORK_TARGET, "Received Hit Finish");
+                    }
+
+                    Ok(PlayerMessage::Spectate { room_id, code }) => {
+                        //Attaching connection to a room as a read-only spectator.
+                        let mut room_control = room_controller.lock().await;
+
+                        match room_control.add_spectator_to_room(room_id, player_data.clone(), client_tx.clone(), code).await {
+                            Ok(()) => {
+                                Span::current().record("room_id", field::display(room_id));
+                                info!(target: NETWORK_TARGET, "Spectating room");
+
+                                let spectate_msg = serde_json::json!({
+                                    "type": "spectating",
+                                    "room_id": room_id.to_string(),
+                                });
+                                let _ = client_tx.send(spectate_msg.to_string());
+                            }
+                            Err(reason) => {
+                                let _ = client_tx.send(error_msg(&reason));
+                            }
+                        }
+                    }
+
+                    Ok(PlayerMessage::ListRooms {}) => {
+                        let room_control = room_controller.lock().await;
+
+                        let list_msg = serde_json::json!({
+                            "type": "room_list",
+                            "rooms": room_control.live_rooms(),
+                        });
+                        let _ = client_tx.send(list_msg.to_string());
+                    }
+
+                    Ok(PlayerMessage::ListReplays {}) => {
+                        let list_msg = serde_json::json!({
+                            "type": "replay_list",
+                            "replays": replay::list_replays(),
+                        });
+                        let _ = client_tx.send(list_msg.to_string());
+                    }
+
+                    Ok(PlayerMessage::WatchReplay { replay_id, speed }) => {
+                        //Streams a recorded match to this connection only, using the same snapshot messages as live play.
+                        let speed = speed.unwrap_or(1.0);
+
+                        if !replay::is_valid_speed(speed) {
+                            let _ = client_tx.send(error_msg("Replay speed must be 0.5, 1 or 2"));
+                            continue;
+                        }
+
+                        if room_controller.lock().await.room_of(player_id).is_some() {
+                            let _ = client_tx.send(error_msg("Leave the room before watching a replay"));
+                            continue;
+                        }
+
+                        match ReplayPlayer::load(replay_id).await {
+                            Ok(player) => {
+                                let (control_tx, control_rx) = mpsc::unbounded_channel();
+                                replay_control = Some(control_tx); //Replaces (and so stops) any replay already playing.
+
+                                let start_msg = serde_json::json!({
+                                    "type": "replay_start",
+                                    "replay_id": replay_id.to_string(),
+                                    "end_tick": player.end_tick(),
+                                    "tick_rate": tick_rate(),
+                                });
+                                let _ = client_tx.send(start_msg.to_string());
+
+                                info!(target: NETWORK_TARGET, %replay_id, speed, "Watching replay");
+                                tokio::spawn(replay::stream_replay(player, client_tx.clone(), control_rx, speed).in_current_span());
+                            }
+                            Err(reason) => {
+                                let _ = client_tx.send(error_msg(&reason));
+                            }
+                        }
+                    }
+
+                    Ok(PlayerMessage::ReplayControl { speed, seek, paused }) => {
+                        let Some(control_tx) = replay_control.as_ref() else {
+                            let _ = client_tx.send(error_msg("No replay playing"));
+                            continue;
+                        };
+
+                        if let Some(speed) = speed {
+                            if replay::is_valid_speed(speed) {
+                                let _ = control_tx.send(ReplayCommand::Speed(speed));
+                            } else {
+                                let _ = client_tx.send(error_msg("Replay speed must be 0.5, 1 or 2"));
+                            }
+                        }
+
+                        if let Some(seek) = seek {
+                            let tick = (seek.max(0.0) * tick_rate() as f64) as u64;
+                            let _ = control_tx.send(ReplayCommand::Seek(tick));
+                        }
+
+                        if let Some(paused) = paused {
+                            let _ = control_tx.send(ReplayCommand::Pause(paused));
+                        }
+                    }
+
+                    Ok(PlayerMessage::StopReplay {}) => {
+                        replay_control = None;
+                    }
+
+                    Ok(PlayerMessage::None) => {}
+
+                    Err(e) => {
+                        warn!(target: NETWORK_TARGET, error = %e, "Failed to parse message");
+                    }
+                }
+                
+            }
+            Message::Close(_) => {
+                disconnect_reason = "closed";
+                break;
+            }
+
+            _ => {} //This takes care of all unneeded message types such as Ping and Pong (used to ignore them).
+        }
+    }
+
+    // Handle closing the WebSocket connection (runs for clean closes and dropped connections).
+    clients.lock().await.remove(&player_id);
+    social.lock().await.disconnect(player_id);
+    metrics.connected_sockets.dec();
+    metrics.disconnects.with_label_values(&[disconnect_reason]).inc();
+    info!(target: NETWORK_TARGET, reason = disconnect_reason, "Connection closed");
+
+    let orphaned = {
+        let mut room_control = room_controller.lock().await; //Waiting for thread to gain access to rooms.
+        room_control.remove_player(player_id).await //Calls remove_player logic, removing them from their room and it's physics world.
+    };
+
+    //Telling any spectators left in a deleted room that it has closed.
+    let closed_msg = serde_json::json!({ "type": "room_closed" }).to_string();
+    send_to_clients(&clients, &orphaned, &closed_msg).await;
+
+    //Sending information to remove player when they disconnect.
+    let removal_msg = serde_json::json!({
+        "type": "remove",
+        "player_id": player_id.to_string(),
+    });
+
+    // Send to all clients via broadcast channel
+    if let Err(e) = tx.send(removal_msg.to_string()) {
+        warn!(target: NETWORK_TARGET, error = %e, "Broadcast error on player remove");
+    }
+}
+
+//Sends a message to each of the given connections that is still connected.
+async fn send_to_clients(clients: &ClientMap, client_ids: &[Uuid], message: &str) {
+    let clients = clients.lock().await;
+
+    for client_id in client_ids {
+        if let Some(client) = clients.get(client_id) {
+            let _ = client.sender.send(message.to_string());
+        }
+    }
+}
+
+//Serves the current metrics in Prometheus' text format.
+async fn metrics_handler() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], metrics::get().render())
+}
+
+//Builds the message sent back to a client when their request can't be completed.
+fn error_msg(reason: &str) -> String {
+    serde_json::json!({
+        "type": "error",
+        "reason": reason,
+    }).to_string()
+}
@@ -0,0 +1,370 @@
+//This is the admin file.
+//It's the HTTP API operators use to look into and step in on the server, mounted under /admin when admin.token is set.
+//Every request needs an "Authorization: Bearer <token>" header. Replies are JSON, failures are {"error": reason} with a matching status.
+//Kicks drop the player's connection (which also takes them out of their room), bans are by IP address and only kept in memory.
+//Tournaments are created, started and (when a player doesn't turn up) settled here, see tournaments.rs for the rest.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use axum::{
+    extract::{ws::close_code, Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, Mutex};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::room_controller::room::mode::{GameMode, DEFAULT_MODE};
+use crate::room_controller::room_task::{RoomCommand, RoomHandle};
+use crate::room_controller::{check_match_length, RoomController};
+use crate::tournament::{Format, Tournament, MAX_ENTRANTS};
+use super::social::SocialGraph;
+use super::tournaments::{self, TournamentList};
+use super::{send_to_clients, BanList, ClientMap};
+
+pub const MAX_ANNOUNCEMENT_LENGTH: usize = 500;
+
+#[derive(Clone)]
+pub struct AdminState {
+    pub room_controller: Arc<Mutex<RoomController>>,
+    pub clients: ClientMap,
+    pub bans: BanList,
+    pub social: SocialGraph,
+    pub tournaments: TournamentList,
+    pub tx: broadcast::Sender<String>, //Server-wide channel every connection listens on, used for announcements.
+    pub token: Arc<str>,
+}
+
+pub struct AdminError(StatusCode, String);
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+type AdminResult = Result<Json<Value>, AdminError>;
+
+#[derive(Deserialize)]
+pub struct Announcement {
+    pub message: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewTournament {
+    pub name: String,
+    pub format: Format, //"single_elimination" or "round_robin".
+    pub mode: Option<String>, //Any mode but practice, defaults to classic.
+    pub match_length: Option<u32>, //Defaults to the mode's.
+    pub max_entrants: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct FixtureWinner {
+    pub player_id: Uuid,
+}
+
+pub fn router(state: AdminState) -> Router {
+    Router::new()
+        .route("/admin/rooms", get(list_rooms))
+        .route("/admin/rooms/{room_id}", get(room_detail).delete(close_room))
+        .route("/admin/rooms/{room_id}/end", post(end_match))
+        .route("/admin/rooms/{room_id}/pause", post(pause_room))
+        .route("/admin/rooms/{room_id}/resume", post(resume_room))
+        .route("/admin/players", get(list_players))
+        .route("/admin/players/{player_id}/kick", post(kick_player))
+        .route("/admin/players/{player_id}/ban", post(ban_player))
+        .route("/admin/bans", get(list_bans))
+        .route("/admin/bans/{ip}", delete(lift_ban))
+        .route("/admin/announce", post(announce))
+        .route("/admin/tournaments", post(create_tournament))
+        .route("/admin/tournaments/{tournament_id}/start", post(start_tournament))
+        .route("/admin/tournaments/{tournament_id}/fixtures/{fixture_id}/winner", post(settle_fixture))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state)
+}
+
+fn tokens_match(given: &str, expected: &str) -> bool {
+    //Looks at every byte whatever the result, so the time taken doesn't give away how much of the token was right.
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+async fn require_token(State(state): State<AdminState>, request: Request, next: Next) -> Response {
+    let given = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match given {
+        Some(token) if tokens_match(token, &state.token) => next.run(request).await,
+        _ => {
+            warn!(path = %request.uri().path(), "Admin request with a missing or wrong token");
+            AdminError(StatusCode::UNAUTHORIZED, "Missing or wrong admin token".to_string()).into_response()
+        }
+    }
+}
+
+fn room_not_found() -> AdminError {
+    AdminError(StatusCode::NOT_FOUND, "Room not found".to_string())
+}
+
+async fn list_rooms(State(state): State<AdminState>) -> AdminResult {
+    //Every room, private and unstarted ones included.
+    let room_control = state.room_controller.lock().await;
+    let rooms: Vec<Value> = room_control.rooms_list.iter().map(|handle| handle.info().summary.clone()).collect();
+
+    Ok(Json(json!({ "rooms": rooms })))
+}
+
+async fn find_room(state: &AdminState, room_id: Uuid) -> Result<RoomHandle, AdminError> {
+    //The room's handle is cloned so it can be asked things without holding up the room controller.
+    state.room_controller.lock().await.find_room_by_id(room_id).cloned().ok_or_else(room_not_found)
+}
+
+fn room_stopped(reason: String) -> AdminError {
+    //The room closed between being found and answering.
+    AdminError(StatusCode::NOT_FOUND, reason)
+}
+
+async fn room_detail(State(state): State<AdminState>, Path(room_id): Path<Uuid>) -> AdminResult {
+    //The room's summary plus it's live physics state, in the same form players receive it.
+    let room = find_room(&state, room_id).await?;
+    let detail = room.request(|reply| RoomCommand::Detail { reply }).await.map_err(room_stopped)?;
+
+    Ok(Json(detail))
+}
+
+async fn close_room(State(state): State<AdminState>, Path(room_id): Path<Uuid>) -> AdminResult {
+    //Deletes the room, everyone in it is told and can join another.
+    let mut room_control = state.room_controller.lock().await;
+    if room_control.find_room_by_id(room_id).is_none() {
+        return Err(room_not_found());
+    }
+
+    let members = room_control.delete_room(room_id).await;
+    drop(room_control);
+
+    let closed_msg = json!({ "type": "room_closed", "reason": "Closed by an admin" }).to_string();
+    send_to_clients(&state.clients, &members, &closed_msg).await;
+
+    info!(%room_id, "Admin closed room");
+    Ok(Json(json!({ "closed": room_id, "members": members })))
+}
+
+async fn end_match(State(state): State<AdminState>, Path(room_id): Path<Uuid>) -> AdminResult {
+    let room = find_room(&state, room_id).await?;
+    let summary = room.request(|reply| RoomCommand::ForceEnd { reply }).await
+        .map_err(room_stopped)?
+        .map_err(|reason| AdminError(StatusCode::CONFLICT, reason))?;
+
+    info!(%room_id, "Admin ended match");
+    Ok(Json(json!({ "room": summary })))
+}
+
+async fn set_paused(state: AdminState, room_id: Uuid, paused: bool) -> AdminResult {
+    let room = find_room(&state, room_id).await?;
+    let summary = room.request(|reply| RoomCommand::SetPaused { paused, reply }).await.map_err(room_stopped)?;
+
+    Ok(Json(json!({ "room": summary })))
+}
+
+async fn pause_room(State(state): State<AdminState>, Path(room_id): Path<Uuid>) -> AdminResult {
+    set_paused(state, room_id, true).await
+}
+
+async fn resume_room(State(state): State<AdminState>, Path(room_id): Path<Uuid>) -> AdminResult {
+    set_paused(state, room_id, false).await
+}
+
+async fn list_players(State(state): State<AdminState>) -> AdminResult {
+    //Every connection, with the room they're in (as a player or spectator) if any.
+    //Rooms are locked before clients, the same order as everywhere else.
+    let room_control = state.room_controller.lock().await;
+    let clients = state.clients.lock().await;
+
+    let players: Vec<Value> = clients.iter().map(|(player_id, client)| json!({
+        "player_id": player_id,
+        "addr": client.addr.to_string(),
+        "room_id": room_control.members.get(player_id),
+    })).collect();
+
+    Ok(Json(json!({ "players": players })))
+}
+
+async fn disconnect(state: &AdminState, player_ids: &[Uuid], reason: &str) {
+    //Tells each player why, then drops their connection. Leaving their room is handled when the connection closes.
+    let clients = state.clients.lock().await;
+    let kicked_msg = json!({ "type": "kicked", "reason": reason }).to_string();
+
+    for player_id in player_ids {
+        if let Some(client) = clients.get(player_id) {
+            let _ = client.sender.send(kicked_msg.clone());
+            client.close(close_code::POLICY, reason);
+        }
+    }
+}
+
+async fn kick_player(State(state): State<AdminState>, Path(player_id): Path<Uuid>) -> AdminResult {
+    if !state.clients.lock().await.contains_key(&player_id) {
+        return Err(AdminError(StatusCode::NOT_FOUND, "Player not connected".to_string()));
+    }
+
+    disconnect(&state, &[player_id], "Kicked by an admin").await;
+
+    info!(%player_id, "Admin kicked player");
+    Ok(Json(json!({ "kicked": [player_id] })))
+}
+
+async fn ban_player(State(state): State<AdminState>, Path(player_id): Path<Uuid>) -> AdminResult {
+    //Bans the player's address, every connection from it is dropped, not just this player's.
+    let (ip, kicked) = {
+        let clients = state.clients.lock().await;
+        let client = clients.get(&player_id).ok_or_else(|| AdminError(StatusCode::NOT_FOUND, "Player not connected".to_string()))?;
+        let ip = client.addr.ip();
+
+        let kicked: Vec<Uuid> = clients.iter()
+            .filter(|(_, other)| other.addr.ip() == ip)
+            .map(|(id, _)| *id)
+            .collect();
+
+        (ip, kicked)
+    };
+
+    state.bans.lock().await.insert(ip);
+    disconnect(&state, &kicked, "Banned by an admin").await;
+
+    info!(%player_id, %ip, "Admin banned player");
+    Ok(Json(json!({ "banned": ip.to_string(), "kicked": kicked })))
+}
+
+async fn list_bans(State(state): State<AdminState>) -> AdminResult {
+    let bans: Vec<String> = state.bans.lock().await.iter().map(|ip| ip.to_string()).collect();
+    Ok(Json(json!({ "bans": bans })))
+}
+
+async fn lift_ban(State(state): State<AdminState>, Path(ip): Path<IpAddr>) -> AdminResult {
+    if !state.bans.lock().await.remove(&ip) {
+        return Err(AdminError(StatusCode::NOT_FOUND, "Address is not banned".to_string()));
+    }
+
+    info!(%ip, "Admin lifted ban");
+    Ok(Json(json!({ "unbanned": ip.to_string() })))
+}
+
+async fn announce(State(state): State<AdminState>, Json(announcement): Json<Announcement>) -> AdminResult {
+    //Sent to every connected client, in a room or not.
+    let message = announcement.message.trim();
+
+    if message.is_empty() || message.len() > MAX_ANNOUNCEMENT_LENGTH {
+        return Err(AdminError(StatusCode::BAD_REQUEST, format!("Announcements must be 1 to {} characters", MAX_ANNOUNCEMENT_LENGTH)));
+    }
+
+    let announcement_msg = json!({ "type": "announcement", "message": message }).to_string();
+    let _ = state.tx.send(announcement_msg); //Only fails when nobody is connected.
+    let recipients = state.clients.lock().await.len();
+
+    info!(recipients, "Admin sent announcement");
+    Ok(Json(json!({ "recipients": recipients })))
+}
+
+async fn create_tournament(State(state): State<AdminState>, Json(new): Json<NewTournament>) -> AdminResult {
+    //Opens a tournament for sign-ups, it's started with /start once everyone is in.
+    let bad_request = |reason: String| AdminError(StatusCode::BAD_REQUEST, reason);
+
+    let mode = new.mode.unwrap_or_else(|| DEFAULT_MODE.to_string());
+    if GameMode::preset(&mode).map_err(bad_request)?.practice {
+        return Err(bad_request("Practice can't be played in a tournament".to_string()));
+    }
+
+    if let Some(match_length) = new.match_length {
+        check_match_length(match_length).map_err(bad_request)?;
+    }
+
+    let tournament = Tournament::new(new.name, new.format, mode, new.match_length, new.max_entrants.unwrap_or(MAX_ENTRANTS)).map_err(bad_request)?;
+    let bracket = tournament.bracket();
+
+    info!(tournament_id = %tournament.id, name = %tournament.name, "Admin created tournament");
+    state.tournaments.lock().await.push(tournament);
+    Ok(Json(json!({ "tournament": bracket })))
+}
+
+async fn start_tournament(State(state): State<AdminState>, Path(tournament_id): Path<Uuid>) -> AdminResult {
+    //Seeds the entrants and draws the bracket, fixture rooms are made from then on.
+    let mut tournaments = state.tournaments.lock().await;
+    let tournament = tournaments::find(&mut tournaments, tournament_id).map_err(|reason| AdminError(StatusCode::NOT_FOUND, reason))?;
+    tournament.start().map_err(|reason| AdminError(StatusCode::CONFLICT, reason))?;
+
+    info!(%tournament_id, entrants = tournament.entrants.len(), "Admin started tournament");
+    tournaments::push_update(&state.clients, tournament).await;
+    tournaments::release_entrants(&state.social, tournament).await; //Can already be over if there was only a bye to play.
+    Ok(Json(json!({ "tournament": tournament.bracket() })))
+}
+
+async fn settle_fixture(State(state): State<AdminState>, Path((tournament_id, fixture_id)): Path<(Uuid, usize)>, Json(winner): Json<FixtureWinner>) -> AdminResult {
+    //Gives a fixture to a player without it being played, for walkovers. Ratings aren't changed and any room made for it is closed.
+    let mut tournaments = state.tournaments.lock().await;
+    let tournament = tournaments::find(&mut tournaments, tournament_id).map_err(|reason| AdminError(StatusCode::NOT_FOUND, reason))?;
+    let room_id = tournament.fixtures.get(fixture_id).and_then(|fixture| fixture.room_id);
+
+    tournament.record_result(fixture_id, winner.player_id, None).map_err(|reason| AdminError(StatusCode::CONFLICT, reason))?;
+
+    if let Some(room_id) = room_id {
+        tournaments::close_fixture_room(&mut *state.room_controller.lock().await, &state.clients, room_id).await;
+    }
+
+    info!(%tournament_id, fixture_id, winner = %winner.player_id, "Admin settled fixture");
+    tournaments::push_update(&state.clients, tournament).await;
+    tournaments::release_entrants(&state.social, tournament).await;
+    Ok(Json(json!({ "tournament": tournament.bracket() })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::social::Social;
+    use axum::body::{to_bytes, Body};
+    use std::collections::{HashMap, HashSet};
+    use tower::Service;
+
+    fn test_router() -> Router {
+        router(AdminState {
+            room_controller: Arc::new(Mutex::new(RoomController::new())),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            bans: Arc::new(Mutex::new(HashSet::new())),
+            social: Arc::new(Mutex::new(Social::new())),
+            tournaments: Arc::new(Mutex::new(Vec::new())),
+            tx: broadcast::channel(4).0,
+            token: "0123456789abcdef".into(),
+        })
+    }
+
+    async fn get_status(mut router: Router, auth: Option<&str>) -> (StatusCode, Value) {
+        let mut request = Request::builder().uri("/admin/rooms");
+        if let Some(auth) = auth {
+            request = request.header(header::AUTHORIZATION, auth);
+        }
+
+        let response = router.call(request.body(Body::empty()).unwrap()).await.unwrap(); //Routers are always ready, no need to poll first.
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn admin_routes_need_the_token() {
+        assert_eq!(get_status(test_router(), None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(get_status(test_router(), Some("Bearer 0123456789abcdeX")).await.0, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = get_status(test_router(), Some("Bearer 0123456789abcdef")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["rooms"], json!([]));
+    }
+}
@@ -0,0 +1,93 @@
+//This is the shutdown file.
+//On ctrl-c or SIGTERM the server drains instead of dropping every match mid-rally.
+//New connections and room joins are refused, everyone is told a shutdown is coming and matches in progress get until the grace period runs out to finish.
+//Anything still going then is ended where it stands, every replay is saved and each socket is closed with the "going away" code.
+//A second signal skips the rest of the wait.
+
+use std::sync::Arc;
+
+use axum::extract::ws::close_code;
+use tokio::sync::{broadcast, Mutex};
+use tokio::time::{self, Duration, Instant};
+use tracing::{info, warn};
+
+use crate::room_controller::RoomController;
+use super::ClientMap;
+
+pub const DRAIN_POLL: Duration = Duration::from_millis(500); //How often to check whether the last match has finished.
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(5); //How long sockets get to close once told to before the server stops anyway.
+
+pub async fn signal() {
+    //Waits for ctrl-c, or SIGTERM (what container runtimes and systemd send) on unix.
+    let ctrl_c = tokio::signal::ctrl_c();
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => { terminate.recv().await; }
+            Err(_) => std::future::pending().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+pub async fn drain(room_controller: &Arc<Mutex<RoomController>>, clients: &ClientMap, tx: &broadcast::Sender<String>, grace: Duration) {
+    let matches = {
+        let mut room_control = room_controller.lock().await;
+        room_control.draining = true;
+        room_control.matches_in_progress()
+    };
+
+    info!(grace_secs = grace.as_secs(), matches, "Shutting down, waiting for matches to finish");
+
+    let shutdown_msg = serde_json::json!({
+        "type": "server_shutdown",
+        "grace_secs": grace.as_secs(), //Matches still going after this long are ended.
+    });
+    let _ = tx.send(shutdown_msg.to_string());
+
+    let matches_finished = async {
+        while room_controller.lock().await.matches_in_progress() > 0 {
+            time::sleep(DRAIN_POLL).await;
+        }
+    };
+
+    tokio::select! {
+        result = time::timeout_at(Instant::now() + grace, matches_finished) => {
+            if result.is_err() {
+                warn!("Grace period over, ending the matches still in progress");
+            }
+        }
+        _ = signal() => warn!("Second shutdown signal, not waiting for matches to finish"),
+    }
+
+    //Each room tells it's members how their match ended and stops, so nothing is sent to them after this.
+    room_controller.lock().await.shutdown_rooms().await;
+
+    {
+        let clients = clients.lock().await;
+        info!(connections = clients.len(), "Closing connections");
+
+        for client in clients.values() {
+            client.close(close_code::AWAY, "Server shutting down");
+        }
+    }
+
+    //Each connection removes itself once it's close frame is sent, the server stops when they're all gone (or they've had long enough).
+    let connections_closed = async {
+        while !clients.lock().await.is_empty() {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+    };
+
+    if time::timeout(CLOSE_TIMEOUT, connections_closed).await.is_err() {
+        warn!("Some connections didn't close in time");
+    }
+}
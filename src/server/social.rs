@@ -0,0 +1,407 @@
+//This is the social file.
+//It keeps every player's display name and friends, and pushes friends' presence (offline, online or in a match) to each other.
+//Friend requests can be sent by player id or display name, a request to someone who already asked you accepts theirs.
+//Each connection gets a session token in it's init message, reconnecting with /ws?session=<token> comes back as the same player,
+//so friends survive a dropped connection. It's all kept in memory, players with no friends, requests, rating or tournaments are forgotten when they disconnect.
+//Ratings live here too since they follow the player rather than a connection, tournament results move them (see tournament.rs).
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use tokio::time::{self, Duration};
+use uuid::Uuid;
+
+use crate::room_controller::RoomController;
+use crate::tournament::{self, DEFAULT_RATING};
+use crate::Player;
+use super::{Client, ClientMap};
+
+pub const MIN_NAME_LENGTH: usize = 3;
+pub const MAX_NAME_LENGTH: usize = 20;
+pub const PRESENCE_INTERVAL: Duration = Duration::from_secs(1); //How often presence is checked for changes.
+
+pub type SocialGraph = Arc<Mutex<Social>>;
+
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub display_name: String,
+    pub session: String,
+    pub named: bool, //Picked a name with set_name, only those can be found by name.
+    pub rating: i32,
+    pub rated: bool, //Has played a rated match, so has a rating worth keeping.
+    pub tournaments: u32, //Tournaments they're signed up to and not yet finished.
+    pub friends: BTreeSet<Uuid>,
+    pub requests: BTreeSet<Uuid>, //Friend requests waiting on this player.
+    pub sent: BTreeSet<Uuid>, //Friend requests this player is waiting on.
+}
+
+//What a friend request did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Requested {
+    Sent,
+    Accepted, //They had already asked, so it's a friendship now.
+}
+
+#[derive(Debug, Default)]
+pub struct Social {
+    pub profiles: HashMap<Uuid, Profile>,
+    sessions: HashMap<String, Uuid>,
+    published: HashMap<Uuid, &'static str>, //Presence last pushed to each player's friends.
+}
+
+impl Social {
+    pub fn new() -> Self {
+        Social::default()
+    }
+
+    pub fn resume(&self, session: &str) -> Option<Player> {
+        let player_id = *self.sessions.get(session)?;
+        let profile = self.profiles.get(&player_id)?;
+
+        Some(Player { id: player_id, display_name: profile.display_name.clone() })
+    }
+
+    pub fn connect(&mut self, player: &Player) -> String {
+        //Returns the player's session token, making them a profile if they're new.
+        if let Some(profile) = self.profiles.get(&player.id) {
+            return profile.session.clone();
+        }
+
+        let session = Uuid::new_v4().simple().to_string();
+        self.sessions.insert(session.clone(), player.id);
+        self.profiles.insert(player.id, Profile {
+            display_name: player.display_name.clone(),
+            session: session.clone(),
+            named: false,
+            rating: DEFAULT_RATING,
+            rated: false,
+            tournaments: 0,
+            friends: BTreeSet::new(),
+            requests: BTreeSet::new(),
+            sent: BTreeSet::new(),
+        });
+
+        session
+    }
+
+    pub fn disconnect(&mut self, player_id: Uuid) {
+        //Keeps anyone with friends, requests, a rating or a tournament so they can come back to them.
+        let lonely = self.profiles.get(&player_id)
+            .is_some_and(|profile| {
+                profile.friends.is_empty() && profile.requests.is_empty() && profile.sent.is_empty() && !profile.rated && profile.tournaments == 0
+            });
+
+        if lonely && let Some(profile) = self.profiles.remove(&player_id) {
+            self.sessions.remove(&profile.session);
+            self.published.remove(&player_id);
+        }
+    }
+
+    pub fn set_name(&mut self, player_id: Uuid, name: &str) -> Result<String, String> {
+        let name = name.trim();
+        let length = name.chars().count();
+
+        if !(MIN_NAME_LENGTH..=MAX_NAME_LENGTH).contains(&length) {
+            return Err(format!("Display names must be {} to {} characters", MIN_NAME_LENGTH, MAX_NAME_LENGTH));
+        }
+
+        if !name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == ' ') {
+            return Err("Display names can only have letters, numbers, spaces, _ and -".to_string());
+        }
+
+        if Uuid::parse_str(name).is_ok() {
+            return Err("Display names can't look like a player id".to_string());
+        }
+
+        let taken = self.profiles.iter()
+            .any(|(id, profile)| *id != player_id && profile.named && profile.display_name.eq_ignore_ascii_case(name));
+        if taken {
+            return Err("That name is taken".to_string());
+        }
+
+        let profile = self.profiles.get_mut(&player_id).ok_or("Not connected")?;
+        profile.display_name = name.to_string();
+        profile.named = true;
+
+        Ok(profile.display_name.clone())
+    }
+
+    pub fn find(&self, player: &str) -> Result<Uuid, String> {
+        //Player id or display name, names are matched ignoring case.
+        let player = player.trim();
+
+        if let Ok(player_id) = Uuid::parse_str(player)
+            && self.profiles.contains_key(&player_id) {
+                return Ok(player_id);
+        }
+
+        self.profiles.iter()
+            .find(|(_, profile)| profile.named && profile.display_name.eq_ignore_ascii_case(player))
+            .map(|(id, _)| *id)
+            .ok_or_else(|| "No player with that id or name".to_string())
+    }
+
+    pub fn name(&self, player_id: Uuid) -> String {
+        self.profiles.get(&player_id).map(|profile| profile.display_name.clone()).unwrap_or_default()
+    }
+
+    pub fn rating(&self, player_id: Uuid) -> i32 {
+        self.profiles.get(&player_id).map_or(DEFAULT_RATING, |profile| profile.rating)
+    }
+
+    pub fn record_result(&mut self, winner_id: Uuid, loser_id: Uuid) -> i32 {
+        //Moves both ratings after a rated match, returns how far.
+        let change = tournament::rating_change(self.rating(winner_id), self.rating(loser_id));
+
+        for (player_id, change) in [(winner_id, change), (loser_id, -change)] {
+            if let Some(profile) = self.profiles.get_mut(&player_id) {
+                profile.rating += change;
+                profile.rated = true;
+            }
+        }
+
+        change
+    }
+
+    pub fn set_entered(&mut self, player_id: Uuid, entered: bool) {
+        //Counts the tournaments a player is in, they're kept while they have any so they can reconnect to play.
+        if let Some(profile) = self.profiles.get_mut(&player_id) {
+            profile.tournaments = if entered { profile.tournaments + 1 } else { profile.tournaments.saturating_sub(1) };
+        }
+    }
+
+    pub fn friends(&self, player_id: Uuid) -> Vec<Uuid> {
+        self.profiles.get(&player_id).map(|profile| profile.friends.iter().copied().collect()).unwrap_or_default()
+    }
+
+    pub fn are_friends(&self, player_id: Uuid, other_id: Uuid) -> bool {
+        self.profiles.get(&player_id).is_some_and(|profile| profile.friends.contains(&other_id))
+    }
+
+    pub fn request(&mut self, from: Uuid, to: Uuid) -> Result<Requested, String> {
+        if from == to {
+            return Err("You can't add yourself".to_string());
+        }
+
+        let (Some(sender), Some(receiver)) = (self.profiles.get(&from), self.profiles.get(&to)) else {
+            return Err("No player with that id or name".to_string());
+        };
+
+        if sender.friends.contains(&to) {
+            return Err("Already friends".to_string());
+        }
+
+        if sender.sent.contains(&to) {
+            return Err("Friend request already sent".to_string());
+        }
+
+        if receiver.sent.contains(&from) {
+            self.accept(from, to)?;
+            return Ok(Requested::Accepted);
+        }
+
+        self.profile(from).sent.insert(to);
+        self.profile(to).requests.insert(from);
+        Ok(Requested::Sent)
+    }
+
+    pub fn accept(&mut self, player_id: Uuid, from: Uuid) -> Result<(), String> {
+        let asked = self.profiles.get(&player_id).is_some_and(|profile| profile.requests.contains(&from));
+        if !asked {
+            return Err("No friend request from that player".to_string());
+        }
+
+        self.forget_requests(player_id, from);
+        self.profile(player_id).friends.insert(from);
+        self.profile(from).friends.insert(player_id);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, player_id: Uuid, other_id: Uuid) -> Result<(), String> {
+        //Unfriends, or turns down (or takes back) a request, whichever there is.
+        let known = self.profiles.get(&player_id).is_some_and(|profile| {
+            profile.friends.contains(&other_id) || profile.requests.contains(&other_id) || profile.sent.contains(&other_id)
+        });
+        if !known {
+            return Err("Not friends with that player".to_string());
+        }
+
+        self.forget_requests(player_id, other_id);
+        self.profile(player_id).friends.remove(&other_id);
+        self.profile(other_id).friends.remove(&player_id);
+        Ok(())
+    }
+
+    fn forget_requests(&mut self, player_id: Uuid, other_id: Uuid) {
+        for (one, other) in [(player_id, other_id), (other_id, player_id)] {
+            let profile = self.profile(one);
+            profile.requests.remove(&other);
+            profile.sent.remove(&other);
+        }
+    }
+
+    fn profile(&mut self, player_id: Uuid) -> &mut Profile {
+        //Only called for players already checked to have a profile.
+        self.profiles.get_mut(&player_id).expect("profile checked before use")
+    }
+
+    pub fn entries(&self, ids: impl IntoIterator<Item = Uuid>, statuses: &HashMap<Uuid, &'static str>) -> Vec<serde_json::Value> {
+        //Friend list entries, with each player's presence.
+        ids.into_iter().map(|id| serde_json::json!({
+            "player_id": id.to_string(),
+            "display_name": self.name(id),
+            "status": statuses.get(&id).copied().unwrap_or("offline"),
+        })).collect()
+    }
+
+    pub fn friend_list(&self, player_id: Uuid, statuses: &HashMap<Uuid, &'static str>) -> serde_json::Value {
+        let Some(profile) = self.profiles.get(&player_id) else {
+            return serde_json::json!({ "type": "friend_list", "friends": [], "requests": [], "sent": [] });
+        };
+
+        serde_json::json!({
+            "type": "friend_list",
+            "friends": self.entries(profile.friends.iter().copied(), statuses),
+            "requests": self.entries(profile.requests.iter().copied(), statuses),
+            "sent": self.entries(profile.sent.iter().copied(), statuses),
+        })
+    }
+}
+
+pub fn status(player_id: Uuid, room_controller: &RoomController, clients: &HashMap<Uuid, Client>) -> &'static str {
+    //Playing in a room whose match has started is in a match, spectating or waiting for a match to start is just online.
+    if !clients.contains_key(&player_id) {
+        return "offline";
+    }
+
+    let playing = room_controller.room_of(player_id).is_some_and(|handle| {
+        let info = handle.info();
+        let players = info.summary["players"].as_array().cloned().unwrap_or_default();
+        info.state == "In Progress" && players.iter().any(|player| player["player_id"] == player_id.to_string().as_str())
+    });
+
+    if playing { "in_match" } else { "online" }
+}
+
+pub async fn statuses(ids: &[Uuid], room_controller: &Mutex<RoomController>, clients: &ClientMap) -> HashMap<Uuid, &'static str> {
+    let room_controller = room_controller.lock().await;
+    let clients = clients.lock().await;
+
+    ids.iter().map(|id| (*id, status(*id, &room_controller, &clients))).collect()
+}
+
+pub async fn announce_friends(social: &SocialGraph, room_controller: &Mutex<RoomController>, clients: &ClientMap, player_id: Uuid, friend_id: Uuid) {
+    //Tells both sides of a new friendship about each other, their presence counts as published so it isn't pushed again.
+    let current = statuses(&[player_id, friend_id], room_controller, clients).await;
+
+    let mut pushes = Vec::new();
+    {
+        let mut social = social.lock().await;
+        for (to, other) in [(player_id, friend_id), (friend_id, player_id)] {
+            let added_msg = serde_json::json!({
+                "type": "friend_added",
+                "player_id": other.to_string(),
+                "display_name": social.name(other),
+                "status": current[&other],
+            }).to_string();
+            pushes.push((to, added_msg));
+            social.published.insert(other, current[&other]);
+        }
+    }
+
+    for (to, added_msg) in pushes {
+        super::send_to_clients(clients, &[to], &added_msg).await;
+    }
+}
+
+pub async fn watch_presence(social: SocialGraph, room_controller: Arc<Mutex<RoomController>>, clients: ClientMap) {
+    //Pushes a presence message to a player's online friends whenever their status changes.
+    let mut interval = time::interval(PRESENCE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let ids: Vec<Uuid> = social.lock().await.profiles.iter()
+            .filter(|(_, profile)| !profile.friends.is_empty())
+            .map(|(id, _)| *id)
+            .collect();
+        let current = statuses(&ids, &room_controller, &clients).await;
+
+        //Working out who to tell with the graph locked, then sending with only the clients locked.
+        let mut pushes = Vec::new();
+        {
+            let mut social = social.lock().await;
+            for (id, status) in current {
+                if social.published.insert(id, status) == Some(status) {
+                    continue;
+                }
+
+                let presence_msg = serde_json::json!({
+                    "type": "presence",
+                    "player_id": id.to_string(),
+                    "display_name": social.name(id),
+                    "status": status,
+                }).to_string();
+                pushes.push((social.friends(id), presence_msg));
+            }
+        }
+
+        for (friends, presence_msg) in pushes {
+            super::send_to_clients(&clients, &friends, &presence_msg).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connected(social: &mut Social, name: &str) -> Uuid {
+        let player = Player::new();
+        social.connect(&player);
+        social.set_name(player.id, name).unwrap();
+        player.id
+    }
+
+    #[test]
+    fn requests_become_friendships_and_can_be_taken_back() {
+        let mut social = Social::new();
+        let ana = connected(&mut social, "Ana");
+        let ben = connected(&mut social, "Ben");
+
+        assert!(social.set_name(ben, "ana").is_err()); //Names are unique ignoring case.
+        assert_eq!(social.find("ANA"), Ok(ana));
+        assert_eq!(social.find(&ben.to_string()), Ok(ben));
+
+        assert_eq!(social.request(ana, ben), Ok(Requested::Sent));
+        assert!(social.request(ana, ben).is_err());
+        assert!(social.accept(ana, ben).is_err()); //Ana sent it, only Ben can accept.
+
+        //Ben asking back accepts Ana's request.
+        assert_eq!(social.request(ben, ana), Ok(Requested::Accepted));
+        assert!(social.are_friends(ana, ben) && social.are_friends(ben, ana));
+
+        social.remove(ben, ana).unwrap();
+        assert!(!social.are_friends(ana, ben));
+        assert!(social.remove(ben, ana).is_err());
+    }
+
+    #[test]
+    fn sessions_bring_players_with_friends_back() {
+        let mut social = Social::new();
+        let player = Player::new();
+        let session = social.connect(&player);
+        let friend = connected(&mut social, "Friend");
+
+        social.request(player.id, friend).unwrap();
+        social.disconnect(player.id);
+        assert_eq!(social.resume(&session).map(|player| player.id), Some(player.id));
+        assert_eq!(social.connect(&player), session);
+
+        //With nothing to come back to, the profile is dropped.
+        social.remove(player.id, friend).unwrap();
+        social.disconnect(player.id);
+        assert!(social.resume(&session).is_none());
+    }
+}
@@ -0,0 +1,388 @@
+//This is the tournaments file.
+//It runs the tournaments in tournament.rs on the server: sign-ups come in over the socket, admins create, start and settle them (see admin.rs),
+//and anyone can look at the brackets at /tournaments and /tournaments/{id}.
+//Once a tournament has started, run_tournaments makes a room for each fixture as soon as both it's players are online and not in another room,
+//and sends them it's invite code in a tournament_match message. Only those two can take a seat, the match starts when they both have.
+//When the match is won (or a player walks out of it) the result goes in the bracket, ratings move and the room is closed. A room left before the match starts, or ended early, is made again later.
+//A player still waiting alone in the room rules.fixture_no_show_secs after it was made is given the fixture, without ratings moving as nothing was played.
+//Entrants get a tournament_update with the whole bracket every time it changes.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use tokio::time::{self, Duration};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config;
+use crate::room_controller::RoomController;
+use crate::tournament::{Tournament, TournamentState};
+use super::social::SocialGraph;
+use super::{send_to_clients, ClientMap};
+
+pub const TOURNAMENT_INTERVAL: Duration = Duration::from_secs(1); //How often fixtures are checked for rooms to make and results to take.
+
+pub type TournamentList = Arc<Mutex<Vec<Tournament>>>;
+
+pub fn router(tournaments: TournamentList) -> Router {
+    Router::new()
+        .route("/tournaments", get(list_tournaments))
+        .route("/tournaments/{tournament_id}", get(tournament_detail))
+        .with_state(tournaments)
+}
+
+async fn list_tournaments(State(tournaments): State<TournamentList>) -> Json<Value> {
+    let tournaments = tournaments.lock().await;
+    Json(json!({ "tournaments": tournaments.iter().map(Tournament::summary).collect::<Vec<_>>() }))
+}
+
+async fn tournament_detail(State(tournaments): State<TournamentList>, Path(tournament_id): Path<Uuid>) -> Response {
+    match tournaments.lock().await.iter().find(|tournament| tournament.id == tournament_id) {
+        Some(tournament) => Json(tournament.bracket()).into_response(),
+        None => (StatusCode::NOT_FOUND, Json(json!({ "error": "Tournament not found" }))).into_response(),
+    }
+}
+
+pub fn find(tournaments: &mut [Tournament], tournament_id: Uuid) -> Result<&mut Tournament, String> {
+    tournaments.iter_mut().find(|tournament| tournament.id == tournament_id).ok_or_else(|| "Tournament not found".to_string())
+}
+
+pub fn update_msg(tournament: &Tournament) -> String {
+    json!({
+        "type": "tournament_update",
+        "tournament": tournament.bracket(),
+    }).to_string()
+}
+
+pub async fn push_update(clients: &ClientMap, tournament: &Tournament) {
+    //The bracket to every entrant that's online.
+    let entrants: Vec<Uuid> = tournament.entrants.iter().map(|entrant| entrant.player_id).collect();
+    send_to_clients(clients, &entrants, &update_msg(tournament)).await;
+}
+
+pub async fn sign_up(tournaments: &TournamentList, social: &SocialGraph, clients: &ClientMap, player_id: Uuid, tournament_id: Uuid) -> Result<(), String> {
+    //Entrants are seeded by the rating they have when they sign up.
+    let (display_name, rating) = {
+        let social = social.lock().await;
+        (social.name(player_id), social.rating(player_id))
+    };
+
+    let mut tournaments = tournaments.lock().await;
+    let tournament = find(&mut tournaments, tournament_id)?;
+    tournament.sign_up(player_id, display_name, rating)?;
+    social.lock().await.set_entered(player_id, true);
+
+    push_update(clients, tournament).await;
+    Ok(())
+}
+
+pub async fn withdraw(tournaments: &TournamentList, social: &SocialGraph, clients: &ClientMap, player_id: Uuid, tournament_id: Uuid) -> Result<(), String> {
+    let mut tournaments = tournaments.lock().await;
+    let tournament = find(&mut tournaments, tournament_id)?;
+    tournament.withdraw(player_id)?;
+    social.lock().await.set_entered(player_id, false);
+
+    //The player who left isn't an entrant any more, so they're sent the bracket on their own.
+    push_update(clients, tournament).await;
+    send_to_clients(clients, &[player_id], &update_msg(tournament)).await;
+    Ok(())
+}
+
+pub async fn release_entrants(social: &SocialGraph, tournament: &Tournament) {
+    //Once a tournament is over it's entrants no longer need keeping for it.
+    if tournament.state != TournamentState::Finished {
+        return;
+    }
+
+    info!(tournament_id = %tournament.id, champion = ?tournament.champion, "Tournament finished");
+    let mut social = social.lock().await;
+    for entrant in &tournament.entrants {
+        social.set_entered(entrant.player_id, false);
+    }
+}
+
+pub async fn close_fixture_room(room_controller: &mut RoomController, clients: &ClientMap, room_id: Uuid) {
+    //Whoever is still in the room is told it's closed, as when a spectator's room goes.
+    let left = room_controller.delete_room(room_id).await;
+    let closed_msg = json!({ "type": "room_closed" }).to_string();
+    send_to_clients(clients, &left, &closed_msg).await;
+}
+
+pub async fn run_tournaments(tournaments: TournamentList, room_controller: Arc<Mutex<RoomController>>, clients: ClientMap, social: SocialGraph) {
+    let mut interval = time::interval(TOURNAMENT_INTERVAL);
+    let no_show = Some(config::get().rules.fixture_no_show_secs).filter(|secs| *secs > 0).map(Duration::from_secs);
+
+    loop {
+        interval.tick().await;
+        drive(&tournaments, &room_controller, &clients, &social, no_show).await;
+    }
+}
+
+//A fixture's match that was won, in the fixture's player order. No score when the other player never turned up.
+struct FixtureResult {
+    tournament_index: usize,
+    fixture_id: usize,
+    winner: Uuid,
+    loser: Uuid,
+    score: Option<[u32; 2]>,
+}
+
+pub async fn drive(tournaments: &TournamentList, room_controller: &Mutex<RoomController>, clients: &ClientMap, social: &SocialGraph, no_show: Option<Duration>) {
+    //One pass over every running tournament: takes results from finished fixture rooms, then makes rooms for fixtures whose players are free.
+    //Fixture rooms that have waited no_show for a second player are given to the one in them, None waits forever.
+    let mut tournaments = tournaments.lock().await;
+    let mut room_control = room_controller.lock().await;
+    let online: HashSet<Uuid> = clients.lock().await.keys().copied().collect();
+
+    let mut changed = HashSet::new();
+    let mut results = Vec::new();
+    let mut finished_rooms = Vec::new();
+    let mut busy = HashSet::new(); //Players with a fixture room waiting on them.
+
+    for (tournament_index, tournament) in tournaments.iter_mut().enumerate().filter(|(_, tournament)| tournament.state == TournamentState::InProgress) {
+        for fixture in tournament.fixtures.iter_mut().filter(|fixture| fixture.is_ready()) {
+            let Some(room_id) = fixture.room_id else {
+                continue;
+            };
+            let [Some(first), Some(second)] = fixture.players else {
+                continue;
+            };
+
+            let Some(info) = room_control.find_room_by_id(room_id).map(|handle| handle.info().clone()) else {
+                //Everyone left and the room went with them.
+                fixture.room_id = None;
+                fixture.code = None;
+                changed.insert(tournament_index);
+                continue;
+            };
+
+            if info.state != "Finished" {
+                let seated: Vec<Uuid> = [first, second].into_iter()
+                    .filter(|player_id| room_control.room_of(*player_id).is_some_and(|handle| handle.id == room_id))
+                    .collect();
+                let waited = no_show.zip(fixture.room_made_at).is_some_and(|(no_show, made_at)| made_at.elapsed() >= no_show);
+
+                if let [winner] = seated[..] && info.state == "Not Started" && waited {
+                    finished_rooms.push(room_id);
+                    results.push(FixtureResult {
+                        tournament_index,
+                        fixture_id: fixture.id,
+                        winner,
+                        loser: if winner == first { second } else { first },
+                        score: None,
+                    });
+                    continue;
+                }
+
+                busy.extend([first, second]);
+                continue;
+            }
+
+            finished_rooms.push(room_id);
+            let winner = info.winner_ids.first().copied().filter(|winner| fixture.players.contains(&Some(*winner)));
+
+            match (winner, info.winner_team) {
+                (Some(winner), Some(winner_team)) => {
+                    //Read by team, a match won by the other player leaving can have the winner behind on points.
+                    let points = |team: usize| info.summary["score"][team].as_u64().unwrap_or(0) as u32;
+                    let (won, lost) = (points(winner_team), points(1 - winner_team));
+
+                    results.push(FixtureResult {
+                        tournament_index,
+                        fixture_id: fixture.id,
+                        winner,
+                        loser: if winner == first { second } else { first },
+                        score: Some(if winner == first { [won, lost] } else { [lost, won] }),
+                    });
+                }
+                _ => {
+                    //Ended early by an admin, it's played again.
+                    fixture.room_id = None;
+                    fixture.code = None;
+                    changed.insert(tournament_index);
+                }
+            }
+        }
+    }
+
+    for result in results {
+        let tournament = &mut tournaments[result.tournament_index];
+
+        match tournament.record_result(result.fixture_id, result.winner, result.score) {
+            Ok(()) if result.score.is_none() => {
+                info!(tournament_id = %tournament.id, fixture_id = result.fixture_id, winner = %result.winner, no_show = %result.loser, "Fixture given to the player who turned up");
+
+                release_entrants(social, tournament).await;
+                changed.insert(result.tournament_index);
+            }
+            Ok(()) => {
+                let change = social.lock().await.record_result(result.winner, result.loser);
+                info!(tournament_id = %tournament.id, fixture_id = result.fixture_id, winner = %result.winner, change, "Fixture won");
+
+                release_entrants(social, tournament).await;
+                changed.insert(result.tournament_index);
+            }
+            Err(reason) => warn!(tournament_id = %tournament.id, fixture_id = result.fixture_id, %reason, "Couldn't record fixture result"),
+        }
+    }
+
+    for room_id in finished_rooms {
+        close_fixture_room(&mut room_control, clients, room_id).await;
+    }
+
+    //Rooms for fixtures both players are free for, a player only gets one at a time.
+    let mut invites = Vec::new();
+    let free = |player_id: Uuid, room_control: &RoomController, busy: &HashSet<Uuid>| {
+        online.contains(&player_id) && room_control.room_of(player_id).is_none() && !busy.contains(&player_id)
+    };
+
+    for (tournament_index, tournament) in tournaments.iter_mut().enumerate().filter(|(_, tournament)| tournament.state == TournamentState::InProgress) {
+        let names: Vec<(Uuid, String)> = tournament.entrants.iter().map(|entrant| (entrant.player_id, entrant.display_name.clone())).collect();
+        let name = |player_id: Uuid| names.iter().find(|(id, _)| *id == player_id).map(|(_, name)| name.clone()).unwrap_or_default();
+
+        for fixture in tournament.fixtures.iter_mut().filter(|fixture| fixture.is_ready() && fixture.room_id.is_none()) {
+            let [Some(first), Some(second)] = fixture.players else {
+                continue;
+            };
+
+            if !free(first, &room_control, &busy) || !free(second, &room_control, &busy) {
+                continue;
+            }
+
+            match room_control.create_fixture_room(&tournament.mode, tournament.match_length, vec![first, second]).await {
+                Ok((room_id, code)) => {
+                    fixture.room_id = Some(room_id);
+                    fixture.code = Some(code.clone());
+                    fixture.room_made_at = Some(Instant::now());
+                    busy.extend([first, second]);
+                    changed.insert(tournament_index);
+
+                    for (player_id, opponent_id) in [(first, second), (second, first)] {
+                        let match_msg = json!({
+                            "type": "tournament_match",
+                            "tournament_id": tournament.id.to_string(),
+                            "fixture_id": fixture.id,
+                            "room_id": room_id.to_string(),
+                            "code": code, //Join with join_room, as for a private room.
+                            "opponent": { "player_id": opponent_id.to_string(), "display_name": name(opponent_id) },
+                        });
+                        invites.push((player_id, match_msg.to_string()));
+                    }
+                }
+                Err(reason) => warn!(tournament_id = %tournament.id, fixture_id = fixture.id, %reason, "Couldn't make a fixture room"),
+            }
+        }
+    }
+
+    drop(room_control);
+
+    for (player_id, match_msg) in invites {
+        send_to_clients(clients, &[player_id], &match_msg).await;
+    }
+
+    for tournament_index in changed {
+        push_update(clients, &tournaments[tournament_index]).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::room_controller::room::Room;
+    use crate::tournament::Format;
+    use crate::Player;
+    use super::super::social::Social;
+
+    #[tokio::test]
+    async fn won_fixtures_go_in_the_bracket_and_move_ratings() {
+        let room_controller = Mutex::new(RoomController::new());
+        let clients: ClientMap = Arc::new(Mutex::new(HashMap::new()));
+        let social: SocialGraph = Arc::new(Mutex::new(Social::new()));
+
+        let (winner, loser) = (Player::new(), Player::new());
+        let mut final_ = Tournament::new("Final".to_string(), Format::SingleElimination, "classic".to_string(), None, 2).unwrap();
+        for player in [&winner, &loser] {
+            social.lock().await.connect(player);
+            final_.sign_up(player.id, player.display_name.clone(), 1200).unwrap();
+        }
+        final_.start().unwrap();
+
+        //A fixture room whose match the winner (seat 1) has just won, built by hand so no replay is written.
+        let mut room = Room::new().await;
+        room.add_player(loser.clone());
+        room.add_player(winner.clone());
+        room.rules.score = [3, 11];
+        room.rules.winner = Some(1);
+        room.winner_ids = vec![winner.id];
+        room.state = "Finished".to_string();
+        final_.fixtures[0].room_id = Some(room.id);
+        room_controller.lock().await.spawn_room(room, Vec::new());
+
+        let tournaments: TournamentList = Arc::new(Mutex::new(vec![final_]));
+        drive(&tournaments, &room_controller, &clients, &social, None).await;
+
+        let tournaments = tournaments.lock().await;
+        assert_eq!(tournaments[0].state, TournamentState::Finished);
+        assert_eq!(tournaments[0].champion, Some(winner.id));
+
+        let seat_of_winner = tournaments[0].fixtures[0].players.iter().position(|player| *player == Some(winner.id)).unwrap();
+        assert_eq!(tournaments[0].fixtures[0].score.unwrap()[seat_of_winner], 11);
+
+        assert_eq!(social.lock().await.rating(winner.id), 1216);
+        assert_eq!(social.lock().await.rating(loser.id), 1184);
+        assert!(room_controller.lock().await.rooms_list.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_fixture_goes_to_the_player_who_turned_up() {
+        let room_controller = Mutex::new(RoomController::new());
+        let clients: ClientMap = Arc::new(Mutex::new(HashMap::new()));
+        let social: SocialGraph = Arc::new(Mutex::new(Social::new()));
+
+        let (waiting, missing) = (Player::new(), Player::new());
+        let mut final_ = Tournament::new("Final".to_string(), Format::SingleElimination, "classic".to_string(), None, 2).unwrap();
+        for player in [&waiting, &missing] {
+            social.lock().await.connect(player);
+            final_.sign_up(player.id, player.display_name.clone(), 1200).unwrap();
+        }
+        final_.start().unwrap();
+
+        //The fixture's room with only one of it's players in, built by hand so no replay is written.
+        let mut room = Room::new().await;
+        room.make_fixture("ABCDEF".to_string(), 11, vec![waiting.id, missing.id]);
+        room.add_player(waiting.clone());
+        final_.fixtures[0].room_id = Some(room.id);
+        final_.fixtures[0].room_made_at = Some(Instant::now());
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        room_controller.lock().await.spawn_room(room, vec![(waiting.id, sender)]);
+
+        //Before the wait is up it's left alone.
+        let tournaments: TournamentList = Arc::new(Mutex::new(vec![final_]));
+        drive(&tournaments, &room_controller, &clients, &social, Some(Duration::from_secs(300))).await;
+        assert_eq!(tournaments.lock().await[0].state, TournamentState::InProgress);
+        assert_eq!(room_controller.lock().await.rooms_list.len(), 1);
+
+        drive(&tournaments, &room_controller, &clients, &social, Some(Duration::ZERO)).await;
+
+        let tournaments = tournaments.lock().await;
+        assert_eq!(tournaments[0].champion, Some(waiting.id));
+        assert_eq!(tournaments[0].fixtures[0].score, None);
+
+        //Nothing was played, so ratings stay put.
+        assert_eq!(social.lock().await.rating(waiting.id), 1200);
+        assert_eq!(social.lock().await.rating(missing.id), 1200);
+        assert!(room_controller.lock().await.rooms_list.is_empty());
+    }
+}
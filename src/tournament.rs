@@ -0,0 +1,452 @@
+//This is the tournament file.
+//It holds a tournament's sign-ups and bracket, and nothing about rooms or connections, so it can be tested (and driven) on it's own.
+//Entrants are seeded by rating when the tournament starts, then either a single elimination bracket or a round robin is drawn.
+//Single elimination fills up to a power of two with byes for the top seeds, seeds 1 and 2 can only meet in the final.
+//Results come in one fixture at a time, winners move on to their next fixture and the tournament finishes once every fixture is played.
+//Ratings are Elo, every player starts at DEFAULT_RATING and only tournament matches move them (see server/tournaments.rs).
+
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const DEFAULT_RATING: i32 = 1200;
+pub const K_FACTOR: f64 = 32.0; //Most a rating can move in one match.
+pub const MAX_ENTRANTS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    SingleElimination,
+    RoundRobin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TournamentState {
+    SigningUp,
+    InProgress,
+    Finished,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Entrant {
+    pub player_id: Uuid,
+    pub display_name: String,
+    pub rating: i32, //When they signed up, seeding goes by this.
+    pub seed: Option<usize>, //1 is the top seed, set when the tournament starts.
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Fixture {
+    pub id: usize,
+    pub round: usize, //From 0, the final is the last round of an elimination bracket.
+    pub players: [Option<Uuid>; 2], //None until the winners of the fixtures before it are known.
+    pub winner: Option<Uuid>,
+    pub score: Option<[u32; 2]>, //In the same order as players, None for byes and results an admin settled.
+    pub bye: bool,
+    pub room_id: Option<Uuid>, //Room the fixture is being played in, made once both players are free.
+    pub code: Option<String>, //Invite code of that room, only it's two players can take a seat.
+    #[serde(skip)]
+    pub room_made_at: Option<Instant>, //When that room was made, a player who hasn't turned up a while after forfeits.
+    #[serde(skip)]
+    next: Option<(usize, usize)>, //Fixture and slot the winner goes on to.
+}
+
+impl Fixture {
+    pub fn is_ready(&self) -> bool {
+        //Both players known and not played yet.
+        self.players.iter().all(Option::is_some) && self.winner.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Standing {
+    pub player_id: Uuid,
+    pub display_name: String,
+    pub played: u32,
+    pub wins: u32,
+    pub losses: u32,
+    pub points_for: u32,
+    pub points_against: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Tournament {
+    pub id: Uuid,
+    pub name: String,
+    pub format: Format,
+    pub mode: String, //Game mode every fixture is played in.
+    pub match_length: Option<u32>, //Defaults to the mode's.
+    pub max_entrants: usize,
+    pub state: TournamentState,
+    pub entrants: Vec<Entrant>, //In sign-up order, then in seed order once started.
+    pub fixtures: Vec<Fixture>,
+    pub champion: Option<Uuid>,
+}
+
+impl Tournament {
+    pub fn new(name: String, format: Format, mode: String, match_length: Option<u32>, max_entrants: usize) -> Result<Self, String> {
+        if name.trim().is_empty() {
+            return Err("Tournament needs a name".to_string());
+        }
+
+        if !(2..=MAX_ENTRANTS).contains(&max_entrants) {
+            return Err(format!("Tournaments take 2 to {} players", MAX_ENTRANTS));
+        }
+
+        Ok(Tournament {
+            id: Uuid::new_v4(),
+            name: name.trim().to_string(),
+            format,
+            mode,
+            match_length,
+            max_entrants,
+            state: TournamentState::SigningUp,
+            entrants: Vec::new(),
+            fixtures: Vec::new(),
+            champion: None,
+        })
+    }
+
+    pub fn sign_up(&mut self, player_id: Uuid, display_name: String, rating: i32) -> Result<(), String> {
+        if self.state != TournamentState::SigningUp {
+            return Err("Sign-ups are closed".to_string());
+        }
+
+        if self.has_entrant(player_id) {
+            return Err("Already signed up".to_string());
+        }
+
+        if self.entrants.len() >= self.max_entrants {
+            return Err("Tournament is full".to_string());
+        }
+
+        self.entrants.push(Entrant { player_id, display_name, rating, seed: None });
+        Ok(())
+    }
+
+    pub fn withdraw(&mut self, player_id: Uuid) -> Result<(), String> {
+        //Only before the draw, after that a player who doesn't turn up is settled by an admin.
+        if self.state != TournamentState::SigningUp {
+            return Err("Sign-ups are closed".to_string());
+        }
+
+        let index = self.entrants.iter().position(|entrant| entrant.player_id == player_id).ok_or("Not signed up")?;
+        self.entrants.remove(index);
+        Ok(())
+    }
+
+    pub fn has_entrant(&self, player_id: Uuid) -> bool {
+        self.entrants.iter().any(|entrant| entrant.player_id == player_id)
+    }
+
+    pub fn start(&mut self) -> Result<(), String> {
+        if self.state != TournamentState::SigningUp {
+            return Err("Tournament has already started".to_string());
+        }
+
+        if self.entrants.len() < 2 {
+            return Err("Need at least 2 players to start".to_string());
+        }
+
+        //Highest rating first, ties stay in sign-up order.
+        self.entrants.sort_by_key(|entrant| std::cmp::Reverse(entrant.rating));
+        for (index, entrant) in self.entrants.iter_mut().enumerate() {
+            entrant.seed = Some(index + 1);
+        }
+
+        let seeded: Vec<Uuid> = self.entrants.iter().map(|entrant| entrant.player_id).collect();
+        self.fixtures = match self.format {
+            Format::SingleElimination => single_elimination(&seeded),
+            Format::RoundRobin => round_robin(&seeded),
+        };
+        self.state = TournamentState::InProgress;
+
+        //Byes go straight through.
+        for id in 0..self.fixtures.len() {
+            let fixture = &self.fixtures[id];
+            if fixture.bye && let Some(winner) = fixture.winner {
+                self.advance(id, winner);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn record_result(&mut self, fixture_id: usize, winner: Uuid, score: Option<[u32; 2]>) -> Result<(), String> {
+        if self.state != TournamentState::InProgress {
+            return Err("Tournament is not in progress".to_string());
+        }
+
+        let fixture = self.fixtures.get_mut(fixture_id).ok_or("No such fixture")?;
+
+        if !fixture.is_ready() {
+            return Err("Fixture isn't waiting on a result".to_string());
+        }
+
+        if !fixture.players.contains(&Some(winner)) {
+            return Err("Winner isn't in this fixture".to_string());
+        }
+
+        fixture.winner = Some(winner);
+        fixture.score = score;
+        fixture.room_id = None;
+        fixture.code = None;
+        self.advance(fixture_id, winner);
+
+        if self.fixtures.iter().all(|fixture| fixture.winner.is_some()) {
+            self.state = TournamentState::Finished;
+            self.champion = match self.format {
+                Format::SingleElimination => self.fixtures.last().and_then(|last| last.winner),
+                Format::RoundRobin => self.standings().first().map(|standing| standing.player_id),
+            };
+        }
+
+        Ok(())
+    }
+
+    fn advance(&mut self, fixture_id: usize, winner: Uuid) {
+        if let Some((next, slot)) = self.fixtures[fixture_id].next {
+            self.fixtures[next].players[slot] = Some(winner);
+        }
+    }
+
+    pub fn standings(&self) -> Vec<Standing> {
+        //Most wins first, then best points difference, then seed.
+        let mut standings: Vec<(usize, Standing)> = self.entrants.iter().map(|entrant| (entrant.seed.unwrap_or(usize::MAX), Standing {
+            player_id: entrant.player_id,
+            display_name: entrant.display_name.clone(),
+            played: 0,
+            wins: 0,
+            losses: 0,
+            points_for: 0,
+            points_against: 0,
+        })).collect();
+
+        for fixture in self.fixtures.iter().filter(|fixture| !fixture.bye) {
+            let Some(winner) = fixture.winner else {
+                continue;
+            };
+
+            for (slot, player) in fixture.players.iter().enumerate() {
+                let Some((_, standing)) = standings.iter_mut().find(|(_, standing)| Some(standing.player_id) == *player) else {
+                    continue;
+                };
+
+                standing.played += 1;
+                if standing.player_id == winner { standing.wins += 1; } else { standing.losses += 1; }
+
+                if let Some(score) = fixture.score {
+                    standing.points_for += score[slot];
+                    standing.points_against += score[1 - slot];
+                }
+            }
+        }
+
+        let difference = |standing: &Standing| standing.points_for as i64 - standing.points_against as i64;
+        standings.sort_by(|(seed_a, a), (seed_b, b)| {
+            b.wins.cmp(&a.wins).then(difference(b).cmp(&difference(a))).then(seed_a.cmp(seed_b))
+        });
+
+        standings.into_iter().map(|(_, standing)| standing).collect()
+    }
+
+    pub fn summary(&self) -> serde_json::Value {
+        //Short description for tournament listings.
+        serde_json::json!({
+            "tournament_id": self.id.to_string(),
+            "name": self.name,
+            "format": self.format,
+            "mode": self.mode,
+            "state": self.state,
+            "entrants": self.entrants.len(),
+            "max_entrants": self.max_entrants,
+            "champion": self.champion.map(|id| id.to_string()),
+        })
+    }
+
+    pub fn bracket(&self) -> serde_json::Value {
+        //Everything about the tournament, sent to it's entrants whenever it changes.
+        let mut bracket = self.summary();
+        bracket["match_length"] = serde_json::json!(self.match_length);
+        bracket["entrants"] = serde_json::json!(self.entrants);
+        bracket["fixtures"] = serde_json::json!(self.fixtures);
+        bracket["standings"] = serde_json::json!(self.standings());
+        bracket
+    }
+}
+
+pub fn seed_order(size: usize) -> Vec<usize> {
+    //Seeds (from 1) in bracket order for a power of two, each pair adds up to size + 1 so the top seeds meet as late as possible.
+    let mut order = vec![1];
+
+    while order.len() < size {
+        let doubled = order.len() * 2;
+        order = order.iter().flat_map(|seed| [*seed, doubled + 1 - seed]).collect();
+    }
+
+    order
+}
+
+fn single_elimination(seeded: &[Uuid]) -> Vec<Fixture> {
+    let size = seeded.len().next_power_of_two();
+    let rounds = size.trailing_zeros() as usize;
+    let order = seed_order(size);
+    let mut fixtures = Vec::with_capacity(size - 1);
+
+    let mut first_of_round = 0;
+    for round in 0..rounds {
+        let count = size >> (round + 1);
+        let first_of_next = first_of_round + count;
+
+        for index in 0..count {
+            let next = (round + 1 < rounds).then_some((first_of_next + index / 2, index % 2));
+            fixtures.push(Fixture {
+                id: first_of_round + index,
+                round,
+                players: [None, None],
+                winner: None,
+                score: None,
+                bye: false,
+                room_id: None,
+                code: None,
+                room_made_at: None,
+                next,
+            });
+        }
+
+        first_of_round = first_of_next;
+    }
+
+    //Seeds past the number of entrants are byes, only ever one to a first round fixture.
+    let entrant = |seed: usize| seeded.get(seed - 1).copied();
+    for (fixture, pair) in fixtures.iter_mut().zip(order.chunks(2)) {
+        fixture.players = [entrant(pair[0]), entrant(pair[1])];
+
+        if let [Some(player), None] | [None, Some(player)] = fixture.players {
+            fixture.bye = true;
+            fixture.winner = Some(player);
+        }
+    }
+
+    fixtures
+}
+
+fn round_robin(seeded: &[Uuid]) -> Vec<Fixture> {
+    //Circle method, the first seed stays put while everyone else rotates round them. An odd number gets a gap, whoever meets it sits out the round.
+    let mut ring: Vec<Option<Uuid>> = seeded.iter().copied().map(Some).collect();
+    if ring.len() % 2 == 1 {
+        ring.push(None);
+    }
+
+    let count = ring.len();
+    let mut fixtures = Vec::new();
+
+    for round in 0..count - 1 {
+        for index in 0..count / 2 {
+            if let (Some(a), Some(b)) = (ring[index], ring[count - 1 - index]) {
+                fixtures.push(Fixture {
+                    id: fixtures.len(),
+                    round,
+                    players: [Some(a), Some(b)],
+                    winner: None,
+                    score: None,
+                    bye: false,
+                    room_id: None,
+                    code: None,
+                    room_made_at: None,
+                    next: None,
+                });
+            }
+        }
+
+        ring[1..].rotate_right(1);
+    }
+
+    fixtures
+}
+
+pub fn rating_change(winner_rating: i32, loser_rating: i32) -> i32 {
+    //Points the winner takes from the loser, more for beating someone rated above you.
+    let expected = 1.0 / (1.0 + 10f64.powf((loser_rating - winner_rating) as f64 / 400.0));
+    (K_FACTOR * (1.0 - expected)).round() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tournament(format: Format, ratings: &[i32]) -> (Tournament, Vec<Uuid>) {
+        let mut tournament = Tournament::new("Office cup".to_string(), format, "quick".to_string(), None, 8).unwrap();
+        let ids: Vec<Uuid> = ratings.iter().map(|_| Uuid::new_v4()).collect();
+
+        for (index, (id, rating)) in ids.iter().zip(ratings).enumerate() {
+            tournament.sign_up(*id, format!("Player {}", index), *rating).unwrap();
+        }
+
+        (tournament, ids)
+    }
+
+    #[test]
+    fn top_seeds_get_the_byes_and_winners_go_through_to_the_final() {
+        //Five players seeded by rating, the bracket fills to eight so seeds 1 to 3 get byes.
+        let (mut cup, ids) = tournament(Format::SingleElimination, &[1100, 1500, 1200, 1300, 1000]);
+        assert!(cup.sign_up(ids[0], "Again".to_string(), 1100).is_err());
+        cup.start().unwrap();
+
+        assert_eq!(seed_order(8), [1, 8, 4, 5, 2, 7, 3, 6]);
+        assert_eq!(cup.entrants[0].player_id, ids[1]);
+        assert_eq!(cup.fixtures.len(), 7);
+        assert_eq!(cup.fixtures.iter().filter(|fixture| fixture.bye).count(), 3);
+        assert!(cup.sign_up(Uuid::new_v4(), "Late".to_string(), 1200).is_err());
+
+        //Seeds 4 and 5 play the only real first round fixture, seeds 2 and 3 both had byes so can already meet, the top seed waits.
+        let ready: Vec<usize> = cup.fixtures.iter().filter(|fixture| fixture.is_ready()).map(|fixture| fixture.id).collect();
+        assert_eq!(ready, [1, 5]);
+        assert_eq!(cup.fixtures[4].players, [Some(ids[1]), None]);
+
+        cup.record_result(1, ids[4], Some([11, 9])).unwrap(); //Seed 5 knocks out seed 4.
+        assert!(cup.record_result(1, ids[3], None).is_err());
+        assert_eq!(cup.fixtures[4].players, [Some(ids[1]), Some(ids[4])]);
+
+        cup.record_result(4, ids[4], Some([8, 11])).unwrap();
+        cup.record_result(5, ids[2], Some([11, 3])).unwrap();
+        assert_eq!(cup.state, TournamentState::InProgress);
+
+        cup.record_result(6, ids[4], Some([11, 7])).unwrap();
+        assert_eq!(cup.state, TournamentState::Finished);
+        assert_eq!(cup.champion, Some(ids[4]));
+    }
+
+    #[test]
+    fn everyone_plays_everyone_once_in_a_round_robin() {
+        let (mut league, ids) = tournament(Format::RoundRobin, &[1200, 1200, 1200]);
+        league.start().unwrap();
+
+        //Three players, one sits out each round.
+        assert_eq!(league.fixtures.len(), 3);
+        for (a, b) in [(0, 1), (0, 2), (1, 2)] {
+            assert_eq!(league.fixtures.iter().filter(|fixture| fixture.players.contains(&Some(ids[a])) && fixture.players.contains(&Some(ids[b]))).count(), 1);
+        }
+
+        //Everyone wins once, the points difference decides it.
+        for (winner, loser, points) in [(ids[0], ids[1], [11, 9]), (ids[2], ids[0], [11, 2]), (ids[1], ids[2], [11, 0])] {
+            let fixture = league.fixtures.iter().find(|fixture| fixture.players.contains(&Some(winner)) && fixture.players.contains(&Some(loser))).unwrap();
+            let score = if fixture.players[0] == Some(winner) { points } else { [points[1], points[0]] };
+            league.record_result(fixture.id, winner, Some(score)).unwrap();
+        }
+
+        let standings = league.standings();
+        assert!(standings.iter().all(|standing| standing.played == 2 && standing.wins == 1));
+        assert_eq!(league.champion, Some(standings[0].player_id));
+        assert_eq!(standings[0].player_id, ids[1]);
+    }
+
+    #[test]
+    fn upsets_move_ratings_more() {
+        assert_eq!(rating_change(1200, 1200), 16);
+        assert!(rating_change(1000, 1400) > 25);
+        assert!(rating_change(1400, 1000) < 7);
+    }
+}
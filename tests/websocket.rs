@@ -0,0 +1,397 @@
+//These are the end to end tests.
+//Each test starts the whole app on a free local port and talks to it over real WebSockets, the same way the browser client does.
+//Rooms still record replays, the files of rooms a test joined are deleted when it finishes.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, Mutex};
+use tokio::time::{self, Duration};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+
+use ping_pong::replay;
+use ping_pong::server::{app, social, tournaments, AppState};
+use ping_pong::tournament::{Format, Tournament};
+use ping_pong::RoomController;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn start_server() -> SocketAddr {
+    start_app().await.0
+}
+
+//Also hands back the app's state, for tests that need to set things up the way an admin would.
+async fn start_app() -> (SocketAddr, AppState) {
+    let state = AppState {
+        room_controller: Arc::new(Mutex::new(RoomController::new())),
+        clients: Arc::new(Mutex::new(HashMap::new())),
+        bans: Arc::new(Mutex::new(HashSet::new())),
+        social: Arc::new(Mutex::new(social::Social::new())),
+        tournaments: Arc::new(Mutex::new(Vec::new())),
+        tx: broadcast::channel(16).0,
+    };
+    tokio::spawn(social::watch_presence(state.social.clone(), state.room_controller.clone(), state.clients.clone()));
+    tokio::spawn(tournaments::run_tournaments(state.tournaments.clone(), state.room_controller.clone(), state.clients.clone(), state.social.clone()));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let router = app(state.clone(), None);
+    tokio::spawn(async move {
+        axum::serve(listener, router.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    });
+
+    (addr, state)
+}
+
+async fn http_get(addr: SocketAddr, path: &str) -> Value {
+    //Plain HTTP/1.1 with the connection closed after, so the body is everything after the headers.
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, addr).as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+
+    serde_json::from_str(body).unwrap()
+}
+
+//Deletes the replays of the rooms a test played in, even if it fails part way.
+#[derive(Default)]
+struct ReplayCleanup(Vec<Uuid>);
+
+impl Drop for ReplayCleanup {
+    fn drop(&mut self) {
+        for room_id in &self.0 {
+            let _ = std::fs::remove_file(replay::replay_path(*room_id));
+        }
+
+        let _ = std::fs::remove_dir(replay::REPLAY_DIR); //Only removed if no other replays are in it.
+    }
+}
+
+struct TestClient {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    player_id: String,
+    session: String,
+}
+
+impl TestClient {
+    async fn connect(addr: SocketAddr) -> Self {
+        Self::connect_to(format!("ws://{}/ws", addr)).await
+    }
+
+    async fn connect_to(url: String) -> Self {
+        let (socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let mut client = TestClient { socket, player_id: String::new(), session: String::new() };
+
+        let init = client.next().await;
+        assert_eq!(init["type"], "init");
+        assert_eq!(init["player_index"], -1);
+        client.player_id = init["player_id"].as_str().unwrap().to_string();
+        client.session = init["session"].as_str().unwrap().to_string();
+
+        client
+    }
+
+    async fn send(&mut self, message: Value) {
+        self.socket.send(Message::Text(message.to_string().into())).await.unwrap();
+    }
+
+    async fn next(&mut self) -> Value {
+        loop {
+            let message = time::timeout(REPLY_TIMEOUT, self.socket.next()).await
+                .expect("Timed out waiting for the server")
+                .expect("Connection closed")
+                .unwrap();
+
+            if let Message::Text(text) = message {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    async fn wait_for(&mut self, matches: impl Fn(&Value) -> bool) -> Value {
+        //Skips everything else (mostly snapshots) until a matching message arrives.
+        time::timeout(REPLY_TIMEOUT, async {
+            loop {
+                let message = self.next().await;
+                if matches(&message) {
+                    return message;
+                }
+            }
+        }).await.expect("Timed out waiting for a matching message")
+    }
+
+    async fn join(&mut self, request: Value, cleanup: &mut ReplayCleanup) -> Value {
+        self.send(request).await;
+        let joined = self.wait_for(|message| message["type"] == "room_joined" || message["type"] == "error").await;
+        assert_eq!(joined["type"], "room_joined", "{}", joined);
+
+        cleanup.0.push(joined["room_id"].as_str().unwrap().parse().unwrap());
+        joined
+    }
+}
+
+async fn two_player_match(cleanup: &mut ReplayCleanup) -> (SocketAddr, TestClient, TestClient) {
+    let addr = start_server().await;
+    let mut first = TestClient::connect(addr).await;
+    let mut second = TestClient::connect(addr).await;
+
+    let first_joined = first.join(json!({ "type": "join_room" }), cleanup).await;
+    let second_joined = second.join(json!({ "type": "join_room" }), cleanup).await;
+
+    assert_eq!(first_joined["room_id"], second_joined["room_id"]);
+    assert_eq!((first_joined["player_index"].as_i64(), second_joined["player_index"].as_i64()), (Some(0), Some(1)));
+
+    (addr, first, second)
+}
+
+#[tokio::test]
+async fn players_are_matched_into_one_room() {
+    let mut cleanup = ReplayCleanup::default();
+    let (_, mut first, second) = two_player_match(&mut cleanup).await;
+
+    //The second join fills the room and starts the match, everyone in it is told.
+    let update = first.wait_for(|message| message["type"] == "room_update" && message["room"]["players"].as_array().unwrap().len() == 2).await;
+    assert_eq!(update["room"]["state"], "In Progress");
+    assert_eq!(update["room"]["players"][1]["player_id"], second.player_id.as_str());
+}
+
+#[tokio::test]
+async fn moves_show_up_in_player_state() {
+    let mut cleanup = ReplayCleanup::default();
+    let (_, mut first, mut second) = two_player_match(&mut cleanup).await;
+
+    first.send(json!({ "type": "move", "dx": 1.5, "dy": 0.5, "dz": 0.0 })).await;
+
+    //Both players see the bat move, it stays on it's seat's plane whatever depth was sent.
+    let moved = |player_id: String| move |message: &Value| {
+        message["type"] == "player_state" && message["player_id"] == player_id.as_str() && message["pos"][0] == 1.5
+    };
+    let seen_by_first = first.wait_for(moved(first.player_id.clone())).await;
+    let seen_by_second = second.wait_for(moved(first.player_id.clone())).await;
+
+    assert_eq!(seen_by_first["pos"][1], 0.5);
+    assert_eq!(seen_by_second["pos"], seen_by_first["pos"]);
+    assert_ne!(seen_by_first["pos"][2], 0.0);
+}
+
+#[tokio::test]
+async fn swings_are_scored_on_practice_shots() {
+    let mut cleanup = ReplayCleanup::default();
+    let addr = start_server().await;
+    let mut player = TestClient::connect(addr).await;
+    player.join(json!({ "type": "join_room", "mode": "practice" }), &mut cleanup).await;
+
+    //Swinging while the machine's ball is in the air gives that shot a contact quality.
+    player.wait_for(|message| message["type"] == "ball_state" && message["vel"][2] != 0.0).await;
+    player.send(json!({ "type": "hit_begin" })).await;
+    player.send(json!({ "type": "hit_end" })).await;
+
+    let shot = player.wait_for(|message| message["type"] == "practice_shot").await;
+    assert_eq!(shot["shot"], 1);
+    assert!(shot["quality"].is_f64(), "{}", shot);
+}
+
+#[tokio::test]
+async fn closing_a_connection_removes_the_player() {
+    let mut cleanup = ReplayCleanup::default();
+    let (addr, first, mut second) = two_player_match(&mut cleanup).await;
+    let first_id = first.player_id.clone();
+    let room_id = cleanup.0[0].to_string();
+
+    let mut socket = first.socket;
+    socket.close(None).await.unwrap();
+
+    let removed = second.wait_for(|message| message["type"] == "remove").await;
+    assert_eq!(removed["player_id"], first_id.as_str());
+
+    //Their seat is free again, so the next player looking for a match takes it.
+    let mut third = TestClient::connect(addr).await;
+    let joined = third.join(json!({ "type": "join_room" }), &mut cleanup).await;
+    assert_eq!(joined["room_id"], room_id);
+}
+
+#[tokio::test]
+async fn chat_reaches_the_room_except_whoever_muted_the_sender() {
+    let mut cleanup = ReplayCleanup::default();
+    let (_, mut first, mut second) = two_player_match(&mut cleanup).await;
+
+    first.send(json!({ "type": "chat", "text": " well played, damn " })).await;
+    let chat = second.wait_for(|message| message["type"] == "chat").await;
+    assert_eq!(chat["text"], "well played, ****");
+    assert_eq!(chat["player_id"], first.player_id.as_str());
+
+    second.send(json!({ "type": "mute", "player_id": first.player_id })).await;
+    let muted = second.wait_for(|message| message["type"] == "muted" || message["type"] == "error").await;
+    assert_eq!(muted["muted"], true, "{}", muted);
+
+    //The sender still sees their own emote, the player who muted them doesn't.
+    first.send(json!({ "type": "emote", "emote": "gg" })).await;
+    first.wait_for(|message| message["type"] == "emote").await;
+
+    second.send(json!({ "type": "chat", "text": "hello?" })).await;
+    let next = second.wait_for(|message| message["type"] == "chat" || message["type"] == "emote").await;
+    assert_eq!(next["text"], "hello?");
+
+    first.send(json!({ "type": "emote", "emote": "dance" })).await;
+    let refused = first.wait_for(|message| message["type"] == "error").await;
+    assert_eq!(refused["reason"], "Unknown emote: dance");
+}
+
+#[tokio::test]
+async fn friends_see_each_other_play_and_can_be_invited() {
+    let mut cleanup = ReplayCleanup::default();
+    let addr = start_server().await;
+    let mut host = TestClient::connect(addr).await;
+    let mut friend = TestClient::connect(addr).await;
+
+    host.send(json!({ "type": "set_name", "display_name": "Alice" })).await;
+    host.wait_for(|message| message["type"] == "name_set").await;
+
+    //Requests go by name (ignoring case), both sides hear when it's accepted.
+    friend.send(json!({ "type": "friend_request", "player": "alice" })).await;
+    let request = host.wait_for(|message| message["type"] == "friend_request").await;
+    assert_eq!(request["player_id"], friend.player_id.as_str());
+
+    host.send(json!({ "type": "accept_friend", "player": friend.player_id })).await;
+    let added = friend.wait_for(|message| message["type"] == "friend_added").await;
+    assert_eq!((&added["display_name"], &added["status"]), (&json!("Alice"), &json!("online")));
+    host.wait_for(|message| message["type"] == "friend_added").await;
+
+    //A dropped connection comes back as the same player, still friends.
+    let session = friend.session.clone();
+    friend.socket.close(None).await.unwrap();
+    let offline = host.wait_for(|message| message["type"] == "presence").await;
+    assert_eq!(offline["status"], "offline");
+
+    let mut friend = TestClient::connect_to(format!("ws://{}/ws?session={}", addr, session)).await;
+    assert_eq!(friend.player_id, offline["player_id"].as_str().unwrap());
+    host.wait_for(|message| message["type"] == "presence" && message["status"] == "online").await;
+
+    host.send(json!({ "type": "create_private_room" })).await;
+    let created = host.wait_for(|message| message["type"] == "private_room_created").await;
+    cleanup.0.push(created["room_id"].as_str().unwrap().parse().unwrap());
+
+    host.send(json!({ "type": "invite_friend", "player": friend.player_id })).await;
+    let invite = friend.wait_for(|message| message["type"] == "room_invite").await;
+    assert_eq!(invite["code"], created["code"]);
+    friend.join(json!({ "type": "join_room", "code": invite["code"] }), &mut cleanup).await;
+
+    host.send(json!({ "type": "start_match" })).await;
+    let playing = friend.wait_for(|message| message["type"] == "presence").await;
+    assert_eq!((&playing["display_name"], &playing["status"]), (&json!("Alice"), &json!("in_match")));
+
+    host.send(json!({ "type": "list_friends" })).await;
+    let list = host.wait_for(|message| message["type"] == "friend_list").await;
+    assert_eq!(list["friends"][0]["player_id"], friend.player_id.as_str());
+    assert_eq!(list["friends"][0]["status"], "in_match");
+}
+
+#[tokio::test]
+async fn a_session_only_comes_back_on_one_connection() {
+    let (addr, state) = start_app().await;
+    let mut first = TestClient::connect(addr).await;
+    let (player_id, session) = (first.player_id.clone(), first.session.clone());
+    let id: Uuid = player_id.parse().unwrap();
+    state.social.lock().await.set_entered(id, true); //Something to come back to, a player with nothing is forgotten when they go.
+    first.socket.close(None).await.unwrap();
+
+    while state.clients.lock().await.contains_key(&id) {
+        time::sleep(Duration::from_millis(10)).await;
+    }
+
+    //Both resume at once, only one of them may become the player. The other is refused before or after the upgrade.
+    let url = format!("ws://{}/ws?session={}", addr, session);
+    let resume = || async {
+        let (mut socket, _) = tokio_tungstenite::connect_async(url.clone()).await.ok()?;
+        match time::timeout(REPLY_TIMEOUT, socket.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => Some((serde_json::from_str::<Value>(&text).unwrap(), socket)),
+            _ => None,
+        }
+    };
+    let (a, b) = tokio::join!(resume(), resume());
+
+    let resumed: Vec<_> = [a, b].into_iter().flatten().collect();
+    assert_eq!(resumed.len(), 1);
+    assert_eq!(resumed[0].0["player_id"], player_id.as_str());
+}
+
+//A two player cup, started with both players seated in their fixture's room.
+async fn fixture_match(cleanup: &mut ReplayCleanup) -> (SocketAddr, Uuid, TestClient, TestClient, Value) {
+    let (addr, state) = start_app().await;
+
+    let cup = Tournament::new("Office cup".to_string(), Format::SingleElimination, "quick".to_string(), None, 8).unwrap();
+    let tournament_id = cup.id;
+    state.tournaments.lock().await.push(cup);
+
+    let mut first = TestClient::connect(addr).await;
+    let mut second = TestClient::connect(addr).await;
+    for player in [&mut first, &mut second] {
+        player.send(json!({ "type": "tournament_sign_up", "tournament_id": tournament_id })).await;
+    }
+    let update = first.wait_for(|message| message["type"] == "tournament_update" && message["tournament"]["entrants"].as_array().unwrap().len() == 2).await;
+    assert_eq!(update["tournament"]["state"], "signing_up");
+
+    //Started the way the admin API does it, the fixture's room follows without anyone asking.
+    state.tournaments.lock().await[0].start().unwrap();
+
+    let mut rooms = Vec::new();
+    let mut code = Value::Null;
+    let ids = [first.player_id.clone(), second.player_id.clone()];
+    for (player, opponent) in [(&mut first, &ids[1]), (&mut second, &ids[0])] {
+        let fixture = player.wait_for(|message| message["type"] == "tournament_match").await;
+        assert_eq!(fixture["opponent"]["player_id"], opponent.as_str());
+
+        let joined = player.join(json!({ "type": "join_room", "code": fixture["code"] }), cleanup).await;
+        rooms.push(joined["room_id"].clone());
+        code = fixture["code"].clone();
+    }
+    assert_eq!(rooms[0], rooms[1]);
+
+    //No owner to start it, the match starts once both players are in.
+    let update = first.wait_for(|message| message["type"] == "room_update" && message["room"]["state"] == "In Progress").await;
+    assert_eq!(update["room"]["room_id"], rooms[0]);
+
+    (addr, tournament_id, first, second, code)
+}
+
+#[tokio::test]
+async fn tournament_fixtures_get_a_room_once_both_players_are_free() {
+    let mut cleanup = ReplayCleanup::default();
+    let (addr, tournament_id, _first, _second, _) = fixture_match(&mut cleanup).await;
+
+    let bracket = http_get(addr, &format!("/tournaments/{}", tournament_id)).await;
+    assert_eq!(bracket["state"], "in_progress");
+    assert_eq!(bracket["fixtures"][0]["room_id"].as_str(), Some(cleanup.0[0].to_string().as_str()));
+    assert_eq!(http_get(addr, "/tournaments").await["tournaments"][0]["entrants"], 2);
+}
+
+#[tokio::test]
+async fn leaving_a_fixture_mid_match_gives_it_to_the_other_player() {
+    let mut cleanup = ReplayCleanup::default();
+    let (addr, _, mut first, mut second, code) = fixture_match(&mut cleanup).await;
+
+    //Walking out hands the match to the player still at the table, rather than sending the room back to the start.
+    let session = second.session.clone();
+    second.socket.close(None).await.unwrap();
+
+    let over = first.wait_for(|message| message["type"] == "match_over").await;
+    assert!(over["winner_team"].is_u64(), "{}", over);
+    let update = first.wait_for(|message| message["type"] == "tournament_update" && message["tournament"]["state"] == "finished").await;
+    assert_eq!(update["tournament"]["fixtures"][0]["winner"], first.player_id.as_str());
+    assert_eq!(update["tournament"]["champion"], first.player_id.as_str());
+
+    //Coming back doesn't get them a fresh 0-0 match, the fixture is over and it's room is gone.
+    let mut second = TestClient::connect_to(format!("ws://{}/ws?session={}", addr, session)).await;
+    second.send(json!({ "type": "join_room", "code": code })).await;
+    let refused = second.wait_for(|message| message["type"] == "error" || message["type"] == "room_joined").await;
+    assert_eq!(refused["type"], "error", "{}", refused);
+}